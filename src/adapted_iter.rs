@@ -1,8 +1,12 @@
+use std::io::Cursor;
+use std::path::PathBuf;
 use std::pin::Pin;
 
 use tokio_stream::Stream;
 
 use crate::adapters::AdaptInfo;
+use crate::config::RgaConfig;
+use crate::limits::LimitUsage;
 
 pub trait AdaptedFilesIter: Stream<Item = anyhow::Result<AdaptInfo>> + Send {}
 impl<T> AdaptedFilesIter for T where T: Stream<Item = anyhow::Result<AdaptInfo>> + Send {}
@@ -12,3 +16,25 @@ pub type AdaptedFilesIterBox = Pin<Box<dyn AdaptedFilesIter>>;
 pub fn one_file(ai: AdaptInfo) -> AdaptedFilesIterBox {
     Box::pin(tokio_stream::once(Ok(ai)))
 }
+
+/// a pseudo file that only contains the given line, e.g. `[rga: error reading member foo.txt: ...]`.
+/// it is not matched by any adapter, so the line ends up in the output as is
+pub fn marker_file(
+    line: String,
+    line_prefix: String,
+    archive_recursion_depth: i32,
+    postprocess: bool,
+    config: RgaConfig,
+    limit_usage: LimitUsage,
+) -> AdaptInfo {
+    AdaptInfo {
+        filepath_hint: PathBuf::from("rga-marker"),
+        is_real_file: false,
+        archive_recursion_depth,
+        inp: Box::pin(Cursor::new(format!("{line}\n").into_bytes())),
+        line_prefix,
+        postprocess,
+        config,
+        limit_usage,
+    }
+}
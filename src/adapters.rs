@@ -2,18 +2,27 @@ pub mod custom;
 pub mod decompress;
 pub mod ffmpeg;
 pub mod mbox;
+pub mod overrides;
+pub mod persistent;
 pub mod postproc;
 use std::sync::Arc;
 pub mod sqlite;
 pub mod tar;
+pub mod tika;
+#[cfg(feature = "wasm")]
+pub mod wasm;
 pub mod writing;
 pub mod zip;
-use crate::{adapted_iter::AdaptedFilesIterBox, config::RgaConfig, matching::*};
+use crate::{
+    adapted_iter::AdaptedFilesIterBox, config::RgaConfig, limits::LimitUsage, matching::*,
+};
 use anyhow::{Context, Result, format_err};
 use async_trait::async_trait;
 use custom::BUILTIN_SPAWNING_ADAPTERS;
 use custom::CustomAdapterConfig;
+use lazy_static::lazy_static;
 use log::*;
+use overrides::AdapterOverride;
 use tokio::io::AsyncRead;
 
 use core::fmt::Debug;
@@ -22,6 +31,7 @@ use std::collections::HashMap;
 use std::iter::Iterator;
 use std::path::PathBuf;
 use std::pin::Pin;
+use std::sync::RwLock;
 
 use self::postproc::PostprocPageBreaks;
 
@@ -83,7 +93,7 @@ pub trait GetMetadata {
 pub trait FileAdapter: GetMetadata + Send + Sync {
     /// adapt a file.
     ///
-    /// detection_reason is the Matcher that was used to identify this file. Unless --rga-accurate was given, it is always a FastMatcher
+    /// detection_reason is the Matcher that was used to identify this file. Unless --rga-accurate was given or the file was detected by its magic bytes, it is always a FastMatcher
     async fn adapt(
         &self,
         a: AdaptInfo,
@@ -104,19 +114,69 @@ pub struct AdaptInfo {
     pub line_prefix: String,
     pub postprocess: bool,
     pub config: RgaConfig,
+    /// what the searched file this file is in has used up of the limits so far. pass it on to nested files
+    pub limit_usage: LimitUsage,
 }
 
 /// (enabledAdapters, disabledAdapters)
 type AdaptersTuple = (Vec<Arc<dyn FileAdapter>>, Vec<Arc<dyn FileAdapter>>);
 
-pub fn get_all_adapters(custom_adapters: Option<Vec<CustomAdapterConfig>>) -> AdaptersTuple {
-    // order in descending priority
-    let mut adapters: Vec<Arc<dyn FileAdapter>> = vec![];
+/// priority of the adapters built into rga
+pub const BUILTIN_ADAPTER_PRIORITY: i32 = 0;
+/// priority of the adapters defined in the `custom_adapters` config, so they take precedence over the builtin ones
+pub const CUSTOM_ADAPTER_PRIORITY: i32 = 100;
+
+lazy_static! {
+    static ref REGISTERED_ADAPTERS: RwLock<Vec<(i32, Arc<dyn FileAdapter>)>> = RwLock::new(vec![]);
+}
+
+/**
+ * register an additional adapter for this process, e.g. a FileAdapter implemented by a library user.
+ *
+ * Adapters with a higher priority are tried first. Builtin adapters have priority `BUILTIN_ADAPTER_PRIORITY`,
+ * adapters from the config file `CUSTOM_ADAPTER_PRIORITY`. On equal priority, registered adapters are tried before the others.
+ *
+ * An adapter with the same name as an existing one (builtin or registered) replaces it.
+ */
+pub fn register_adapter(adapter: Arc<dyn FileAdapter>, priority: i32) {
+    let mut registered = REGISTERED_ADAPTERS
+        .write()
+        .expect("adapter registry poisoned");
+    let name = &adapter.metadata().name;
+    registered.retain(|(_, a)| &a.metadata().name != name);
+    registered.push((priority, adapter));
+}
+
+/// remove an adapter added with `register_adapter`. returns false if there is none with that name
+pub fn unregister_adapter(name: &str) -> bool {
+    let mut registered = REGISTERED_ADAPTERS
+        .write()
+        .expect("adapter registry poisoned");
+    let before = registered.len();
+    registered.retain(|(_, a)| a.metadata().name != name);
+    registered.len() != before
+}
+
+pub fn get_all_adapters(
+    custom_adapters: Option<Vec<CustomAdapterConfig>>,
+    overrides: &[AdapterOverride],
+) -> AdaptersTuple {
+    let mut adapters: Vec<(i32, Arc<dyn FileAdapter>)> = REGISTERED_ADAPTERS
+        .read()
+        .expect("adapter registry poisoned")
+        .clone();
     if let Some(custom_adapters) = custom_adapters {
         for adapter_config in custom_adapters {
-            adapters.push(Arc::new(adapter_config.to_adapter()));
+            adapters.push((
+                CUSTOM_ADAPTER_PRIORITY,
+                Arc::new(adapter_config.to_adapter()),
+            ));
         }
     }
+    #[cfg(feature = "wasm")]
+    for plugin in wasm::plugin_adapters() {
+        adapters.push((CUSTOM_ADAPTER_PRIORITY, plugin as Arc<dyn FileAdapter>));
+    }
 
     let internal_adapters: Vec<Arc<dyn FileAdapter>> = vec![
         Arc::new(PostprocPageBreaks::default()),
@@ -126,16 +186,26 @@ pub fn get_all_adapters(custom_adapters: Option<Vec<CustomAdapterConfig>>) -> Ad
         Arc::new(mbox::MboxAdapter::new()),
         Arc::new(tar::TarAdapter::new()),
         Arc::new(sqlite::SqliteAdapter::new()),
+        Arc::new(tika::TikaAdapter::new()),
     ];
     adapters.extend(
         BUILTIN_SPAWNING_ADAPTERS
             .iter()
-            .map(|e| -> Arc<dyn FileAdapter> { Arc::new(e.to_adapter()) }),
+            .map(|e| -> Arc<dyn FileAdapter> { Arc::new(e.to_adapter()) })
+            .chain(internal_adapters)
+            .map(|a| (BUILTIN_ADAPTER_PRIORITY, a)),
     );
-    adapters.extend(internal_adapters);
+
+    // the first adapter with a given name wins, so registered adapters can replace builtin ones
+    let mut seen = std::collections::HashSet::new();
+    adapters.retain(|(_, a)| seen.insert(a.metadata().name.clone()));
+    overrides::apply_overrides(&mut adapters, overrides);
+    // order in descending priority. stable, so equal priorities keep the order above
+    adapters.sort_by_key(|(priority, _)| std::cmp::Reverse(*priority));
 
     adapters
         .into_iter()
+        .map(|(_, a)| a)
         .partition(|e| !e.metadata().disabled_by_default)
 }
 
@@ -149,9 +219,11 @@ pub fn get_all_adapters(custom_adapters: Option<Vec<CustomAdapterConfig>>) -> Ad
  */
 pub fn get_adapters_filtered<T: AsRef<str>>(
     custom_adapters: Option<Vec<CustomAdapterConfig>>,
+    overrides: &[AdapterOverride],
     adapter_names: &[T],
 ) -> Result<Vec<Arc<dyn FileAdapter>>> {
-    let (def_enabled_adapters, def_disabled_adapters) = get_all_adapters(custom_adapters);
+    let (def_enabled_adapters, def_disabled_adapters) =
+        get_all_adapters(custom_adapters, overrides);
     let adapters = if !adapter_names.is_empty() {
         let adapters_map: HashMap<_, _> = def_enabled_adapters
             .iter()
@@ -214,3 +286,70 @@ pub fn get_adapters_filtered<T: AsRef<str>>(
     );
     Ok(adapters)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::adapted_iter::one_file;
+
+    struct DummyAdapter(AdapterMeta);
+    impl GetMetadata for DummyAdapter {
+        fn metadata(&self) -> &AdapterMeta {
+            &self.0
+        }
+    }
+    #[async_trait]
+    impl FileAdapter for DummyAdapter {
+        async fn adapt(
+            &self,
+            a: AdaptInfo,
+            _detection_reason: &FileMatcher,
+        ) -> Result<AdaptedFilesIterBox> {
+            Ok(one_file(a))
+        }
+    }
+    fn dummy(name: &str) -> Arc<dyn FileAdapter> {
+        Arc::new(DummyAdapter(AdapterMeta {
+            name: name.to_string(),
+            version: 1,
+            description: "test".to_string(),
+            recurses: false,
+            fast_matchers: vec![FastFileMatcher::FileExtension("rgatestdummy".to_string())],
+            slow_matchers: None,
+            keep_fast_matchers_if_accurate: false,
+            disabled_by_default: false,
+        }))
+    }
+
+    /// the registry is global, so remove the adapters again even if the test fails
+    struct Registered(Vec<&'static str>);
+    impl Drop for Registered {
+        fn drop(&mut self) {
+            for name in &self.0 {
+                unregister_adapter(name);
+            }
+        }
+    }
+
+    #[test]
+    fn registered_adapters() -> Result<()> {
+        let _registered = Registered(vec!["testhigh", "testlow"]);
+        register_adapter(dummy("testhigh"), 1000);
+        register_adapter(dummy("testlow"), -1000);
+        let names: Vec<String> = get_adapters_filtered::<&str>(None, &[], &[])?
+            .iter()
+            .map(|a| a.metadata().name.clone())
+            .collect();
+        assert_eq!(names.first().map(|e| e.as_str()), Some("testhigh"));
+        assert_eq!(names.last().map(|e| e.as_str()), Some("testlow"));
+        assert!(names.iter().any(|n| n == "decompress"));
+
+        let only = get_adapters_filtered(None, &[], &["testlow"])?;
+        assert_eq!(only.len(), 1);
+
+        assert!(unregister_adapter("testlow"));
+        assert!(!unregister_adapter("testlow"));
+        assert!(get_adapters_filtered(None, &[], &["testlow"]).is_err());
+        Ok(())
+    }
+}
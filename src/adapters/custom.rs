@@ -1,11 +1,14 @@
+use super::postproc::{Postprocessor, apply_postprocessors};
 use super::*;
 use super::{AdaptInfo, AdapterMeta, FileAdapter, GetMetadata};
 use crate::adapted_iter::one_file;
 
+use crate::process_group::{self, ProcessGroup};
+use crate::sandbox::{self, SandboxPolicy};
 use crate::{
     adapted_iter::AdaptedFilesIterBox,
     expand::expand_str_ez,
-    matching::{FastFileMatcher, FileMatcher},
+    matching::{FastFileMatcher, FileMatcher, unescape_bytes},
 };
 use crate::{join_handle_to_stream, to_io_err};
 use anyhow::Result;
@@ -15,13 +18,18 @@ use lazy_static::lazy_static;
 use log::debug;
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::path::Path;
-use std::process::Stdio;
-use tokio::io::AsyncReadExt;
+use std::process::{ExitStatus, Stdio};
+use std::time::Duration;
+use tempfile::{TempDir, TempPath};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::process::Child;
-use tokio::process::Command;
+use tokio::process::{ChildStderr, Command};
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
 
-use tokio_util::io::StreamReader;
+use tokio_util::io::{ReaderStream, StreamReader};
 // mostly the same as AdapterMeta + SpawningFileAdapter
 #[derive(Debug, Deserialize, Serialize, JsonSchema, Default, PartialEq, Clone)]
 pub struct CustomAdapterConfig {
@@ -44,6 +52,18 @@ pub struct CustomAdapterConfig {
     /// The file extensions this adapter supports, for example `["epub", "mobi"]`.
     pub extensions: Vec<String>,
 
+    /// Additional rules to match files by name or path, for files that can't be recognized by their extension. For example:
+    ///
+    /// - `{"file_name_glob": "Dockerfile*"}`
+    /// - `{"path_glob": "**/Maildir/**/cur/*"}` (`*` does not match `/`, `**` matches any number of directories)
+    /// - `{"path_regex": "\\.log\\.\\d+$"}`
+    /// - `{"path_prefix": "/var/mail"}`
+    pub matchers: Option<Vec<FastFileMatcher>>,
+
+    /// Magic bytes identifying the file type, used if the file name does not match (see `--rga-detection`).
+    /// `bytes` can contain `\\xNN` escapes, e.g. `[{"offset": 0, "bytes": "%PDF-"}]`.
+    pub magic_bytes: Option<Vec<MagicBytesConfig>>,
+
     /// If not null and `--rga-accurate` is enabled, mimetype matching is used instead of file name matching.
     pub mimetypes: Option<Vec<String>>,
 
@@ -59,6 +79,10 @@ pub struct CustomAdapterConfig {
     /// - `$input_file_stem`: the file name without the last extension. e.g. foo.tar.gz -> foo.tar
     /// - `$input_virtual_path`: the full input file path.
     ///   Note that this path may not actually exist on disk because it is the result of another adapter.
+    /// - `$input_file`: path of a real, seekable file with the input. For files within archives, the input is written to a temporary file first.
+    ///   If used, stdin of the program is empty.
+    /// - `$output_dir`: path of an empty temporary directory. If used, the output is read from the files the program writes there
+    ///   (concatenated in file name order) instead of from stdout. See also `.output_files`.
     ///
     /// stdin of the program will be connected to the input file, and stdout is assumed to be the converted file
     pub args: Vec<String>,
@@ -70,6 +94,81 @@ pub struct CustomAdapterConfig {
     ///
     /// Setting this is useful if the output format is not plain text (.txt) but instead some other format that should be passed to another adapter
     pub output_path_hint: Option<String>,
+
+    /// If true, the program is started once and converts many files, speaking the length-prefixed
+    /// protocol described in `adapters/persistent.rs` on stdin/stdout instead of being run once per file.
+    ///
+    /// The placeholders in `.args` are not available, the path of each file is sent with the request instead.
+    ///
+    /// If the output of a file is dropped before it is read completely (e.g. because of `--rga-adapter-timeout`
+    /// or a limit), the process and everything it started are killed, and a new one is started for the next file.
+    pub persistent: Option<bool>,
+
+    /// The maximum number of processes of a persistent adapter running at the same time. Defaults to 1.
+    ///
+    /// The processes are shared by all files with the same adapter name and the same `.binary`, `.args`,
+    /// `.env`, `.working_dir`, `.stderr` and `.max_concurrency`.
+    pub max_concurrency: Option<usize>,
+
+    /// Kill the program if it has not finished converting a file after this many seconds.
+    ///
+    /// Not used for persistent adapters.
+    pub timeout_secs: Option<u64>,
+
+    /// Additional environment variables to set for the program.
+    pub env: Option<BTreeMap<String, String>>,
+
+    /// The working directory to run the program in. Defaults to the working directory of rga.
+    pub working_dir: Option<String>,
+
+    /// What to do with the stderr output of the program. Defaults to `inherit`.
+    ///
+    /// - `inherit`: print it to the terminal
+    /// - `capture`: hide it, but include it in the error message if the program fails. Not available for persistent adapters.
+    /// - `discard`: hide it
+    pub stderr: Option<StderrMode>,
+
+    /// If true, every file the program writes to `$output_dir` (including subdirectories) is passed on
+    /// as its own file, with its path relative to the output directory as the virtual path.
+    /// This allows using extraction tools like `unar` or `7z x` as archive adapters.
+    ///
+    /// Requires the `$output_dir` placeholder in `.args`. `.output_path_hint` is not used.
+    pub output_files: Option<bool>,
+
+    /// Filters to apply to the output of the program, in order. Available:
+    ///
+    /// - `pagebreaks`: add "Page N: " to each line, with pages separated by ASCII form feeds
+    /// - `encoding`: convert UTF-16 to UTF-8, replace binary output with `[rga: binary data]`
+    /// - `strip_ansi`: remove ANSI escape sequences like colors
+    pub postprocessors: Option<Vec<Postprocessor>>,
+
+    /// If true, always run the program in a sandbox, as if the adapter was listed in `--rga-sandbox`.
+    /// Linux only, not available for persistent adapters.
+    pub sandbox: Option<bool>,
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Default, PartialEq, Eq, Clone)]
+pub struct MagicBytesConfig {
+    /// Position of the bytes in the file. Defaults to 0.
+    #[serde(default)]
+    pub offset: usize,
+    pub bytes: String,
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Default, PartialEq, Eq, Hash, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum StderrMode {
+    #[default]
+    Inherit,
+    Capture,
+    Discard,
+}
+
+/// how to run the subprocess of an adapter
+#[derive(Debug, Default, Clone)]
+pub struct ProcessOptions {
+    pub timeout: Option<Duration>,
+    pub stderr: StderrMode,
 }
 
 fn strs(arr: &[&str]) -> Vec<String> {
@@ -130,7 +229,18 @@ lazy_static! {
             ]),
             disabled_by_default: None,
             match_only_by_mime: None,
-            output_path_hint: None
+            output_path_hint: None,
+            persistent: None,
+            max_concurrency: None,
+            timeout_secs: None,
+            env: None,
+            working_dir: None,
+            stderr: None,
+            output_files: None,
+            postprocessors: None,
+            matchers: None,
+            magic_bytes: None,
+            sandbox: None,
         },
         CustomAdapterConfig {
             name: "poppler".to_owned(),
@@ -145,7 +255,21 @@ lazy_static! {
             args: strs(&["-", "-"]),
             disabled_by_default: None,
             match_only_by_mime: None,
-            output_path_hint: Some("${input_virtual_path}.txt.asciipagebreaks".into())
+            output_path_hint: None,
+            persistent: None,
+            max_concurrency: None,
+            timeout_secs: None,
+            env: None,
+            working_dir: None,
+            stderr: None,
+            output_files: None,
+            postprocessors: Some(vec![Postprocessor::Encoding, Postprocessor::Pagebreaks]),
+            matchers: None,
+            magic_bytes: Some(vec![MagicBytesConfig {
+                offset: 0,
+                bytes: "%PDF-".to_string(),
+            }]),
+            sandbox: None,
         }
     ];
 }
@@ -160,42 +284,143 @@ pub fn map_exe_error(err: std::io::Error, exe_name: &str, help: &str) -> anyhow:
     }
 }
 
-fn proc_wait(mut child: Child, context: impl FnOnce() -> String) -> impl AsyncRead {
+/// how much of the stderr output of a failed program to include in the error message
+const MAX_CAPTURED_STDERR: usize = 64 * 1024;
+
+enum ProcEnd {
+    Exited(std::io::Result<ExitStatus>),
+    TimedOut(Duration),
+    Cancelled,
+}
+
+/// kill the child and the programs it started, which are in the same process group (see `pipe_output`)
+async fn kill_tree(child: &mut Child) {
+    #[cfg(unix)]
+    if let Some(pid) = child.id() {
+        // SAFETY: only sends a signal, the group id is the pid of our own child
+        unsafe {
+            libc::killpg(pid as libc::pid_t, libc::SIGKILL);
+        }
+    }
+    child
+        .kill()
+        .await
+        .unwrap_or_else(|e| debug!("could not kill subprocess: {}", e));
+}
+
+/// waits for the child in a separate task, so it is killed on timeout even if nobody reads the output
+fn proc_wait(
+    mut child: Child,
+    group: ProcessGroup,
+    stderr: Option<JoinHandle<Vec<u8>>>,
+    timeout: Option<Duration>,
+    context: impl FnOnce() -> String + Send + 'static,
+) -> impl AsyncRead {
+    let (cancel_tx, cancel_rx) = oneshot::channel::<()>();
+    let watchdog = tokio::spawn(async move {
+        let timed_out = async {
+            match timeout {
+                Some(timeout) => tokio::time::sleep(timeout).await,
+                None => std::future::pending().await,
+            }
+        };
+        let end = tokio::select! {
+            status = child.wait() => ProcEnd::Exited(status),
+            _ = timed_out => ProcEnd::TimedOut(timeout.unwrap_or_default()),
+            // the sender is never used, it is dropped when the output stream is dropped
+            _ = cancel_rx => ProcEnd::Cancelled,
+        };
+        if !matches!(end, ProcEnd::Exited(_)) {
+            kill_tree(&mut child).await;
+        }
+        drop(group);
+        end
+    });
     let s = stream! {
-        let res = child.wait().await?;
-        if res.success() {
-            yield std::io::Result::Ok(Bytes::new());
+        let _cancel = cancel_tx;
+        let end = watchdog.await.map_err(std::io::Error::other)?;
+        let err = match end {
+            ProcEnd::Exited(status) => {
+                let status = status?;
+                if status.success() {
+                    yield std::io::Result::Ok(Bytes::new());
+                    return;
+                }
+                format_err!("{}", status)
+            }
+            ProcEnd::TimedOut(timeout) => format_err!("timed out after {}s", timeout.as_secs_f64()),
+            ProcEnd::Cancelled => format_err!("cancelled"),
+        };
+        let stderr = match stderr {
+            Some(stderr) => stderr.await.unwrap_or_default(),
+            None => vec![],
+        };
+        let err = if stderr.is_empty() {
+            err
         } else {
-            Err(format_err!("{:?}", res)).with_context(context).map_err(to_io_err)?;
-        }
+            format_err!("{}\nstderr:\n{}", err, String::from_utf8_lossy(&stderr).trim_end())
+        };
+        Err(err).with_context(context).map_err(to_io_err)?;
     };
     StreamReader::new(s)
 }
 
+/// read the stderr of a child, keeping only the beginning
+fn capture_stderr(mut stderr: ChildStderr) -> JoinHandle<Vec<u8>> {
+    tokio::spawn(async move {
+        let mut captured = Vec::new();
+        let mut buf = [0u8; 8192];
+        loop {
+            match stderr.read(&mut buf).await {
+                Ok(0) | Err(_) => break,
+                Ok(n) => {
+                    let keep = n.min(MAX_CAPTURED_STDERR - captured.len());
+                    captured.extend_from_slice(&buf[..keep]);
+                }
+            }
+        }
+        captured
+    })
+}
+
 pub fn pipe_output(
     _line_prefix: &str,
     mut cmd: Command,
     inp: ReadBox,
     exe_name: &str,
     help: &str,
+    options: &ProcessOptions,
 ) -> Result<ReadBox> {
-    let cmd_log = format!("{:?}", cmd); // todo: perf
-    let mut cmd = cmd
-        .stdin(Stdio::piped())
+    // a new process group, so the whole tree can be killed on timeout or when the output is dropped
+    process_group::configure(&mut cmd);
+    cmd.stdin(Stdio::piped())
         .stdout(Stdio::piped())
-        .spawn()
-        .map_err(|e| map_exe_error(e, exe_name, help))?;
+        .stderr(match options.stderr {
+            StderrMode::Inherit => Stdio::inherit(),
+            StderrMode::Capture => Stdio::piped(),
+            StderrMode::Discard => Stdio::null(),
+        })
+        .kill_on_drop(true);
+    let cmd_log = format!("{:?}", cmd); // todo: perf
+    let mut cmd = cmd.spawn().map_err(|e| map_exe_error(e, exe_name, help))?;
+    let group = ProcessGroup::track(&cmd);
     let mut stdi = cmd.stdin.take().expect("is piped");
     let stdo = cmd.stdout.take().expect("is piped");
+    let stde = cmd.stderr.take().map(capture_stderr);
 
     let join = tokio::spawn(async move {
         let mut z = inp;
         tokio::io::copy(&mut z, &mut stdi).await?;
         std::io::Result::Ok(())
     });
-    Ok(Box::pin(stdo.chain(
-        proc_wait(cmd, move || format!("subprocess: {cmd_log}")).chain(join_handle_to_stream(join)),
-    )))
+    Ok(Box::pin(
+        stdo.chain(
+            proc_wait(cmd, group, stde, options.timeout, move || {
+                format!("subprocess: {cmd_log}")
+            })
+            .chain(join_handle_to_stream(join)),
+        ),
+    ))
 }
 
 pub struct CustomSpawningFileAdapter {
@@ -203,13 +428,31 @@ pub struct CustomSpawningFileAdapter {
     args: Vec<String>,
     meta: AdapterMeta,
     output_path_hint: Option<String>,
+    /// Some(max_concurrency) if this is a persistent adapter
+    persistent: Option<usize>,
+    env: BTreeMap<String, String>,
+    working_dir: Option<String>,
+    process_options: ProcessOptions,
+    output_files: bool,
+    postprocessors: Vec<Postprocessor>,
+    sandbox: bool,
 }
 impl GetMetadata for CustomSpawningFileAdapter {
     fn metadata(&self) -> &AdapterMeta {
         &self.meta
     }
 }
-fn arg_replacer(arg: &str, filepath_hint: &Path) -> Result<String> {
+/// real paths for the `$input_file` and `$output_dir` placeholders, if they are used
+#[derive(Default)]
+struct FilePlaceholders {
+    input_file: Option<PathBuf>,
+    output_dir: Option<PathBuf>,
+}
+
+pub(crate) fn arg_replacer(arg: &str, filepath_hint: &Path) -> Result<String> {
+    file_arg_replacer(arg, filepath_hint, &FilePlaceholders::default())
+}
+fn file_arg_replacer(arg: &str, filepath_hint: &Path, files: &FilePlaceholders) -> Result<String> {
     expand_str_ez(arg, |s| match s {
         "input_virtual_path" => Ok(filepath_hint.to_string_lossy()),
         "input_file_stem" => Ok(filepath_hint
@@ -220,24 +463,302 @@ fn arg_replacer(arg: &str, filepath_hint: &Path) -> Result<String> {
             .extension()
             .unwrap_or_default()
             .to_string_lossy()),
+        "input_file" => files
+            .input_file
+            .as_ref()
+            .map(|p| Cow::Owned(p.to_string_lossy().into_owned()))
+            .ok_or_else(|| anyhow::format_err!("$input_file is not available here")),
+        "output_dir" => files
+            .output_dir
+            .as_ref()
+            .map(|p| Cow::Owned(p.to_string_lossy().into_owned()))
+            .ok_or_else(|| anyhow::format_err!("$output_dir is not available here")),
         e => Err(anyhow::format_err!("unknown replacer ${{{e}}}")),
     })
 }
+/// whether any of the args contains the given placeholder
+fn uses_placeholder(args: &[String], name: &str) -> bool {
+    let used = std::cell::Cell::new(false);
+    for arg in args {
+        let _ = expand_str_ez(arg, |s| {
+            if s == name {
+                used.set(true);
+            }
+            Ok(Cow::Borrowed(""))
+        });
+    }
+    used.get()
+}
+
+/// write the input to a temporary file, keeping the extension since some programs need it to detect the file type
+async fn materialize_input(filepath_hint: &Path, mut inp: ReadBox) -> Result<TempPath> {
+    let mut builder = tempfile::Builder::new();
+    builder.prefix("rga-input-");
+    let suffix = filepath_hint
+        .extension()
+        .map(|e| format!(".{}", e.to_string_lossy()))
+        .unwrap_or_default();
+    builder.suffix(&suffix);
+    let tmp = builder.tempfile()?.into_temp_path();
+    let mut file = tokio::fs::File::create(&tmp).await?;
+    tokio::io::copy(&mut inp, &mut file).await?;
+    file.flush().await?;
+    Ok(tmp)
+}
+
+/// read the files in the output directory in name order, deleting it afterwards
+fn read_output_dir(dir: TempDir) -> ReadBox {
+    let s = stream! {
+        let mut paths = vec![];
+        let mut entries = tokio::fs::read_dir(dir.path()).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            if entry.file_type().await?.is_file() {
+                paths.push(entry.path());
+            }
+        }
+        paths.sort();
+        for path in paths {
+            let file = tokio::fs::File::open(&path).await?;
+            for await chunk in ReaderStream::new(file) {
+                yield chunk;
+            }
+        }
+        drop(dir);
+    };
+    Box::pin(StreamReader::new(s))
+}
+
+/// all files in the directory and its subdirectories, relative to it, in name order
+async fn list_files(dir: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let mut files = vec![];
+    let mut dirs = vec![PathBuf::new()];
+    while let Some(rel) = dirs.pop() {
+        let mut entries = tokio::fs::read_dir(dir.join(&rel)).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let file_type = entry.file_type().await?;
+            let path = rel.join(entry.file_name());
+            if file_type.is_dir() {
+                dirs.push(path);
+            } else if file_type.is_file() {
+                files.push(path);
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// keep `guard` (e.g. a temporary file) alive until `inp` has been read completely
+fn read_then_drop<T: Send + 'static>(inp: ReadBox, guard: T) -> ReadBox {
+    let s = stream! {
+        for await chunk in ReaderStream::new(inp) {
+            yield chunk;
+        }
+        drop(guard);
+    };
+    Box::pin(StreamReader::new(s))
+}
+
+/// a running program. stdout has to be read to the end to wait for it to exit
+struct Spawned {
+    stdout: ReadBox,
+    output_dir: Option<TempDir>,
+    /// deleted when dropped, so it has to be kept until the program has exited
+    input_tmp: Option<TempPath>,
+    /// the only directory a sandboxed program can write to. also kept until the program has exited
+    sandbox_dir: Option<TempDir>,
+}
+
 impl CustomSpawningFileAdapter {
     fn command(
         &self,
         filepath_hint: &std::path::Path,
+        files: &FilePlaceholders,
         mut command: tokio::process::Command,
     ) -> Result<tokio::process::Command> {
         command.args(
             self.args
                 .iter()
-                .map(|arg| arg_replacer(arg, filepath_hint))
+                .map(|arg| file_arg_replacer(arg, filepath_hint, files))
                 .collect::<Result<Vec<_>>>()?,
         );
+        command.envs(&self.env);
+        if let Some(working_dir) = &self.working_dir {
+            command.current_dir(working_dir);
+        }
         log::debug!("running command {:?}", command);
         Ok(command)
     }
+
+    /// start the program for this file
+    async fn start(
+        &self,
+        filepath_hint: &Path,
+        is_real_file: bool,
+        inp: ReadBox,
+        line_prefix: &str,
+        sandbox: bool,
+    ) -> Result<Spawned> {
+        let mut files = FilePlaceholders::default();
+        let mut input_tmp = None;
+        let inp: ReadBox = if uses_placeholder(&self.args, "input_file") {
+            if is_real_file {
+                files.input_file = Some(filepath_hint.to_path_buf());
+            } else {
+                let tmp = materialize_input(filepath_hint, inp)
+                    .await
+                    .context("writing input to temporary file")?;
+                files.input_file = Some(tmp.to_path_buf());
+                input_tmp = Some(tmp);
+            }
+            Box::pin(tokio::io::empty())
+        } else {
+            inp
+        };
+        let output_dir = if uses_placeholder(&self.args, "output_dir") {
+            let dir = tempfile::Builder::new().prefix("rga-output-").tempdir()?;
+            files.output_dir = Some(dir.path().to_path_buf());
+            Some(dir)
+        } else {
+            None
+        };
+
+        let cmd = Command::new(&self.binary);
+        let mut cmd = self
+            .command(filepath_hint, &files, cmd)
+            .with_context(|| format!("Could not set cmd arguments for {}", self.binary))?;
+        let sandbox_dir = if sandbox {
+            let dir = tempfile::Builder::new().prefix("rga-sandbox-").tempdir()?;
+            let policy = SandboxPolicy {
+                read: files
+                    .input_file
+                    .iter()
+                    .cloned()
+                    .chain(self.working_dir.iter().map(PathBuf::from))
+                    .collect(),
+                write: files
+                    .output_dir
+                    .iter()
+                    .cloned()
+                    .chain([dir.path().to_path_buf()])
+                    .collect(),
+            };
+            sandbox::restrict(&mut cmd, &policy)
+                .with_context(|| format!("sandboxing {}", self.meta.name))?;
+            cmd.env("TMPDIR", dir.path());
+            Some(dir)
+        } else {
+            None
+        };
+        debug!("executing {:?}", cmd);
+        let stdout = pipe_output(
+            line_prefix,
+            cmd,
+            inp,
+            &self.binary,
+            "",
+            &self.process_options,
+        )?;
+        Ok(Spawned {
+            stdout,
+            output_dir,
+            input_tmp,
+            sandbox_dir,
+        })
+    }
+
+    /// run the program once for this file, returning its output
+    async fn spawn(
+        &self,
+        filepath_hint: &Path,
+        is_real_file: bool,
+        inp: ReadBox,
+        line_prefix: &str,
+        sandbox: bool,
+    ) -> Result<ReadBox> {
+        let Spawned {
+            stdout: mut output,
+            output_dir,
+            input_tmp,
+            sandbox_dir,
+        } = self
+            .start(filepath_hint, is_real_file, inp, line_prefix, sandbox)
+            .await?;
+        if let Some(output_dir) = output_dir {
+            // stdout is ignored, draining it also waits for the program to exit
+            tokio::io::copy(&mut output, &mut tokio::io::sink()).await?;
+            output = read_output_dir(output_dir);
+        }
+        Ok(if input_tmp.is_some() || sandbox_dir.is_some() {
+            read_then_drop(output, (input_tmp, sandbox_dir))
+        } else {
+            output
+        })
+    }
+
+    /// whether the program should run in the sandbox, from the adapter config or `--rga-sandbox`
+    fn sandboxed(&self, config: &RgaConfig) -> bool {
+        self.sandbox || sandbox::enabled_for(config, &self.meta.name)
+    }
+
+    /// run the program once for this file, returning every file it wrote to `$output_dir`
+    async fn spawn_output_files(&self, ai: AdaptInfo) -> Result<AdaptedFilesIterBox> {
+        let AdaptInfo {
+            filepath_hint,
+            is_real_file,
+            inp,
+            line_prefix,
+            archive_recursion_depth,
+            postprocess,
+            config,
+            limit_usage,
+        } = ai;
+        if self.persistent.is_some() || !uses_placeholder(&self.args, "output_dir") {
+            return Err(format_err!(
+                "{}: output_files requires the $output_dir placeholder and can't be used with persistent",
+                self.meta.name
+            ));
+        }
+        let Spawned {
+            mut stdout,
+            output_dir,
+            input_tmp,
+            sandbox_dir,
+        } = self
+            .start(
+                &filepath_hint,
+                is_real_file,
+                inp,
+                &line_prefix,
+                self.sandboxed(&config),
+            )
+            .await?;
+        let output_dir = output_dir.expect("$output_dir is used");
+        let postprocessors = self.postprocessors.clone();
+        let s = stream! {
+            let _input_tmp = input_tmp;
+            let _sandbox_dir = sandbox_dir;
+            // stdout is ignored, draining it also waits for the program to exit
+            tokio::io::copy(&mut stdout, &mut tokio::io::sink()).await?;
+            for path in list_files(output_dir.path()).await? {
+                let file = tokio::fs::File::open(output_dir.path().join(&path)).await?;
+                debug!("{}|{}", filepath_hint.display(), path.display());
+                let inp = apply_postprocessors(&postprocessors, Box::pin(file)).await?;
+                yield Ok(AdaptInfo {
+                    line_prefix: format!("{}{}: ", line_prefix, path.display()),
+                    filepath_hint: path,
+                    is_real_file: false,
+                    archive_recursion_depth: archive_recursion_depth + 1,
+                    inp,
+                    postprocess,
+                    config: config.clone(),
+                    limit_usage: limit_usage.clone(),
+                });
+            }
+            drop(output_dir);
+        };
+        Ok(Box::pin(s))
+    }
 }
 #[async_trait]
 impl FileAdapter for CustomSpawningFileAdapter {
@@ -246,22 +767,51 @@ impl FileAdapter for CustomSpawningFileAdapter {
         ai: AdaptInfo,
         _detection_reason: &FileMatcher,
     ) -> Result<AdaptedFilesIterBox> {
+        if self.output_files {
+            return self.spawn_output_files(ai).await;
+        }
         let AdaptInfo {
             filepath_hint,
+            is_real_file,
             inp,
             line_prefix,
             archive_recursion_depth,
             postprocess,
             config,
-            ..
+            limit_usage,
         } = ai;
 
-        let cmd = Command::new(&self.binary);
-        let cmd = self
-            .command(&filepath_hint, cmd)
-            .with_context(|| format!("Could not set cmd arguments for {}", self.binary))?;
-        debug!("executing {:?}", cmd);
-        let output = pipe_output(&line_prefix, cmd, inp, &self.binary, "")?;
+        let sandbox = self.sandboxed(&config);
+        let output = if let Some(max_concurrency) = self.persistent {
+            if sandbox {
+                // the program reads the paths it gets sent, they can't be allowed up front
+                return Err(format_err!(
+                    "{}: persistent adapters can't be sandboxed",
+                    self.meta.name
+                ));
+            }
+            if self.process_options.stderr == StderrMode::Capture {
+                return Err(format_err!(
+                    "{}: persistent adapters can't capture stderr, use inherit or discard",
+                    self.meta.name
+                ));
+            }
+            let worker_config = persistent::WorkerConfig {
+                binary: self.binary.clone(),
+                args: self.args.clone(),
+                env: self.env.clone(),
+                working_dir: self.working_dir.clone(),
+                stderr: self.process_options.stderr,
+                max_concurrency,
+            };
+            persistent::get_pool(&self.meta.name, &worker_config)
+                .convert(&filepath_hint.to_string_lossy(), inp)
+                .await?
+        } else {
+            self.spawn(&filepath_hint, is_real_file, inp, &line_prefix, sandbox)
+                .await?
+        };
+        let output = apply_postprocessors(&self.postprocessors, output).await?;
         Ok(one_file(AdaptInfo {
             filepath_hint: PathBuf::from(arg_replacer(
                 self.output_path_hint
@@ -275,6 +825,7 @@ impl FileAdapter for CustomSpawningFileAdapter {
             archive_recursion_depth: archive_recursion_depth + 1,
             postprocess,
             config,
+            limit_usage,
         }))
     }
 }
@@ -284,6 +835,19 @@ impl CustomAdapterConfig {
             binary: self.binary.clone(),
             args: self.args.clone(),
             output_path_hint: self.output_path_hint.clone(),
+            persistent: self
+                .persistent
+                .unwrap_or(false)
+                .then(|| self.max_concurrency.unwrap_or(1)),
+            env: self.env.clone().unwrap_or_default(),
+            working_dir: self.working_dir.clone(),
+            process_options: ProcessOptions {
+                timeout: self.timeout_secs.map(Duration::from_secs),
+                stderr: self.stderr.unwrap_or_default(),
+            },
+            output_files: self.output_files.unwrap_or(false),
+            postprocessors: self.postprocessors.clone().unwrap_or_default(),
+            sandbox: self.sandbox.unwrap_or(false),
             meta: AdapterMeta {
                 name: self.name.clone(),
                 version: self.version,
@@ -298,13 +862,22 @@ impl CustomAdapterConfig {
                     .extensions
                     .iter()
                     .map(|s| FastFileMatcher::FileExtension(s.to_string()))
+                    .chain(self.matchers.iter().flatten().cloned())
                     .collect(),
-                slow_matchers: self.mimetypes.as_ref().map(|mimetypes| {
-                    mimetypes
-                        .iter()
-                        .map(|s| FileMatcher::MimeType(s.to_string()))
-                        .collect()
-                }),
+                slow_matchers: (self.mimetypes.is_some() || self.magic_bytes.is_some()).then(
+                    || {
+                        self.mimetypes
+                            .iter()
+                            .flatten()
+                            .map(|s| FileMatcher::MimeType(s.to_string()))
+                            .chain(
+                                self.magic_bytes.iter().flatten().map(|m| {
+                                    FileMatcher::magic(m.offset, &unescape_bytes(&m.bytes))
+                                }),
+                            )
+                            .collect()
+                    },
+                ),
                 keep_fast_matchers_if_accurate: !self.match_only_by_mime.unwrap_or(false),
                 disabled_by_default: self.disabled_by_default.unwrap_or(false),
             },
@@ -365,6 +938,7 @@ PREFIX:Page 1:
             binary: "sed".to_string(),
             args: vec!["s/e/u/g".to_string()],
             output_path_hint: None,
+            ..Default::default()
         };
 
         let adapter = adapter.to_adapter();
@@ -390,4 +964,152 @@ PREFIX:Page 1:
         println!("output: {}", String::from_utf8_lossy(&oup));
         Ok(())
     }
+
+    fn file_adapter_config(args: &[&str]) -> CustomAdapterConfig {
+        CustomAdapterConfig {
+            name: "file_args".to_string(),
+            description: "".to_string(),
+            version: 1,
+            extensions: vec!["txt".to_string()],
+            binary: "sh".to_string(),
+            args: strs(args),
+            ..Default::default()
+        }
+    }
+    fn file_adapter(args: &[&str]) -> CustomSpawningFileAdapter {
+        file_adapter_config(args).to_adapter()
+    }
+
+    #[tokio::test]
+    async fn input_file() -> Result<()> {
+        let adapter = file_adapter(&[
+            "-c",
+            "cat \"$$1\"; echo \"$$1\" | grep -q '\\.txt$$'",
+            "sh",
+            "$input_file",
+        ]);
+        let (a, d) = simple_adapt_info(
+            Path::new("foo.txt"),
+            Box::pin(Cursor::new(b"from a temp file\n".to_vec())),
+        );
+        let output = adapted_to_vec(adapter.adapt(a, &d).await?).await?;
+        assert_eq!(String::from_utf8(output)?, "from a temp file\n");
+
+        let filepath = test_data_dir().join("short.pdf");
+        let adapter = file_adapter(&["-c", "echo \"$$1\"", "sh", "$input_file"]);
+        let (a, d) = simple_fs_adapt_info(&filepath).await?;
+        let output = adapted_to_vec(adapter.adapt(a, &d).await?).await?;
+        assert_eq!(
+            String::from_utf8(output)?,
+            format!("{}\n", filepath.to_string_lossy())
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn output_dir() -> Result<()> {
+        let adapter = file_adapter(&[
+            "-c",
+            "echo ignored; printf b > \"$$1/2.txt\"; cat > \"$$1/1.txt\"",
+            "sh",
+            "$output_dir",
+        ]);
+        let (a, d) = simple_adapt_info(Path::new("foo.txt"), Box::pin(Cursor::new(b"a".to_vec())));
+        let output = adapted_to_vec(adapter.adapt(a, &d).await?).await?;
+        assert_eq!(String::from_utf8(output)?, "ab");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn output_files() -> Result<()> {
+        let adapter = CustomAdapterConfig {
+            output_files: Some(true),
+            ..file_adapter_config(&[
+                "-c",
+                "mkdir \"$$1/sub\"; printf 'one\\n' > \"$$1/a.txt\"; printf 'two\\n' > \"$$1/sub/b.txt\"",
+                "sh",
+                "$output_dir",
+            ])
+        }
+        .to_adapter();
+        let (a, d) = simple_adapt_info(Path::new("foo.multi"), Box::pin(Cursor::new(vec![])));
+        let r = loop_adapt(&adapter, d, a).await?;
+        let o = adapted_to_vec(r).await?;
+        assert_eq!(
+            String::from_utf8(o)?,
+            "PREFIX:a.txt: one
+PREFIX:a.txt: 
+PREFIX:sub/b.txt: two
+PREFIX:sub/b.txt: 
+"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn postprocessors() -> Result<()> {
+        let adapter = CustomAdapterConfig {
+            postprocessors: Some(vec![Postprocessor::StripAnsi, Postprocessor::Pagebreaks]),
+            ..file_adapter_config(&["-c", "printf '\\033[1mbold\\033[0m\\nline\\fpage two'"])
+        }
+        .to_adapter();
+        let (a, d) = simple_adapt_info(Path::new("foo.txt"), Box::pin(Cursor::new(vec![])));
+        let output = adapted_to_vec(adapter.adapt(a, &d).await?).await?;
+        assert_eq!(
+            String::from_utf8(output)?,
+            "Page 1: bold\nPage 1: line\nPage 2: page two"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn env_and_working_dir() -> Result<()> {
+        let adapter = CustomAdapterConfig {
+            env: Some(BTreeMap::from([(
+                "RGA_TEST".to_string(),
+                "foo".to_string(),
+            )])),
+            working_dir: Some("/".to_string()),
+            ..file_adapter_config(&["-c", "echo $$RGA_TEST; pwd"])
+        }
+        .to_adapter();
+        let (a, d) = simple_adapt_info(Path::new("foo.txt"), Box::pin(Cursor::new(vec![])));
+        let output = adapted_to_vec(adapter.adapt(a, &d).await?).await?;
+        assert_eq!(String::from_utf8(output)?, "foo\n/\n");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn captured_stderr() -> Result<()> {
+        let adapter = CustomAdapterConfig {
+            stderr: Some(StderrMode::Capture),
+            ..file_adapter_config(&["-c", "echo broken input >&2; exit 3"])
+        }
+        .to_adapter();
+        let (a, d) = simple_adapt_info(Path::new("foo.txt"), Box::pin(Cursor::new(vec![])));
+        let err = adapted_to_vec(adapter.adapt(a, &d).await?)
+            .await
+            .expect_err("program fails");
+        let err = format!("{err:#}");
+        assert!(err.contains("exit status: 3"), "{err}");
+        assert!(err.contains("stderr:\nbroken input"), "{err}");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn timeout() -> Result<()> {
+        let adapter = CustomAdapterConfig {
+            timeout_secs: Some(1),
+            ..file_adapter_config(&["-c", "echo started; exec sleep 30"])
+        }
+        .to_adapter();
+        let (a, d) = simple_adapt_info(Path::new("foo.txt"), Box::pin(Cursor::new(vec![])));
+        let start = std::time::Instant::now();
+        let err = adapted_to_vec(adapter.adapt(a, &d).await?)
+            .await
+            .expect_err("program is killed");
+        assert!(format!("{err:#}").contains("timed out after 1s"), "{err:#}");
+        assert!(start.elapsed() < Duration::from_secs(20));
+        Ok(())
+    }
 }
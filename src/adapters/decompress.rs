@@ -1,12 +1,14 @@
 use crate::adapted_iter::one_file;
+use crate::limits::CountingReader;
 
 use super::*;
 
 use anyhow::Result;
 use lazy_static::lazy_static;
-use tokio::io::BufReader;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, BufReader};
 
 use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicU64;
 
 static EXTENSIONS: &[&str] = &["als", "bz2", "gz", "tbz", "tbz2", "tgz", "xz", "zst"];
 static MIME_TYPES: &[&str] = &[
@@ -15,6 +17,7 @@ static MIME_TYPES: &[&str] = &[
     "application/x-xz",
     "application/zstd",
 ];
+static MAGIC_BYTES: &[&[u8]] = &[b"\x1f\x8b", b"BZh", b"\xfd7zXZ\x00", b"\x28\xb5\x2f\xfd"];
 lazy_static! {
     static ref METADATA: AdapterMeta = AdapterMeta {
         name: "decompress".to_owned(),
@@ -31,6 +34,7 @@ lazy_static! {
             MIME_TYPES
                 .iter()
                 .map(|s| FileMatcher::MimeType(s.to_string()))
+                .chain(MAGIC_BYTES.iter().map(|m| FileMatcher::magic(0, m)))
                 .collect()
         ),
         disabled_by_default: false,
@@ -68,6 +72,10 @@ fn decompress_any(reason: &FileMatcher, inp: ReadBox) -> Result<ReadBox> {
             "xz" => xz(inp),
             ext => Err(format_err!("don't know how to decompress {}", ext))?,
         },
+        Fast(matcher) => Err(format_err!(
+            "don't know how to decompress a file matched by {:?}",
+            matcher
+        ))?,
         MimeType(mime) => match mime.as_ref() {
             "application/gzip" => gz(inp),
             "application/x-bzip" => bz2(inp),
@@ -75,8 +83,26 @@ fn decompress_any(reason: &FileMatcher, inp: ReadBox) -> Result<ReadBox> {
             "application/zstd" => zst(inp),
             mime => Err(format_err!("don't know how to decompress mime {}", mime))?,
         },
+        MagicBytes { bytes, .. } => match bytes.as_slice() {
+            b"\x1f\x8b" => gz(inp),
+            b"BZh" => bz2(inp),
+            b"\xfd7zXZ\x00" => xz(inp),
+            b"\x28\xb5\x2f\xfd" => zst(inp),
+            _ => Err(format_err!(
+                "don't know how to decompress magic {:?}",
+                bytes
+            ))?,
+        },
     })
 }
+/// the magic bytes matcher for the compression format of the input, if known
+async fn sniff_format(inp: &mut (impl AsyncBufRead + Unpin)) -> Result<Option<FileMatcher>> {
+    let head = inp.fill_buf().await?;
+    Ok(MAGIC_BYTES
+        .iter()
+        .find(|m| head.starts_with(m))
+        .map(|m| FileMatcher::magic(0, m)))
+}
 fn get_inner_filename(filename: &Path) -> PathBuf {
     let extension = filename
         .extension()
@@ -100,14 +126,34 @@ impl FileAdapter for DecompressAdapter {
         ai: AdaptInfo,
         detection_reason: &FileMatcher,
     ) -> Result<AdaptedFilesIterBox> {
+        // compressed bytes read so far, for the compression ratio limit
+        let compressed_size = Arc::new(AtomicU64::new(0));
+        let mut inp = BufReader::new(CountingReader::new(ai.inp, compressed_size.clone()));
+        let reason = match detection_reason {
+            FileMatcher::Fast(FastFileMatcher::FileExtension(ext))
+                if EXTENSIONS.contains(&ext.as_str()) =>
+            {
+                detection_reason.clone()
+            }
+            // e.g. an extension added in adapter_overrides, the format has to be detected from the content
+            FileMatcher::Fast(_) => sniff_format(&mut inp)
+                .await?
+                .unwrap_or_else(|| detection_reason.clone()),
+            _ => detection_reason.clone(),
+        };
         Ok(one_file(AdaptInfo {
             filepath_hint: get_inner_filename(&ai.filepath_hint),
             is_real_file: false,
             archive_recursion_depth: ai.archive_recursion_depth + 1,
-            inp: decompress_any(detection_reason, ai.inp)?,
+            inp: ai.config.limits.limit_ratio(
+                decompress_any(&reason, Box::pin(inp))?,
+                compressed_size,
+                &ai.limit_usage,
+            ),
             line_prefix: ai.line_prefix,
             config: ai.config.clone(),
             postprocess: ai.postprocess,
+            limit_usage: ai.limit_usage,
         }))
     }
 }
@@ -148,6 +194,20 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn unknown_extension() -> Result<()> {
+        let adapter = DecompressAdapter;
+
+        let filepath = test_data_dir().join("hello.gz");
+
+        let (a, _) = simple_adapt_info(&filepath, Box::pin(File::open(&filepath).await?));
+        let d: FileMatcher = FastFileMatcher::FileExtension("crate".to_string()).into();
+        let r = adapter.adapt(a, &d).await?;
+        let o = adapted_to_vec(r).await?;
+        assert_eq!(String::from_utf8(o)?, "hello\n");
+        Ok(())
+    }
+
     #[tokio::test]
     async fn pdf_gz() -> Result<()> {
         let adapter = DecompressAdapter;
@@ -1,5 +1,6 @@
 use super::*;
 use super::{custom::map_exe_error, writing::async_writeln};
+use crate::sandbox::{self, SandboxPolicy};
 use anyhow::*;
 use async_trait::async_trait;
 use lazy_static::lazy_static;
@@ -68,6 +69,7 @@ impl WritingFileAdapter for FFmpegAdapter {
             is_real_file,
             filepath_hint,
             line_prefix,
+            config,
             ..
         } = ai;
         if !is_real_file {
@@ -79,9 +81,19 @@ impl WritingFileAdapter for FFmpegAdapter {
             return Ok(());
         }
         let inp_fname = filepath_hint;
+        // ffprobe and ffmpeg only need to read the file, the output goes to stdout
+        let sandbox_policy = sandbox::enabled_for(&config, &METADATA.name).then(|| SandboxPolicy {
+            read: vec![inp_fname.clone()],
+            write: vec![],
+        });
+        let restrict = |cmd: &mut Command| match &sandbox_policy {
+            Some(policy) => sandbox::restrict(cmd, policy).context("sandboxing ffmpeg"),
+            None => Ok(()),
+        };
         let spawn_fail = |e| map_exe_error(e, "ffprobe", "Make sure you have ffmpeg installed.");
         let subtitle_streams = {
-            let probe = Command::new("ffprobe")
+            let mut probe = Command::new("ffprobe");
+            probe
                 .args(vec![
                     "-v",
                     "error", // show all errors
@@ -94,9 +106,9 @@ impl WritingFileAdapter for FFmpegAdapter {
                 ])
                 .arg("-i")
                 .arg(&inp_fname)
-                .output()
-                .await
-                .map_err(spawn_fail)?;
+                .kill_on_drop(true);
+            restrict(&mut probe)?;
+            let probe = probe.output().await.map_err(spawn_fail)?;
             if !probe.status.success() {
                 return Err(format_err!(
                     "ffprobe failed: {:?}\n{}",
@@ -109,7 +121,8 @@ impl WritingFileAdapter for FFmpegAdapter {
         };
         {
             // extract file metadata (especially chapter names in a greppable format)
-            let mut probe = Command::new("ffprobe")
+            let mut probe = Command::new("ffprobe");
+            probe
                 .args(vec![
                     "-v",
                     "error",
@@ -127,7 +140,9 @@ impl WritingFileAdapter for FFmpegAdapter {
                 .arg("-i")
                 .arg(&inp_fname)
                 .stdout(Stdio::piped())
-                .spawn()?;
+                .kill_on_drop(true);
+            restrict(&mut probe)?;
+            let mut probe = probe.spawn()?;
             let mut lines = BufReader::new(probe.stdout.as_mut().unwrap()).lines();
             while let Some(line) = lines.next_line().await? {
                 let line = line.replace("\\r\\n", "\n").replace("\\n", "\n"); // just unescape newlines
@@ -153,7 +168,12 @@ impl WritingFileAdapter for FFmpegAdapter {
                     .arg("-f")
                     .arg("webvtt")
                     .arg("-");
-                let mut cmd = cmd.stdout(Stdio::piped()).spawn().map_err(spawn_fail)?;
+                restrict(&mut cmd)?;
+                let mut cmd = cmd
+                    .stdout(Stdio::piped())
+                    .kill_on_drop(true)
+                    .spawn()
+                    .map_err(spawn_fail)?;
                 let stdo = cmd.stdout.as_mut().expect("is piped");
                 let mut time: String = "".to_owned();
                 // rewrite subtitle times so they are shown as a prefix in every line
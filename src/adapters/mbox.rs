@@ -62,6 +62,7 @@ impl FileAdapter for MboxAdapter {
             archive_recursion_depth,
             config,
             postprocess,
+            limit_usage,
             ..
         } = ai;
 
@@ -115,6 +116,7 @@ impl FileAdapter for MboxAdapter {
                     line_prefix: line_prefix.to_string(),
                     config,
                     postprocess,
+                    limit_usage: limit_usage.clone(),
                 };
                 ais.push(ai2);
                 }
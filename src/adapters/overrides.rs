@@ -0,0 +1,183 @@
+/*!
+ * Changes to the matchers and priority of existing adapters from the `adapter_overrides` config,
+ * e.g. to route `.whl` files to the zip adapter without defining a custom adapter.
+ */
+use super::*;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default, JsonSchema)]
+pub struct AdapterOverride {
+    /// the name of the adapter to change, as shown by `--rga-list-adapters`
+    pub name: String,
+    /// additional file extensions (without the dot) the adapter should be used for
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub add_extensions: Vec<String>,
+    /// file extensions the adapter should no longer be used for
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub remove_extensions: Vec<String>,
+    /// additional mime types the adapter should be used for with `--rga-accurate`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub add_mimetypes: Vec<String>,
+    /// mime types the adapter should no longer be used for
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub remove_mimetypes: Vec<String>,
+    /// replaces the priority of the adapter. Adapters with a higher priority are tried first.
+    /// Builtin adapters have priority 0, custom adapters 100.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub priority: Option<i32>,
+}
+
+impl AdapterOverride {
+    fn changes_matchers(&self) -> bool {
+        !(self.add_extensions.is_empty()
+            && self.remove_extensions.is_empty()
+            && self.add_mimetypes.is_empty()
+            && self.remove_mimetypes.is_empty())
+    }
+}
+
+/// an adapter with the matchers changed by an `AdapterOverride`
+pub struct OverriddenAdapter {
+    inner: Arc<dyn FileAdapter>,
+    meta: AdapterMeta,
+}
+
+impl OverriddenAdapter {
+    pub fn new(inner: Arc<dyn FileAdapter>, o: &AdapterOverride) -> Self {
+        let m = inner.metadata();
+        let fast_matchers = m
+            .fast_matchers
+            .iter()
+            .filter(|matcher| match matcher {
+                FastFileMatcher::FileExtension(ext) => !o
+                    .remove_extensions
+                    .iter()
+                    .any(|r| r.eq_ignore_ascii_case(ext)),
+                _ => true,
+            })
+            .cloned()
+            .chain(
+                o.add_extensions
+                    .iter()
+                    .map(|ext| FastFileMatcher::FileExtension(ext.clone())),
+            )
+            .collect();
+        let slow_matchers: Vec<FileMatcher> = m
+            .slow_matchers
+            .iter()
+            .flatten()
+            .filter(|matcher| match matcher {
+                FileMatcher::MimeType(mime) => !o.remove_mimetypes.contains(mime),
+                _ => true,
+            })
+            .cloned()
+            .chain(o.add_mimetypes.iter().cloned().map(FileMatcher::MimeType))
+            .collect();
+        Self {
+            meta: AdapterMeta {
+                name: m.name.clone(),
+                version: m.version,
+                description: m.description.clone(),
+                recurses: m.recurses,
+                fast_matchers,
+                slow_matchers: if slow_matchers.is_empty() && m.slow_matchers.is_none() {
+                    None
+                } else {
+                    Some(slow_matchers)
+                },
+                keep_fast_matchers_if_accurate: m.keep_fast_matchers_if_accurate,
+                disabled_by_default: m.disabled_by_default,
+            },
+            inner,
+        }
+    }
+}
+
+impl GetMetadata for OverriddenAdapter {
+    fn metadata(&self) -> &AdapterMeta {
+        &self.meta
+    }
+}
+
+#[async_trait]
+impl FileAdapter for OverriddenAdapter {
+    async fn adapt(
+        &self,
+        ai: AdaptInfo,
+        detection_reason: &FileMatcher,
+    ) -> Result<AdaptedFilesIterBox> {
+        self.inner.adapt(ai, detection_reason).await
+    }
+}
+
+/// apply the overrides to the (priority, adapter) list. overrides for unknown adapters are ignored with a warning
+pub fn apply_overrides(
+    adapters: &mut [(i32, Arc<dyn FileAdapter>)],
+    overrides: &[AdapterOverride],
+) {
+    for o in overrides {
+        let Some((priority, adapter)) = adapters
+            .iter_mut()
+            .find(|(_, a)| a.metadata().name == o.name)
+        else {
+            warn!("adapter_overrides: unknown adapter {}", o.name);
+            continue;
+        };
+        if let Some(p) = o.priority {
+            *priority = p;
+        }
+        if o.changes_matchers() {
+            *adapter = Arc::new(OverriddenAdapter::new(adapter.clone(), o));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::DetectionMode;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn overrides() -> Result<()> {
+        let (enabled, _) = get_all_adapters(
+            None,
+            &[
+                AdapterOverride {
+                    name: "zip".to_string(),
+                    add_extensions: vec!["whl".to_string()],
+                    remove_extensions: vec!["jar".to_string()],
+                    ..Default::default()
+                },
+                AdapterOverride {
+                    name: "sqlite".to_string(),
+                    add_mimetypes: vec!["application/x-foo".to_string()],
+                    priority: Some(2000),
+                    ..Default::default()
+                },
+            ],
+        );
+        assert_eq!(enabled[0].metadata().name, "sqlite");
+        assert!(
+            enabled[0]
+                .metadata()
+                .slow_matchers
+                .iter()
+                .flatten()
+                .any(|m| matches!(m, FileMatcher::MimeType(mime) if mime == "application/x-foo"))
+        );
+
+        let matcher = adapter_matcher(&enabled, DetectionMode::Fast)?;
+        let meta = |name: &str| FileMeta {
+            lossy_filename: name.to_string(),
+            lossy_path: name.to_string(),
+            mimetype: None,
+            head: None,
+        };
+        let (adapter, _) = matcher(meta("foo-1.0-py3-none-any.whl")).expect("whl should match");
+        assert_eq!(adapter.metadata().name, "zip");
+        assert!(matcher(meta("foo.jar")).is_none());
+        Ok(())
+    }
+}
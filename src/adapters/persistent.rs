@@ -0,0 +1,504 @@
+/*!
+ * Protocol for custom adapters that are started once and then convert many files
+ * (`"persistent": true` in the custom adapter config). Useful for extractors with a slow startup (JVM, Python).
+ *
+ * All messages are frames: a 4 byte big endian length followed by that many bytes.
+ * For every file, rga sends to stdin of the worker:
+ *
+ * 1. a frame with a JSON request header: `{"id": 1, "path": "foo/bar.docx"}`.
+ *    `path` is the virtual path of the file and may not exist on disk.
+ * 2. the contents of the file as any number of non-empty frames, terminated by an empty frame.
+ *
+ * The worker answers on stdout with:
+ *
+ * 1. a frame with a JSON response header: `{"id": 1}` on success or `{"id": 1, "error": "message"}` on failure.
+ * 2. if successful, the converted output as any number of non-empty frames, terminated by an empty frame.
+ *
+ * The worker may start answering before it has received the whole file, but it must always read the request
+ * up to the terminating empty frame, even if it fails. Requests are sent one at a time per worker process.
+ * The worker should exit when its stdin is closed. stderr is passed through, unless `stderr` is `discard` in the adapter config
+ * (`capture` is not available, since the output is not tied to a single file).
+ *
+ * Workers that crashed are replaced by a new process on the next request. Workers that are still busy when
+ * the output of their request is dropped are killed (with all their subprocesses), since they can't be interrupted.
+ */
+use super::ReadBox;
+use super::custom::{StderrMode, map_exe_error};
+use crate::process_group::{self, ProcessGroup};
+use crate::to_io_err;
+use anyhow::{Context, Result, format_err};
+use async_stream::stream;
+use bytes::Bytes;
+use lazy_static::lazy_static;
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, BufWriter};
+use tokio::process::{Child, ChildStdin, ChildStdout, Command};
+use tokio::sync::Semaphore;
+use tokio::task::AbortHandle;
+use tokio_util::io::StreamReader;
+
+/// sanity limit so a misbehaving worker can't make us allocate arbitrary amounts of memory
+const MAX_FRAME_LEN: usize = 64 * 1024 * 1024;
+
+pub async fn write_frame(w: &mut (impl AsyncWrite + Unpin), data: &[u8]) -> std::io::Result<()> {
+    let len = u32::try_from(data.len()).map_err(|_| std::io::Error::other("frame too large"))?;
+    w.write_all(&len.to_be_bytes()).await?;
+    w.write_all(data).await
+}
+
+pub async fn read_frame(r: &mut (impl AsyncRead + Unpin)) -> std::io::Result<Vec<u8>> {
+    let mut len = [0u8; 4];
+    r.read_exact(&mut len).await?;
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_FRAME_LEN {
+        return Err(std::io::Error::other(format!(
+            "frame of {len} bytes exceeds maximum length"
+        )));
+    }
+    let mut buf = vec![0u8; len];
+    r.read_exact(&mut buf).await?;
+    Ok(buf)
+}
+
+#[derive(Serialize)]
+struct RequestHeader<'a> {
+    id: u64,
+    path: &'a str,
+}
+
+#[derive(Deserialize)]
+struct ResponseHeader {
+    id: u64,
+    #[serde(default)]
+    error: Option<String>,
+}
+
+struct Worker {
+    child: Child,
+    group: ProcessGroup,
+    stdin: BufWriter<ChildStdin>,
+    stdout: BufReader<ChildStdout>,
+    next_id: u64,
+}
+
+/// the process of a worker while it converts a file. kills it if dropped before the conversion is done
+struct BusyWorker {
+    child: Option<Child>,
+    writer: AbortHandle,
+}
+
+impl BusyWorker {
+    /// the conversion is done, the worker can be used again
+    fn done(mut self) -> Child {
+        self.child.take().expect("only taken once")
+    }
+}
+
+impl Drop for BusyWorker {
+    fn drop(&mut self) {
+        let Some(child) = self.child.take() else {
+            return;
+        };
+        debug!("output of persistent adapter dropped during a conversion, killing it");
+        self.writer.abort();
+        #[cfg(unix)]
+        if let Some(pid) = child.id() {
+            // SAFETY: only sends a signal, the group id is the pid of our own child, which is not reaped yet
+            unsafe {
+                libc::killpg(pid as libc::pid_t, libc::SIGKILL);
+            }
+        }
+        // kill_on_drop
+        drop(child);
+    }
+}
+
+/// how the workers of a persistent adapter are started. the adapter config is read for every file,
+/// so a changed config gets its own pool
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct WorkerConfig {
+    pub binary: String,
+    pub args: Vec<String>,
+    pub env: BTreeMap<String, String>,
+    pub working_dir: Option<String>,
+    /// `Capture` is treated as `Inherit`
+    pub stderr: StderrMode,
+    pub max_concurrency: usize,
+}
+
+pub struct WorkerPool {
+    config: WorkerConfig,
+    idle: Mutex<Vec<Worker>>,
+    permits: Arc<Semaphore>,
+}
+
+/// adapter name and worker config
+type PoolKey = (String, WorkerConfig);
+
+lazy_static! {
+    // adapters are recreated for every file, so the pools live here
+    static ref POOLS: Mutex<HashMap<PoolKey, Arc<WorkerPool>>> = Mutex::new(HashMap::new());
+}
+
+/// get the worker pool for the given adapter, creating it if necessary
+pub fn get_pool(name: &str, config: &WorkerConfig) -> Arc<WorkerPool> {
+    let mut pools = POOLS.lock().expect("worker pools poisoned");
+    pools
+        .entry((name.to_string(), config.clone()))
+        .or_insert_with(|| {
+            Arc::new(WorkerPool {
+                config: config.clone(),
+                idle: Mutex::new(vec![]),
+                permits: Arc::new(Semaphore::new(config.max_concurrency.max(1))),
+            })
+        })
+        .clone()
+}
+
+impl WorkerPool {
+    fn spawn_worker(&self) -> Result<Worker> {
+        let config = &self.config;
+        debug!(
+            "starting persistent adapter {} {:?}",
+            config.binary, config.args
+        );
+        let mut cmd = Command::new(&config.binary);
+        cmd.args(&config.args).envs(&config.env);
+        if let Some(working_dir) = &config.working_dir {
+            cmd.current_dir(working_dir);
+        }
+        // a new process group, so everything the worker started can be killed if a conversion is abandoned
+        process_group::configure(&mut cmd);
+        let mut child = cmd
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(match config.stderr {
+                StderrMode::Inherit | StderrMode::Capture => Stdio::inherit(),
+                StderrMode::Discard => Stdio::null(),
+            })
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| map_exe_error(e, &config.binary, ""))?;
+        let group = ProcessGroup::track(&child);
+        let stdin = child.stdin.take().expect("is piped");
+        let stdout = child.stdout.take().expect("is piped");
+        Ok(Worker {
+            child,
+            group,
+            stdin: BufWriter::new(stdin),
+            stdout: BufReader::new(stdout),
+            next_id: 1,
+        })
+    }
+
+    /// an idle worker that is still running, or a new one
+    fn take_worker(&self) -> Result<Worker> {
+        let mut idle = self.idle.lock().expect("worker pool poisoned");
+        while let Some(mut worker) = idle.pop() {
+            match worker.child.try_wait() {
+                Ok(None) => return Ok(worker),
+                Ok(Some(status)) => warn!(
+                    "persistent adapter {} exited ({}), restarting",
+                    self.config.binary, status
+                ),
+                Err(e) => warn!(
+                    "persistent adapter {}: {}, restarting",
+                    self.config.binary, e
+                ),
+            }
+        }
+        drop(idle);
+        self.spawn_worker()
+    }
+
+    fn put_worker(&self, worker: Worker) {
+        self.idle.lock().expect("worker pool poisoned").push(worker);
+    }
+
+    /// send the request header, retrying once with a fresh process if the worker died in the meantime
+    async fn start_request(&self, path: &str) -> Result<(Worker, u64)> {
+        let mut retried = false;
+        loop {
+            let mut worker = self.take_worker()?;
+            let id = worker.next_id;
+            worker.next_id += 1;
+            let header = serde_json::to_vec(&RequestHeader { id, path })?;
+            let res = async {
+                write_frame(&mut worker.stdin, &header).await?;
+                worker.stdin.flush().await
+            }
+            .await;
+            match res {
+                Ok(()) => return Ok((worker, id)),
+                Err(e) if !retried => {
+                    warn!(
+                        "persistent adapter {} not accepting requests ({}), restarting",
+                        self.config.binary, e
+                    );
+                    retried = true;
+                }
+                Err(e) => {
+                    return Err(e).with_context(|| {
+                        format!(
+                            "sending request to persistent adapter {}",
+                            self.config.binary
+                        )
+                    });
+                }
+            }
+        }
+    }
+
+    /// convert one file. the worker is returned to the pool once the output has been read completely
+    pub async fn convert(self: Arc<Self>, path: &str, inp: ReadBox) -> Result<ReadBox> {
+        let permit = self.permits.clone().acquire_owned().await?;
+        let (worker, id) = self.start_request(path).await?;
+        let binary = self.config.binary.clone();
+        let Worker {
+            child,
+            group,
+            stdin,
+            mut stdout,
+            next_id,
+        } = worker;
+        let writer = tokio::spawn(async move {
+            let mut inp = inp;
+            let mut stdin = stdin;
+            let mut buf = vec![0u8; 1 << 16];
+            loop {
+                let n = inp.read(&mut buf).await?;
+                write_frame(&mut stdin, &buf[..n]).await?;
+                if n == 0 {
+                    break;
+                }
+            }
+            stdin.flush().await?;
+            std::io::Result::Ok(stdin)
+        });
+        let busy = BusyWorker {
+            child: Some(child),
+            writer: writer.abort_handle(),
+        };
+        let s = stream! {
+            let _permit = permit;
+            let header = read_frame(&mut stdout).await?;
+            let header: ResponseHeader = serde_json::from_slice(&header)
+                .context("invalid response header")
+                .map_err(to_io_err)?;
+            if header.id != id {
+                Err(format_err!("expected response to request {}, got {}", id, header.id)).map_err(to_io_err)?;
+            }
+            if let Some(error) = header.error {
+                // the worker is still usable, it has to read the rest of the request anyway
+                let stdin = writer.await.map_err(std::io::Error::other)??;
+                let child = busy.done();
+                self.put_worker(Worker { child, group, stdin, stdout, next_id });
+                Err(format_err!("{}: {}", binary, error)).map_err(to_io_err)?;
+            } else {
+                loop {
+                    let frame = read_frame(&mut stdout).await?;
+                    if frame.is_empty() {
+                        break;
+                    }
+                    yield std::io::Result::Ok(Bytes::from(frame));
+                }
+                let stdin = writer.await.map_err(std::io::Error::other)??;
+                let child = busy.done();
+                self.put_worker(Worker { child, group, stdin, stdout, next_id });
+            }
+        };
+        Ok(Box::pin(StreamReader::new(s)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor;
+
+    #[tokio::test]
+    async fn framing() -> Result<()> {
+        let mut buf = vec![];
+        write_frame(&mut buf, b"{\"id\":1}").await?;
+        write_frame(&mut buf, b"").await?;
+        assert_eq!(&buf[..4], &[0, 0, 0, 8]);
+
+        let mut r = Cursor::new(buf);
+        assert_eq!(read_frame(&mut r).await?, b"{\"id\":1}");
+        assert_eq!(read_frame(&mut r).await?, b"");
+        assert!(read_frame(&mut r).await.is_err());
+
+        let mut r = Cursor::new(vec![0xff, 0xff, 0xff, 0xff]);
+        assert!(read_frame(&mut r).await.is_err());
+        Ok(())
+    }
+
+    /// answers with its pid and the uppercased input, exits on the input "crash".
+    /// on "hang", starts a subprocess, answers with its pid and hangs. on "env", answers with `$RGA_TEST` and its working directory
+    const WORKER: &str = r#"
+import json, os, struct, subprocess, sys, time
+inp, out = sys.stdin.buffer, sys.stdout.buffer
+def read():
+    n = inp.read(4)
+    if len(n) < 4:
+        sys.exit(0)
+    return inp.read(struct.unpack(">I", n)[0])
+def write(b):
+    out.write(struct.pack(">I", len(b)) + b)
+while True:
+    req = json.loads(read())
+    data = b""
+    while True:
+        frame = read()
+        if not frame:
+            break
+        data += frame
+    if data == b"crash":
+        sys.exit(1)
+    if data == b"env":
+        data = ("%s %s" % (os.environ.get("RGA_TEST"), os.getcwd())).encode()
+    if data == b"hang":
+        sub = subprocess.Popen(["sleep", "60"])
+        write(json.dumps({"id": req["id"]}).encode())
+        write(b"%d:" % sub.pid)
+        out.flush()
+        time.sleep(60)
+    write(json.dumps({"id": req["id"]}).encode())
+    write(b"%d:" % os.getpid() + data.upper())
+    write(b"")
+    out.flush()
+"#;
+
+    fn worker_config() -> WorkerConfig {
+        WorkerConfig {
+            binary: "python3".to_string(),
+            args: vec!["-c".to_string(), WORKER.to_string()],
+            env: BTreeMap::new(),
+            working_dir: None,
+            stderr: StderrMode::Inherit,
+            max_concurrency: 1,
+        }
+    }
+
+    async fn convert(pool: &Arc<WorkerPool>, data: &str) -> Result<String> {
+        let inp = Box::pin(Cursor::new(data.as_bytes().to_vec()));
+        let mut out = String::new();
+        pool.clone()
+            .convert("test.txt", inp)
+            .await?
+            .read_to_string(&mut out)
+            .await?;
+        Ok(out)
+    }
+
+    #[tokio::test]
+    async fn worker() -> Result<()> {
+        let pool = get_pool("persistent-test", &worker_config());
+        let first = convert(&pool, "hello").await?;
+        let (pid, text) = first.split_once(':').expect("pid prefix");
+        assert_eq!(text, "HELLO");
+
+        // the same process is used again
+        let second = convert(&pool, "world").await?;
+        assert_eq!(second, format!("{pid}:WORLD"));
+
+        // a crashed worker is replaced
+        assert!(convert(&pool, "crash").await.is_err());
+        let third = convert(&pool, "again").await?;
+        let (new_pid, text) = third.split_once(':').expect("pid prefix");
+        assert_ne!(new_pid, pid);
+        assert_eq!(text, "AGAIN");
+
+        // only max_concurrency conversions at the same time, the next one waits until the output is read
+        let running = pool
+            .clone()
+            .convert("a.txt", Box::pin(Cursor::new(b"a".to_vec())))
+            .await?;
+        let waiting = pool
+            .clone()
+            .convert("b.txt", Box::pin(Cursor::new(b"b".to_vec())));
+        tokio::pin!(waiting);
+        assert!(
+            tokio::time::timeout(std::time::Duration::from_millis(200), &mut waiting)
+                .await
+                .is_err()
+        );
+        drop(running);
+        let mut out = String::new();
+        waiting.await?.read_to_string(&mut out).await?;
+        assert!(out.ends_with(":B"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn worker_config_options() -> Result<()> {
+        let config = WorkerConfig {
+            env: BTreeMap::from([("RGA_TEST".to_string(), "foo".to_string())]),
+            working_dir: Some("/".to_string()),
+            ..worker_config()
+        };
+        let pool = get_pool("persistent-test-config", &config);
+        let out = convert(&pool, "env").await?;
+        assert!(out.ends_with(":FOO /"), "{out}");
+
+        // a changed config gets a new pool
+        let more = WorkerConfig {
+            max_concurrency: 2,
+            ..config.clone()
+        };
+        assert!(Arc::ptr_eq(
+            &pool,
+            &get_pool("persistent-test-config", &config)
+        ));
+        assert!(!Arc::ptr_eq(
+            &pool,
+            &get_pool("persistent-test-config", &more)
+        ));
+        Ok(())
+    }
+
+    /// zombies count as exited, they might never be reaped in a container
+    #[cfg(target_os = "linux")]
+    fn exited(pid: &str) -> bool {
+        match std::fs::read_to_string(format!("/proc/{pid}/stat")) {
+            Ok(stat) => stat
+                .rsplit_once(')')
+                .is_some_and(|(_, state)| state.trim_start().starts_with('Z')),
+            Err(_) => true,
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn abandoned() -> Result<()> {
+        let pool = get_pool("persistent-test-abandoned", &worker_config());
+        let mut out = pool
+            .clone()
+            .convert("hang.txt", Box::pin(Cursor::new(b"hang".to_vec())))
+            .await?;
+        let mut buf = [0u8; 64];
+        let n = out.read(&mut buf).await?;
+        let sub_pid = std::str::from_utf8(&buf[..n])?
+            .trim_end_matches(':')
+            .to_string();
+        assert!(!exited(&sub_pid));
+
+        // the worker is still busy, so it is killed together with its subprocess
+        drop(out);
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+        while !exited(&sub_pid) {
+            assert!(
+                std::time::Instant::now() < deadline,
+                "subprocess of the worker still running"
+            );
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        }
+        // and replaced for the next file
+        assert!(convert(&pool, "hello").await?.ends_with(":HELLO"));
+        Ok(())
+    }
+}
@@ -8,6 +8,8 @@ use async_trait::async_trait;
 use bytes::Bytes;
 use encoding_rs::Encoding;
 use encoding_rs_io::DecodeReaderBytesBuilder;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
 use tokio_util::io::SyncIoBridge;
 
 use std::io::Cursor;
@@ -21,7 +23,34 @@ use crate::adapted_iter::AdaptedFilesIterBox;
 use crate::adapted_iter::one_file;
 use crate::matching::FastFileMatcher;
 
-use super::{AdaptInfo, AdapterMeta, FileAdapter, GetMetadata};
+use super::{AdaptInfo, AdapterMeta, FileAdapter, GetMetadata, ReadBox};
+
+/// A filter that custom adapters can apply to the output of their program (`postprocessors` in the config)
+#[derive(Debug, Deserialize, Serialize, JsonSchema, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum Postprocessor {
+    /// Adds "Page N: " to each line, with pages separated by ASCII form feeds (as output by pdftotext)
+    Pagebreaks,
+    /// Converts UTF-16 to UTF-8 and replaces binary output with `[rga: binary data]`
+    Encoding,
+    /// Removes ANSI escape sequences (colors, cursor movement)
+    StripAnsi,
+}
+
+/// Applies the given postprocessors in order.
+pub async fn apply_postprocessors(
+    postprocessors: &[Postprocessor],
+    mut inp: ReadBox,
+) -> Result<ReadBox> {
+    for postprocessor in postprocessors {
+        inp = match postprocessor {
+            Postprocessor::Pagebreaks => Box::pin(postproc_pagebreaks(inp)),
+            Postprocessor::Encoding => postproc_encoding("", inp).await?,
+            Postprocessor::StripAnsi => Box::pin(postproc_strip_ansi(inp)),
+        };
+    }
+    Ok(inp)
+}
 
 fn add_newline(ar: impl AsyncRead + Send) -> impl AsyncRead + Send {
     ar.chain(Cursor::new(b"\n"))
@@ -164,7 +193,7 @@ impl GetMetadata for PostprocPageBreaks {
             static ref METADATA: AdapterMeta = AdapterMeta {
                 name: "postprocpagebreaks".to_owned(),
                 version: 1,
-                description: "Adds the page number to each line for an input file that specifies page breaks as ascii page break character.\nMainly to be used internally by the poppler adapter.".to_owned(),
+                description: "Adds the page number to each line for an input file that specifies page breaks as ascii page break character.\nCustom adapters can use the `pagebreaks` postprocessor instead.".to_owned(),
                 recurses: false,
                 fast_matchers: vec![FastFileMatcher::FileExtension("asciipagebreaks".to_string())],
                 slow_matchers: None,
@@ -241,6 +270,50 @@ pub fn postproc_pagebreaks(input: impl AsyncRead + Send) -> impl AsyncRead + Sen
     Box::pin(StreamReader::new(output_stream))
 }
 
+/// Removes ANSI escape sequences: CSI sequences like colors (`ESC [ ... m`),
+/// OSC sequences like terminal titles (`ESC ] ... BEL`) and two-byte escapes.
+pub fn postproc_strip_ansi(input: impl AsyncRead + Send) -> impl AsyncRead + Send {
+    #[derive(Clone, Copy)]
+    enum State {
+        Text,
+        Escape,
+        Csi,
+        Osc,
+        OscEscape,
+    }
+    let input_stream = ReaderStream::new(input);
+    let output_stream = stream! {
+        // the state is kept across chunks since a sequence can be split between them
+        let mut state = State::Text;
+        for await read_chunk in input_stream {
+            let read_chunk = read_chunk?;
+            let mut out = Vec::with_capacity(read_chunk.len());
+            for &b in read_chunk.iter() {
+                state = match (state, b) {
+                    (State::Text, 0x1b) => State::Escape,
+                    (State::Text, b) => {
+                        out.push(b);
+                        State::Text
+                    }
+                    (State::Escape, b'[') => State::Csi,
+                    (State::Escape, b']') => State::Osc,
+                    (State::Escape, _) => State::Text,
+                    (State::Csi, 0x40..=0x7e) => State::Text,
+                    (State::Csi, _) => State::Csi,
+                    (State::Osc, 0x07) => State::Text,
+                    (State::Osc, 0x1b) => State::OscEscape,
+                    (State::Osc, _) => State::Osc,
+                    (State::OscEscape, _) => State::Text,
+                };
+            }
+            if !out.is_empty() {
+                yield std::io::Result::Ok(Bytes::from(out));
+            }
+        }
+    };
+    Box::pin(StreamReader::new(output_stream))
+}
+
 #[cfg(test)]
 mod tests {
     use crate::preproc::loop_adapt;
@@ -392,6 +465,30 @@ PREFIX:Page 3:
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_strip_ansi() -> Result<()> {
+        let mut output: Vec<u8> = Vec::new();
+        let mock: Mock = Builder::new()
+            .read(b"\x1b[1;31mred\x1b")
+            .read(b"[0m plain\n\x1b]0;title\x07text\x1b]8;;http://x\x1b\\link\x1bc")
+            .build();
+        postproc_strip_ansi(mock).read_to_end(&mut output).await?;
+        assert_eq!(String::from_utf8(output)?, "red plain\ntextlink");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_apply_postprocessors() -> Result<()> {
+        let mut output: Vec<u8> = Vec::new();
+        let inp = Box::pin(Cursor::new(b"\x1b[32mone\x0ctwo".to_vec()));
+        apply_postprocessors(&[Postprocessor::StripAnsi, Postprocessor::Pagebreaks], inp)
+            .await?
+            .read_to_end(&mut output)
+            .await?;
+        assert_eq!(String::from_utf8(output)?, "Page 1: one\nPage 2: two");
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_binary_content() -> Result<()> {
         test_from_strs(
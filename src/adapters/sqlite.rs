@@ -24,9 +24,10 @@ lazy_static! {
             .iter()
             .map(|s| FastFileMatcher::FileExtension(s.to_string()))
             .collect(),
-        slow_matchers: Some(vec![FileMatcher::MimeType(
-            "application/x-sqlite3".to_owned()
-        )]),
+        slow_matchers: Some(vec![
+            FileMatcher::MimeType("application/x-sqlite3".to_owned()),
+            FileMatcher::magic(0, b"SQLite format 3\0")
+        ]),
         keep_fast_matchers_if_accurate: false,
         disabled_by_default: false
     };
@@ -1,15 +1,14 @@
 use crate::{
-    adapted_iter::AdaptedFilesIterBox,
+    adapted_iter::{AdaptedFilesIterBox, marker_file},
     adapters::AdapterMeta,
     matching::{FastFileMatcher, FileMatcher},
     print_bytes,
 };
-use anyhow::*;
+use anyhow::Result;
 use async_stream::stream;
 use async_trait::async_trait;
 use lazy_static::lazy_static;
 use log::*;
-use std::path::PathBuf;
 
 use tokio_stream::StreamExt;
 
@@ -27,7 +26,8 @@ lazy_static! {
             .iter()
             .map(|s| FastFileMatcher::FileExtension(s.to_string()))
             .collect(),
-        slow_matchers: None,
+        // the mime type detection does not work for tar, but the magic is at a fixed offset
+        slow_matchers: Some(vec![FileMatcher::magic(257, b"ustar")]),
         keep_fast_matchers_if_accurate: true,
         disabled_by_default: false
     };
@@ -60,16 +60,49 @@ impl FileAdapter for TarAdapter {
             archive_recursion_depth,
             config,
             postprocess,
+            limit_usage,
             ..
         } = ai;
         let mut archive = ::tokio_tar::Archive::new(inp);
 
         let mut entries = archive.entries()?;
         let s = stream! {
+            let mut index = 0;
+            let mut members = 0;
             while let Some(entry) = entries.next().await {
-                let file = entry?;
+                index += 1;
+                let file = match entry {
+                    Ok(file) => file,
+                    Err(e) => {
+                        // the position of the next header is unknown, so report how far we got
+                        yield Ok(marker_file(
+                            format!("[rga: tar truncated or corrupt, stopped after {members} files: {e}]"),
+                            line_prefix.clone(),
+                            archive_recursion_depth + 1,
+                            postprocess,
+                            config.clone(),
+                            limit_usage.clone(),
+                        ));
+                        break;
+                    }
+                };
                 if tokio_tar::EntryType::Regular == file.header().entry_type() {
-                    let path = PathBuf::from(file.path()?.to_owned());
+                    let path = match file.path() {
+                        Ok(path) => path.into_owned(),
+                        Err(e) => {
+                            // the data of this member is skipped with the next entry
+                            yield Ok(marker_file(
+                                format!("[rga: error reading member #{index}: {e}]"),
+                                line_prefix.clone(),
+                                archive_recursion_depth + 1,
+                                postprocess,
+                                config.clone(),
+                                limit_usage.clone(),
+                            ));
+                            continue;
+                        }
+                    };
+                    members += 1;
                     debug!(
                         "{}|{}: {}",
                         filepath_hint.display(),
@@ -85,6 +118,7 @@ impl FileAdapter for TarAdapter {
                         line_prefix: line_prefix.to_string(),
                         config: config.clone(),
                         postprocess,
+                        limit_usage: limit_usage.clone(),
                     };
                     yield Ok(ai2);
                 }
@@ -99,9 +133,42 @@ impl FileAdapter for TarAdapter {
 mod tests {
     use super::*;
     use crate::{preproc::loop_adapt, test_utils::*};
+    use anyhow::Context;
     use pretty_assertions::assert_eq;
+    use std::path::Path;
     use tokio::fs::File;
 
+    async fn create_tar(files: &[(&str, &str)]) -> Result<Vec<u8>> {
+        let mut builder = tokio_tar::Builder::new(Vec::new());
+        for (name, content) in files {
+            let mut header = tokio_tar::Header::new_gnu();
+            header.set_entry_type(tokio_tar::EntryType::Regular);
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            builder
+                .append_data(&mut header, name, content.as_bytes())
+                .await?;
+        }
+        Ok(builder.into_inner().await?)
+    }
+
+    #[tokio::test]
+    async fn corrupt_tar() -> Result<()> {
+        let mut tar = create_tar(&[("a.txt", "hello"), ("b.txt", "world")]).await?;
+        // break the checksum of the second header, after the first header and its data padded to 512 bytes
+        tar[1024] ^= 0xff;
+        let (a, d) = simple_adapt_info(Path::new("test.tar"), Box::pin(std::io::Cursor::new(tar)));
+        let r = loop_adapt(&TarAdapter::new(), d, a).await?;
+        let o = String::from_utf8(adapted_to_vec(r).await?)?;
+        assert!(o.starts_with("PREFIX:a.txt: hello\n"), "{o}");
+        assert!(
+            o.contains("PREFIX:[rga: tar truncated or corrupt, stopped after 1 files: "),
+            "{o}"
+        );
+        assert!(!o.contains("world"));
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_simple_tar() -> Result<()> {
         let filepath = test_data_dir().join("hello.tar");
@@ -0,0 +1,271 @@
+use super::*;
+use crate::adapted_iter::one_file;
+use anyhow::Result;
+use async_trait::async_trait;
+use lazy_static::lazy_static;
+use log::*;
+use reqwest::header::{ACCEPT, CONTENT_DISPOSITION, HeaderValue};
+use std::collections::BTreeMap;
+use std::io::Cursor;
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
+use tokio_stream::StreamExt;
+use tokio_util::io::{ReaderStream, StreamReader};
+
+// formats rga has no other adapter for
+static EXTENSIONS: &[&str] = &[
+    "doc", "dot", "xls", "xlsx", "xlsm", "ppt", "pptx", "pps", "ppsx", "rtf", "msg", "pst", "odp",
+    "ods", "odg", "pages", "numbers", "key", "vsd", "vsdx", "wpd", "pub", "one", "chm", "djvu",
+];
+
+lazy_static! {
+    static ref METADATA: AdapterMeta = AdapterMeta {
+        name: "tika".to_owned(),
+        version: 1,
+        description:
+            "Sends files to an Apache Tika server (--rga-tika-url) to extract text from hundreds of formats"
+                .to_owned(),
+        recurses: false,
+        fast_matchers: EXTENSIONS
+            .iter()
+            .map(|s| FastFileMatcher::FileExtension(s.to_string()))
+            .collect(),
+        slow_matchers: None,
+        keep_fast_matchers_if_accurate: true,
+        disabled_by_default: true
+    };
+}
+
+#[derive(Default, Clone)]
+pub struct TikaAdapter;
+
+impl TikaAdapter {
+    pub fn new() -> Self {
+        Self
+    }
+}
+impl GetMetadata for TikaAdapter {
+    fn metadata(&self) -> &AdapterMeta {
+        &METADATA
+    }
+}
+
+/// format the JSON returned by `/meta` as one `key: value` line per entry
+fn format_metadata(meta: &serde_json::Value) -> Result<String> {
+    let meta: BTreeMap<String, serde_json::Value> =
+        serde_json::from_value(meta.clone()).context("tika metadata is not a JSON object")?;
+    let mut out = String::new();
+    for (key, value) in meta {
+        let values = match value {
+            serde_json::Value::Array(a) => a,
+            v => vec![v],
+        };
+        for value in values {
+            let value = match value {
+                serde_json::Value::String(s) => s,
+                v => v.to_string(),
+            };
+            out.push_str(&format!("metadata: {key}: {}\n", value.replace('\n', " ")));
+        }
+    }
+    Ok(out)
+}
+
+#[async_trait]
+impl FileAdapter for TikaAdapter {
+    async fn adapt(
+        &self,
+        ai: AdaptInfo,
+        _detection_reason: &FileMatcher,
+    ) -> Result<AdaptedFilesIterBox> {
+        let AdaptInfo {
+            filepath_hint,
+            mut inp,
+            line_prefix,
+            archive_recursion_depth,
+            postprocess,
+            config,
+            limit_usage,
+            ..
+        } = ai;
+        let base_url = config.tika.url.0.trim_end_matches('/').to_string();
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(config.tika.timeout.0))
+            .build()?;
+        // helps tika with the type detection. file names that are not valid header values are skipped
+        let disposition = filepath_hint.file_name().and_then(|f| {
+            HeaderValue::from_str(&format!(
+                "attachment; filename=\"{}\"",
+                f.to_string_lossy().replace('"', "")
+            ))
+            .ok()
+        });
+        let put = |endpoint: &str, accept: &'static str| {
+            let mut req = client
+                .put(format!("{base_url}/{endpoint}"))
+                .header(ACCEPT, accept);
+            if let Some(disposition) = &disposition {
+                req = req.header(CONTENT_DISPOSITION, disposition.clone());
+            }
+            req
+        };
+
+        let (body, metadata) = if config.tika.metadata {
+            // the input can only be read once, so it has to be buffered to send it twice
+            let mut buf = Vec::new();
+            inp.read_to_end(&mut buf).await?;
+            debug!("requesting tika metadata for {}", filepath_hint.display());
+            let meta = put("meta", "application/json")
+                .body(buf.clone())
+                .send()
+                .await
+                .and_then(|r| r.error_for_status())
+                .with_context(|| format!("requesting metadata from tika server {base_url}"))?
+                .bytes()
+                .await
+                .context("reading tika metadata")?;
+            let meta: serde_json::Value =
+                serde_json::from_slice(&meta).context("parsing tika metadata")?;
+            (reqwest::Body::from(buf), format_metadata(&meta)?)
+        } else {
+            (
+                reqwest::Body::wrap_stream(ReaderStream::new(inp)),
+                String::new(),
+            )
+        };
+        debug!("requesting tika text for {}", filepath_hint.display());
+        let resp = put("tika", "text/plain")
+            .body(body)
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .with_context(|| format!("requesting text from tika server {base_url}"))?;
+        let text = StreamReader::new(
+            resp.bytes_stream()
+                .map(|r| r.map_err(std::io::Error::other)),
+        );
+        Ok(one_file(AdaptInfo {
+            filepath_hint: PathBuf::from(format!("{}.txt", filepath_hint.to_string_lossy())),
+            is_real_file: false,
+            archive_recursion_depth: archive_recursion_depth + 1,
+            inp: Box::pin(Cursor::new(metadata.into_bytes()).chain(text)),
+            line_prefix,
+            postprocess,
+            config,
+            limit_usage,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{preproc::loop_adapt, test_utils::*};
+    use pretty_assertions::assert_eq;
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpListener;
+
+    /// minimal stand-in for tika-server. returns the raw requests it received
+    async fn fake_tika(listener: TcpListener, requests: usize) -> Result<Vec<String>> {
+        let mut received = vec![];
+        for _ in 0..requests {
+            let (mut conn, _) = listener.accept().await?;
+            let mut req = Vec::new();
+            let mut buf = [0u8; 4096];
+            loop {
+                let n = conn.read(&mut buf).await?;
+                if n == 0 {
+                    break;
+                }
+                req.extend_from_slice(&buf[..n]);
+                let s = String::from_utf8_lossy(&req).to_lowercase();
+                if let Some(header_end) = s.find("\r\n\r\n") {
+                    let body = &s[header_end + 4..];
+                    let content_length = s
+                        .lines()
+                        .find_map(|l| l.strip_prefix("content-length: "))
+                        .and_then(|l| l.trim().parse::<usize>().ok());
+                    let done = match content_length {
+                        Some(len) => body.len() >= len,
+                        None => body.ends_with("0\r\n\r\n"),
+                    };
+                    if done {
+                        break;
+                    }
+                }
+            }
+            let req = String::from_utf8_lossy(&req).to_string();
+            let body = if req.starts_with("PUT /meta") {
+                r#"{"dc:title": "Test document", "Content-Type": "application/msword"}"#
+            } else {
+                "hello from tika\n"
+            };
+            conn.write_all(
+                format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                )
+                .as_bytes(),
+            )
+            .await?;
+            received.push(req);
+        }
+        Ok(received)
+    }
+
+    async fn run(metadata: bool, requests: usize) -> Result<(String, Vec<String>)> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("http://{}", listener.local_addr()?);
+        let server = tokio::spawn(fake_tika(listener, requests));
+
+        let filepath = test_data_dir().join("test.doc");
+        let (mut a, d) = simple_adapt_info(
+            &filepath,
+            Box::pin(Cursor::new(b"binary doc content".to_vec())),
+        );
+        a.config.tika.url = crate::config::TikaUrl(url);
+        a.config.tika.metadata = metadata;
+        let r = loop_adapt(&TikaAdapter::new(), d, a).await?;
+        let o = adapted_to_vec(r).await?;
+        Ok((String::from_utf8(o)?, server.await??))
+    }
+
+    #[tokio::test]
+    async fn text() -> Result<()> {
+        let (output, requests) = run(false, 1).await?;
+        assert_eq!(output, "PREFIX:hello from tika\nPREFIX:\n");
+        let req = requests[0].to_lowercase();
+        assert!(req.starts_with("put /tika "));
+        assert!(req.contains("accept: text/plain"));
+        assert!(req.contains("filename=\"test.doc\""));
+        assert!(req.contains("binary doc content"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn metadata() -> Result<()> {
+        let (output, requests) = run(true, 2).await?;
+        assert_eq!(
+            output,
+            "PREFIX:metadata: Content-Type: application/msword
+PREFIX:metadata: dc:title: Test document
+PREFIX:hello from tika
+PREFIX:
+"
+        );
+        assert!(requests[0].starts_with("PUT /meta "));
+        assert!(requests[1].starts_with("PUT /tika "));
+        Ok(())
+    }
+
+    #[test]
+    fn https_url_rejected() {
+        let parse = |url: &str| {
+            serde_json::from_value::<crate::config::TikaUrl>(serde_json::Value::from(url))
+        };
+        assert!(parse("http://localhost:9998").is_ok());
+        let err = parse("https://tika.example.com").unwrap_err().to_string();
+        assert!(err.contains("only http:// urls are supported"), "{err}");
+    }
+}
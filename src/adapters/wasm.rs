@@ -0,0 +1,401 @@
+/*!
+ * Adapters compiled to WebAssembly (WASI preview 1), loaded from the `.wasm` files in the `plugins` folder of the rga config directory.
+ *
+ * Plugins run in-process in a sandbox: they get no file system and network access, only
+ * stdin (the input file), stdout (the converted output), stderr and the arguments `[name, virtual path]`.
+ * A plugin is interrupted when it runs longer than `--rga-adapter-timeout`, and its output is capped at `--rga-limit-output-size`.
+ * The input is read into memory first, so files larger than `--rga-limit-output-size` are rejected.
+ *
+ * A plugin module has to export:
+ *
+ * - `memory`
+ * - `rga_meta_ptr() -> i32` and `rga_meta_len() -> i32`: location of a UTF-8 JSON string in memory describing the adapter,
+ *   e.g. `{"name": "foo", "version": 1, "extensions": ["foo"], "mimetypes": ["application/x-foo"]}`.
+ *   See `PluginMeta` for all fields.
+ * - `_start`: the conversion, reading stdin and writing stdout. Called once per file on a fresh instance.
+ */
+use super::custom::arg_replacer;
+use super::*;
+use crate::adapted_iter::one_file;
+use crate::{print_bytes, project_dirs};
+use anyhow::{Result, bail};
+use async_trait::async_trait;
+use lazy_static::lazy_static;
+use log::*;
+use serde::Deserialize;
+use std::io::Cursor;
+use std::path::Path;
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
+use wasmtime::{Engine, Linker, Module, Store, StoreLimits, StoreLimitsBuilder, Trap};
+use wasmtime_wasi::pipe::{MemoryInputPipe, MemoryOutputPipe};
+use wasmtime_wasi::preview1::{self, WasiP1Ctx};
+use wasmtime_wasi::{I32Exit, WasiCtxBuilder};
+
+/// maximum linear memory of a plugin instance
+const MAX_MEMORY: usize = 1024 * 1024 * 1024;
+/// how often the epoch of `ENGINE` is incremented, the granularity of plugin timeouts
+const EPOCH_TICK: Duration = Duration::from_millis(100);
+/// epoch deadline of plugins without timeout. far away, but small enough not to overflow when added to the current epoch
+const NO_DEADLINE: u64 = u64::MAX / 2;
+
+lazy_static! {
+    static ref ENGINE: Engine = new_engine();
+    static ref PLUGINS: Vec<Arc<WasmAdapter>> = match project_dirs() {
+        Ok(dirs) => load_plugins(&dirs.config_dir().join("plugins")),
+        Err(e) => {
+            warn!("could not load wasm plugins: {:#}", e);
+            vec![]
+        }
+    };
+}
+
+/// an engine that interrupts plugins at their epoch deadline (see `new_store`).
+/// the epoch is advanced by a background thread that runs as long as the process
+fn new_engine() -> Engine {
+    let mut config = wasmtime::Config::new();
+    config.epoch_interruption(true);
+    let engine = Engine::new(&config).expect("valid wasmtime config");
+    let ticker = engine.clone();
+    std::thread::Builder::new()
+        .name("rga-wasm-epoch".to_string())
+        .spawn(move || {
+            loop {
+                std::thread::sleep(EPOCH_TICK);
+                ticker.increment_epoch();
+            }
+        })
+        .expect("could not spawn wasm epoch thread");
+    engine
+}
+
+/// all plugins from the config directory. compiled once per process
+pub fn plugin_adapters() -> Vec<Arc<WasmAdapter>> {
+    PLUGINS.clone()
+}
+
+/// load all `*.wasm` files in the given directory. plugins that fail to load are skipped with a warning
+pub fn load_plugins(dir: &Path) -> Vec<Arc<WasmAdapter>> {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return vec![];
+    };
+    let mut paths: Vec<_> = entries
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|p| p.extension().is_some_and(|e| e == "wasm"))
+        .collect();
+    paths.sort();
+    paths
+        .into_iter()
+        .filter_map(|path| match WasmAdapter::load(&path) {
+            Ok(a) => Some(Arc::new(a)),
+            Err(e) => {
+                warn!("could not load wasm plugin {}: {:#}", path.display(), e);
+                None
+            }
+        })
+        .collect()
+}
+
+/// the JSON returned by a plugin's `rga_meta_ptr`/`rga_meta_len`. same meaning as in `CustomAdapterConfig`
+#[derive(Deserialize)]
+struct PluginMeta {
+    name: String,
+    version: i32,
+    #[serde(default)]
+    description: String,
+    extensions: Vec<String>,
+    mimetypes: Option<Vec<String>>,
+    match_only_by_mime: Option<bool>,
+    disabled_by_default: Option<bool>,
+    output_path_hint: Option<String>,
+}
+
+struct PluginState {
+    wasi: WasiP1Ctx,
+    limits: StoreLimits,
+}
+
+/// a store for one plugin run. the plugin traps with `Trap::Interrupt` once `timeout` has passed
+fn new_store(wasi: WasiP1Ctx, timeout: Option<Duration>) -> Store<PluginState> {
+    let mut store = Store::new(
+        &ENGINE,
+        PluginState {
+            wasi,
+            limits: StoreLimitsBuilder::new().memory_size(MAX_MEMORY).build(),
+        },
+    );
+    store.limiter(|s| &mut s.limits);
+    store.set_epoch_deadline(timeout.map_or(NO_DEADLINE, |t| {
+        (t.as_millis() / EPOCH_TICK.as_millis()) as u64 + 1
+    }));
+    store
+}
+
+pub struct WasmAdapter {
+    module: Module,
+    linker: Linker<PluginState>,
+    meta: AdapterMeta,
+    output_path_hint: Option<String>,
+}
+
+impl GetMetadata for WasmAdapter {
+    fn metadata(&self) -> &AdapterMeta {
+        &self.meta
+    }
+}
+
+impl WasmAdapter {
+    pub fn load(path: &Path) -> Result<Self> {
+        let module = Module::from_file(&ENGINE, path)?;
+        let mut linker = Linker::new(&ENGINE);
+        preview1::add_to_linker_sync(&mut linker, |s: &mut PluginState| &mut s.wasi)?;
+
+        let mut store = new_store(WasiCtxBuilder::new().build_p1(), None);
+        let instance = linker.instantiate(&mut store, &module)?;
+        let memory = instance
+            .get_memory(&mut store, "memory")
+            .context("plugin does not export memory")?;
+        let ptr = instance
+            .get_typed_func::<(), i32>(&mut store, "rga_meta_ptr")?
+            .call(&mut store, ())? as usize;
+        let len = instance
+            .get_typed_func::<(), i32>(&mut store, "rga_meta_len")?
+            .call(&mut store, ())? as usize;
+        let meta_json = memory
+            .data(&store)
+            .get(ptr..ptr + len)
+            .context("plugin metadata out of bounds")?;
+        let meta: PluginMeta =
+            serde_json::from_slice(meta_json).context("invalid plugin metadata")?;
+        debug!("loaded wasm plugin {} from {}", meta.name, path.display());
+
+        Ok(Self {
+            module,
+            linker,
+            output_path_hint: meta.output_path_hint,
+            meta: AdapterMeta {
+                name: meta.name,
+                version: meta.version,
+                description: format!("{}\nWASM plugin: {}", meta.description, path.display()),
+                recurses: true,
+                fast_matchers: meta
+                    .extensions
+                    .into_iter()
+                    .map(FastFileMatcher::FileExtension)
+                    .collect(),
+                slow_matchers: meta
+                    .mimetypes
+                    .map(|m| m.into_iter().map(FileMatcher::MimeType).collect()),
+                keep_fast_matchers_if_accurate: !meta.match_only_by_mime.unwrap_or(false),
+                disabled_by_default: meta.disabled_by_default.unwrap_or(false),
+            },
+        })
+    }
+}
+
+/// run `_start` on a fresh instance with the given stdin, returning stdout.
+///
+/// `max_output`: stdout is cut off one byte after this many bytes (0 for no limit),
+/// so that the `limit_output` check on the adapter output adds its marker
+fn run_plugin(
+    module: &Module,
+    linker: &Linker<PluginState>,
+    name: &str,
+    filepath_hint: &Path,
+    input: Vec<u8>,
+    timeout: Option<Duration>,
+    max_output: u64,
+) -> Result<bytes::Bytes> {
+    let capacity = match max_output {
+        0 => usize::MAX,
+        max => usize::try_from(max).unwrap_or(usize::MAX).saturating_add(1),
+    };
+    let stdout = MemoryOutputPipe::new(capacity);
+    let wasi = WasiCtxBuilder::new()
+        .arg(name)
+        .arg(filepath_hint.to_string_lossy())
+        .stdin(MemoryInputPipe::new(input))
+        .stdout(stdout.clone())
+        .inherit_stderr()
+        .build_p1();
+    let mut store = new_store(wasi, timeout);
+    let instance = linker.instantiate(&mut store, module)?;
+    let start = instance.get_typed_func::<(), ()>(&mut store, "_start")?;
+    if let Err(e) = start.call(&mut store, ()) {
+        if let Some(Trap::Interrupt) = e.downcast_ref::<Trap>() {
+            bail!("timed out after {}s", timeout.map_or(0, |t| t.as_secs()));
+        }
+        match e.downcast_ref::<I32Exit>() {
+            Some(I32Exit(0)) => {}
+            // writing past the output capacity fails the plugin. the output is truncated, not an error
+            _ if max_output > 0 && stdout.contents().len() as u64 > max_output => {}
+            _ => return Err(e),
+        }
+    }
+    drop(store);
+    Ok(stdout.contents())
+}
+
+#[async_trait]
+impl FileAdapter for WasmAdapter {
+    async fn adapt(
+        &self,
+        ai: AdaptInfo,
+        _detection_reason: &FileMatcher,
+    ) -> Result<AdaptedFilesIterBox> {
+        let AdaptInfo {
+            filepath_hint,
+            mut inp,
+            line_prefix,
+            archive_recursion_depth,
+            postprocess,
+            config,
+            limit_usage,
+            ..
+        } = ai;
+        // plugins get their input in memory, so it is limited like the output of an adapter
+        let max_input = config.limits.output_size.0;
+        let mut input = Vec::new();
+        match max_input {
+            0 => inp.read_to_end(&mut input).await?,
+            max => (&mut inp).take(max + 1).read_to_end(&mut input).await?,
+        };
+        if max_input > 0 && input.len() as u64 > max_input {
+            bail!(
+                "input of wasm plugin {} larger than {} (--rga-limit-output-size)",
+                self.meta.name,
+                print_bytes(max_input as f64)
+            );
+        }
+        let (module, linker, name) = (
+            self.module.clone(),
+            self.linker.clone(),
+            self.meta.name.clone(),
+        );
+        let fph = filepath_hint.clone();
+        let timeout = config.adapter_timeout.duration();
+        let max_output = config.limits.output_size.0;
+        // wasmtime's synchronous WASI implementation must not run on an async worker thread
+        let output = tokio::task::spawn_blocking(move || {
+            run_plugin(&module, &linker, &name, &fph, input, timeout, max_output)
+        })
+        .await?
+        .with_context(|| format!("in wasm plugin {}", self.meta.name))?;
+        Ok(one_file(AdaptInfo {
+            filepath_hint: PathBuf::from(arg_replacer(
+                self.output_path_hint
+                    .as_deref()
+                    .unwrap_or("${input_virtual_path}.txt"),
+                &filepath_hint,
+            )?),
+            inp: Box::pin(Cursor::new(output)),
+            line_prefix,
+            is_real_file: false,
+            archive_recursion_depth: archive_recursion_depth + 1,
+            postprocess,
+            config,
+            limit_usage,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{preproc::loop_adapt, test_utils::*};
+    use pretty_assertions::assert_eq;
+
+    /// a plugin (in the text format, which wasmtime also accepts) that copies stdin to stdout
+    fn cat_plugin() -> String {
+        plugin_wat(
+            "wasmcat",
+            r#"(block $done
+      (loop $loop
+        (i32.store (i32.const 1024) (i32.const 2048))
+        (i32.store (i32.const 1028) (i32.const 4096))
+        (br_if $done (call $fd_read (i32.const 0) (i32.const 1024) (i32.const 1) (i32.const 1040)))
+        (br_if $done (i32.eqz (i32.load (i32.const 1040))))
+        (i32.store (i32.const 1028) (i32.load (i32.const 1040)))
+        (br_if $done (call $fd_write (i32.const 1) (i32.const 1024) (i32.const 1) (i32.const 1044)))
+        (br $loop)))"#,
+        )
+    }
+
+    fn plugin_wat(name: &str, start: &str) -> String {
+        let meta = format!(r#"{{"name":"{name}","version":1,"extensions":["{name}"]}}"#);
+        format!(
+            r#"(module
+  (import "wasi_snapshot_preview1" "fd_read" (func $fd_read (param i32 i32 i32 i32) (result i32)))
+  (import "wasi_snapshot_preview1" "fd_write" (func $fd_write (param i32 i32 i32 i32) (result i32)))
+  (memory (export "memory") 1)
+  (data (i32.const 0) "{data}")
+  (func (export "rga_meta_ptr") (result i32) (i32.const 0))
+  (func (export "rga_meta_len") (result i32) (i32.const {len}))
+  (func (export "_start")
+    {start}))"#,
+            data = meta.replace('"', "\\\""),
+            len = meta.len()
+        )
+    }
+
+    #[tokio::test]
+    async fn plugin() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        std::fs::write(dir.path().join("cat.wasm"), cat_plugin())?;
+        std::fs::write(dir.path().join("broken.wasm"), "not a module")?;
+        let plugins = load_plugins(dir.path());
+        assert_eq!(plugins.len(), 1);
+        let adapter = &plugins[0];
+        assert_eq!(adapter.metadata().name, "wasmcat");
+
+        let (a, d) = simple_adapt_info(
+            Path::new("foo.wasmcat"),
+            Box::pin(Cursor::new(b"hello\nworld\n".to_vec())),
+        );
+        let r = loop_adapt(adapter.as_ref(), d, a).await?;
+        let o = adapted_to_vec(r).await?;
+        assert_eq!(
+            String::from_utf8(o)?,
+            "PREFIX:hello\nPREFIX:world\nPREFIX:\n"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn timeout() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        std::fs::write(
+            dir.path().join("spin.wasm"),
+            plugin_wat("wasmspin", "(loop $spin (br $spin))"),
+        )?;
+        let plugins = load_plugins(dir.path());
+        let (mut a, d) = simple_adapt_info(
+            Path::new("foo.wasmspin"),
+            Box::pin(Cursor::new(b"hello".to_vec())),
+        );
+        a.config.adapter_timeout = crate::config::AdapterTimeout(1);
+        let Err(e) = plugins[0].adapt(a, &d).await else {
+            panic!("plugin should time out");
+        };
+        assert!(format!("{e:#}").contains("timed out after 1s"), "{e:#}");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn input_limit() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        std::fs::write(dir.path().join("cat.wasm"), cat_plugin())?;
+        let plugins = load_plugins(dir.path());
+        let (mut a, d) = simple_adapt_info(
+            Path::new("foo.wasmcat"),
+            Box::pin(Cursor::new(vec![b'a'; 100])),
+        );
+        a.config.limits.output_size = crate::config::MaxOutputSize(10);
+        let Err(e) = plugins[0].adapt(a, &d).await else {
+            panic!("input should be rejected");
+        };
+        assert!(format!("{e:#}").contains("larger than"), "{e:#}");
+        Ok(())
+    }
+}
@@ -59,6 +59,7 @@ where
         let postprocess = a.postprocess;
         let line_prefix = a.line_prefix.clone();
         let config = a.config.clone();
+        let limit_usage = a.limit_usage.clone();
         let joiner = tokio::spawn(async move {
             let x = d2;
             T::adapt_write(a, &x, Box::pin(w))
@@ -75,6 +76,7 @@ where
             inp: Box::pin(r.chain(join_handle_to_stream(joiner))),
             line_prefix,
             postprocess,
+            limit_usage,
         }))
     }
 }
@@ -1,12 +1,12 @@
 use super::*;
+use crate::adapted_iter::marker_file;
 use crate::print_bytes;
-use anyhow::*;
+use anyhow::{Context, Result};
 use async_stream::stream;
 use lazy_static::lazy_static;
 use log::*;
 
-// TODO: allow users to configure file extensions instead of hard coding the list
-// https://github.com/phiresky/ripgrep-all/pull/208#issuecomment-2173241243
+// more extensions can be added with `adapter_overrides` in the config
 static EXTENSIONS: &[&str] = &["zip", "jar", "xpi", "kra", "snagx"];
 
 lazy_static! {
@@ -19,7 +19,10 @@ lazy_static! {
             .iter()
             .map(|s| FastFileMatcher::FileExtension(s.to_string()))
             .collect(),
-        slow_matchers: Some(vec![FileMatcher::MimeType("application/zip".to_owned())]),
+        slow_matchers: Some(vec![
+            FileMatcher::MimeType("application/zip".to_owned()),
+            FileMatcher::magic(0, b"PK\x03\x04")
+        ]),
         keep_fast_matchers_if_accurate: false,
         disabled_by_default: false
     };
@@ -54,6 +57,7 @@ impl FileAdapter for ZipAdapter {
             line_prefix,
             config,
             is_real_file,
+            limit_usage,
             ..
         } = ai;
         if is_real_file {
@@ -62,11 +66,54 @@ impl FileAdapter for ZipAdapter {
             let zip = ZipFileReader::new(&filepath_hint).await?;
             let s = stream! {
                 for i in 0..zip.file().entries().len() {
-                    let file = zip.get_entry(i)?;
-                    let reader = zip.entry(i).await?;
+                    // a broken member is reported in the output, the others can still be read
+                    let file = match zip.get_entry(i) {
+                        Ok(file) => file,
+                        Err(e) => {
+                            yield Ok(marker_file(
+                                format!("[rga: error reading member #{i}: {e}]"),
+                                line_prefix.clone(),
+                                archive_recursion_depth + 1,
+                                postprocess,
+                                config.clone(),
+                                limit_usage.clone(),
+                            ));
+                            continue;
+                        }
+                    };
                     if file.filename().ends_with('/') {
                         continue;
                     }
+                    let ratio_exceeded = config.limits.check_ratio(
+                        &limit_usage,
+                        file.compressed_size() as u64,
+                        file.uncompressed_size() as u64,
+                    );
+                    if let Some(reason) = ratio_exceeded {
+                        yield Ok(marker_file(
+                            format!("[rga: limit exceeded: {reason}]"),
+                            format!("{}{}: ", line_prefix, file.filename()),
+                            archive_recursion_depth + 1,
+                            postprocess,
+                            config.clone(),
+                            limit_usage.clone(),
+                        ));
+                        continue;
+                    }
+                    let reader = match zip.entry(i).await {
+                        Ok(reader) => reader,
+                        Err(e) => {
+                            yield Ok(marker_file(
+                                format!("[rga: error reading member {}: {e}]", file.filename()),
+                                format!("{}{}: ", line_prefix, file.filename()),
+                                archive_recursion_depth + 1,
+                                postprocess,
+                                config.clone(),
+                                limit_usage.clone(),
+                            ));
+                            continue;
+                        }
+                    };
                     debug!(
                         "{}{}|{}: {} ({} packed)",
                         line_prefix,
@@ -95,6 +142,7 @@ impl FileAdapter for ZipAdapter {
                         archive_recursion_depth: archive_recursion_depth + 1,
                         postprocess,
                         config: config.clone(),
+                        limit_usage: limit_usage.clone(),
                     });
                 }
             };
@@ -106,14 +154,63 @@ impl FileAdapter for ZipAdapter {
 
             let s = stream! {
                     trace!("begin zip");
-                    while let Some(mut entry) = zip.next_entry().await? {
+                    // a stream can't be continued after a broken entry, so report how far we got
+                    let truncated = |members: usize, e: anyhow::Error| {
+                        marker_file(
+                            format!("[rga: zip truncated or corrupt, stopped after {members} files: {e:#}]"),
+                            line_prefix.clone(),
+                            archive_recursion_depth + 1,
+                            postprocess,
+                            config.clone(),
+                            limit_usage.clone(),
+                        )
+                    };
+                    let mut members = 0;
+                    loop {
+                        let mut entry = match zip.next_entry().await {
+                            Ok(Some(entry)) => entry,
+                            Ok(None) => break,
+                            Err(e) => {
+                                yield Ok(truncated(members, e.into()));
+                                break;
+                            }
+                        };
                         trace!("zip next entry");
                         let file = entry.entry();
                         if file.filename().ends_with('/') {
-                            zip = entry.skip().await?;
+                            zip = match entry.skip().await {
+                                Ok(zip) => zip,
+                                Err(e) => {
+                                    yield Ok(truncated(members, e.into()));
+                                    break;
+                                }
+                            };
 
                             continue;
                         }
+                        let ratio_exceeded = config.limits.check_ratio(
+                            &limit_usage,
+                            file.compressed_size() as u64,
+                            file.uncompressed_size() as u64,
+                        );
+                        if let Some(reason) = ratio_exceeded {
+                            yield Ok(marker_file(
+                                format!("[rga: limit exceeded: {reason}]"),
+                                format!("{}{}: ", line_prefix, file.filename()),
+                                archive_recursion_depth + 1,
+                                postprocess,
+                                config.clone(),
+                                limit_usage.clone(),
+                            ));
+                            zip = match entry.skip().await {
+                                Ok(zip) => zip,
+                                Err(e) => {
+                                    yield Ok(truncated(members, e.into()));
+                                    break;
+                                }
+                            };
+                            continue;
+                        }
                         debug!(
                             "{}{}|{}: {} ({} packed)",
                             line_prefix,
@@ -143,8 +240,16 @@ impl FileAdapter for ZipAdapter {
                             archive_recursion_depth: archive_recursion_depth + 1,
                             postprocess,
                             config: config.clone(),
+                            limit_usage: limit_usage.clone(),
                         });
-                        zip = entry.done().await.context("going to next file in zip but entry was not read fully")?;
+                        members += 1;
+                        zip = match entry.done().await.context("going to next file in zip but entry was not read fully") {
+                            Ok(zip) => zip,
+                            Err(e) => {
+                                yield Ok(truncated(members, e));
+                                break;
+                            }
+                        };
 
                 }
                 trace!("zip over");
@@ -221,6 +326,67 @@ mod test {
         Ok(cursor.into_inner())
     }
 
+    async fn create_zip_files(files: &[(&str, &str)]) -> Result<Vec<u8>> {
+        let mut cursor = std::io::Cursor::new(Vec::new());
+        let mut zip = ZipFileWriter::new(&mut cursor);
+        for (name, content) in files {
+            let options = ZipEntryBuilder::new(name.to_string(), Compression::Stored);
+            zip.write_entry_whole(options, content.as_bytes()).await?;
+        }
+        zip.close().await?;
+        Ok(cursor.into_inner())
+    }
+
+    /// a zip with three files where the local header of b.txt is broken
+    async fn corrupt_zip() -> Result<Vec<u8>> {
+        let mut zip =
+            create_zip_files(&[("a.txt", "hello"), ("b.txt", "world"), ("c.txt", "again")]).await?;
+        let second_header = zip
+            .windows(4)
+            .enumerate()
+            .filter(|(_, w)| *w == b"PK\x03\x04")
+            .nth(1)
+            .expect("three local headers")
+            .0;
+        zip[second_header + 3] ^= 0xff;
+        Ok(zip)
+    }
+
+    #[tokio::test]
+    async fn corrupt_zip_stream() -> Result<()> {
+        let (a, d) = simple_adapt_info(
+            &PathBuf::from("test.zip"),
+            Box::pin(std::io::Cursor::new(corrupt_zip().await?)),
+        );
+        let o =
+            String::from_utf8(adapted_to_vec(loop_adapt(&ZipAdapter::new(), d, a).await?).await?)?;
+        assert!(o.starts_with("PREFIX:a.txt: hello\n"), "{o}");
+        assert!(
+            o.contains("PREFIX:[rga: zip truncated or corrupt, stopped after 1 files: "),
+            "{o}"
+        );
+        assert!(!o.contains("world"), "{o}");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn corrupt_zip_fs() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("test.zip");
+        tokio::fs::write(&path, corrupt_zip().await?).await?;
+        let (a, d) = simple_fs_adapt_info(&path).await?;
+        let o =
+            String::from_utf8(adapted_to_vec(loop_adapt(&ZipAdapter::new(), d, a).await?).await?)?;
+        // the other members are still read from the central directory
+        assert!(o.starts_with("PREFIX:a.txt: hello\n"), "{o}");
+        assert!(
+            o.contains("PREFIX:b.txt: [rga: error reading member b.txt: "),
+            "{o}"
+        );
+        assert!(o.ends_with("PREFIX:c.txt: again\n"), "{o}");
+        Ok(())
+    }
+
     #[tokio::test]
     async fn only_seek_zip_fs() -> Result<()> {
         let zip = test_data_dir().join("only-seek-zip.zip");
@@ -1,11 +1,13 @@
 use anyhow::{Context, Result};
 use rga::adapters::custom::map_exe_error;
 use rga::adapters::*;
-use rga::config::{RgaConfig, split_args};
+use rga::config::{DetectionMode, RgaConfig, split_args};
 use rga::integrated_search::IntegratedSearcher;
+use rga::limits::LimitUsage;
 use rga::matching::*;
 use rga::preproc::*;
-use rga::print_dur;
+use rga::preproc_cache::{vacuum_cache, verify_cache};
+use rga::{print_bytes, print_dur};
 use ripgrep_all as rga;
 use structopt::StructOpt;
 
@@ -17,7 +19,8 @@ use std::time::Instant;
 use tokio::fs::File;
 
 fn list_adapters(args: RgaConfig) -> Result<()> {
-    let (enabled_adapters, disabled_adapters) = get_all_adapters(args.custom_adapters);
+    let (enabled_adapters, disabled_adapters) =
+        get_all_adapters(args.custom_adapters, &args.adapter_overrides);
 
     println!("Adapters:\n");
     let print = |adapter: std::sync::Arc<dyn FileAdapter>| {
@@ -27,6 +30,10 @@ fn list_adapters(args: RgaConfig) -> Result<()> {
             .iter()
             .map(|m| match m {
                 FastFileMatcher::FileExtension(ext) => format!(".{ext}"),
+                FastFileMatcher::FileNameGlob(glob) => glob.to_string(),
+                FastFileMatcher::PathGlob(glob) => format!("path {glob}"),
+                FastFileMatcher::PathRegex(re) => format!("path /{re}/"),
+                FastFileMatcher::PathPrefix(prefix) => format!("in {prefix}"),
             })
             .collect::<Vec<_>>()
             .join(", ");
@@ -37,7 +44,7 @@ fn list_adapters(args: RgaConfig) -> Result<()> {
             .iter()
             .filter_map(|m| match m {
                 FileMatcher::MimeType(x) => Some(x.to_string()),
-                FileMatcher::Fast(_) => None,
+                FileMatcher::Fast(_) | FileMatcher::MagicBytes { .. } => None,
             })
             .collect::<Vec<_>>()
             .join(", ");
@@ -83,6 +90,7 @@ fn get_invocation_mode() -> &'static str {
             "preproc" => return "preproc",
             "fzf" => return "fzf",
             "fzf-open" => return "fzf-open",
+            "cache" => return "cache",
             _ => {}
         }
     }
@@ -113,7 +121,7 @@ fn main() -> anyhow::Result<()> {
         unsafe { std::env::set_var("RUST_LOG", "debug") };
     }
 
-    env_logger::init();
+    rga::diagnostics::init();
 
     // Determine which mode to run in
     let mode = get_invocation_mode();
@@ -125,13 +133,68 @@ fn main() -> anyhow::Result<()> {
         }
         "fzf" => run_fzf(),
         "fzf-open" => run_fzf_open(),
+        "cache" => run_cache(),
         _ => run_main(),
     }
 }
 
+/// `--rga-explain`: describe the adapter chain of each given file instead of searching
+fn explain_files(config: RgaConfig, args: Vec<std::ffi::OsString>) -> Result<()> {
+    let paths: Vec<PathBuf> = args
+        .into_iter()
+        .filter(|a| !a.to_string_lossy().starts_with('-'))
+        .map(PathBuf::from)
+        .collect();
+    if paths.is_empty() {
+        anyhow::bail!("--rga-explain needs at least one file");
+    }
+    add_exe_to_path()?;
+    let rt = tokio::runtime::Runtime::new()?;
+    for path in paths {
+        print!("{}", rt.block_on(rga::explain::explain(&config, &path))?);
+    }
+    Ok(())
+}
+
+/// `--rga-list-adapters-json`
+fn list_adapters_json(config: RgaConfig) -> Result<()> {
+    add_exe_to_path()?;
+    let rt = tokio::runtime::Runtime::new()?;
+    let adapters = rt.block_on(rga::doctor::adapter_infos(&config))?;
+    println!("{}", serde_json::to_string_pretty(&adapters)?);
+    Ok(())
+}
+
+/// `--rga-doctor`: check the programs of the adapters, the cache and the config file
+fn run_doctor(config: RgaConfig) -> Result<()> {
+    add_exe_to_path()?;
+    let rt = tokio::runtime::Runtime::new()?;
+    let checks = rt.block_on(rga::doctor::doctor(&config))?;
+    print!("{}", rga::doctor::format_table(&checks));
+    let errors = checks
+        .iter()
+        .filter(|c| c.status == rga::doctor::Status::Error)
+        .count();
+    if errors > 0 {
+        anyhow::bail!("{errors} problem(s) found");
+    }
+    Ok(())
+}
+
 /// Run the main rga search functionality
 fn run_main() -> anyhow::Result<()> {
-    let (config, mut passthrough_args) = split_args(false)?;
+    let (config, mut passthrough_args) = match split_args(false) {
+        Ok(args) => args,
+        // a broken config file is one of the things the doctor reports, so use only the command line
+        Err(_) if std::env::args().any(|a| a == "--rga-doctor") => {
+            let args = std::env::args()
+                .take(1)
+                .chain(std::env::args().filter(|a| a.starts_with("--rga-")));
+            return run_doctor(RgaConfig::from_iter(args));
+        }
+        Err(e) => return Err(e),
+    };
+    rga::diagnostics::configure(config.verbose, config.progress);
 
     if config.print_config_schema {
         println!("{}", serde_json::to_string_pretty(&schema_for!(RgaConfig))?);
@@ -140,6 +203,15 @@ fn run_main() -> anyhow::Result<()> {
     if config.list_adapters {
         return list_adapters(config);
     }
+    if config.explain {
+        return explain_files(config, passthrough_args);
+    }
+    if config.list_adapters_json {
+        return list_adapters_json(config);
+    }
+    if config.doctor {
+        return run_doctor(config);
+    }
     if let Some(ref path) = config.fzf_path {
         if path == "_" {
             // fzf found no result, ignore everything and return
@@ -156,14 +228,20 @@ fn run_main() -> anyhow::Result<()> {
         return Ok(());
     }
 
-    let adapters = get_adapters_filtered(config.custom_adapters.clone(), &config.adapters)?;
+    let adapters = get_adapters_filtered(
+        config.custom_adapters.clone(),
+        &config.adapter_overrides,
+        &config.adapters,
+    )?;
 
-    let pre_glob = if !config.accurate {
+    let pre_glob = if config.detection_mode() != DetectionMode::Accurate {
         let extensions = adapters
             .iter()
             .flat_map(|a| &a.metadata().fast_matchers)
             .flat_map(|m| match m {
                 FastFileMatcher::FileExtension(ext) => vec![ext.clone(), ext.to_ascii_uppercase()],
+                // checked by the searcher using the adapter matchers
+                _ => vec![],
             })
             .collect::<Vec<_>>()
             .join(",");
@@ -182,12 +260,15 @@ fn run_main() -> anyhow::Result<()> {
     ];
 
     let before = Instant::now();
+    if config.profile || config.profile_trace.is_some() {
+        rga::profile::start(config.profile_trace.is_some());
+    }
     
     // Create runtime for async preprocessing
     let rt = tokio::runtime::Runtime::new()?;
     
     // Use integrated search instead of spawning rg subprocess
-    let searcher = IntegratedSearcher::new(config.clone(), adapters, pre_glob);
+    let searcher = IntegratedSearcher::new(config.clone(), adapters, pre_glob)?;
     
     // Parse pattern and paths from passthrough_args
     let passthrough_strings: Vec<String> = passthrough_args
@@ -248,6 +329,7 @@ fn run_main() -> anyhow::Result<()> {
     })?;
 
     log::debug!("running search took {}", print_dur(before));
+    rga::profile::finish(config.profile_trace.as_deref().map(std::path::Path::new))?;
     
     if exit_code != 0 {
         std::process::exit(exit_code);
@@ -288,6 +370,7 @@ async fn run_preproc() -> anyhow::Result<()> {
     
     let last = arg_arr.pop().expect("No filename specified");
     let config = rga::config::parse_args(arg_arr, true)?;
+    rga::diagnostics::configure(config.verbose, false);
     //clap::App::new("rga-preproc").arg(Arg::from_usage())
     let path = {
         let filepath = last;
@@ -306,6 +389,7 @@ async fn run_preproc() -> anyhow::Result<()> {
         archive_recursion_depth: 0,
         postprocess: !config.no_prefix_filenames,
         config,
+        limit_usage: LimitUsage::default(),
     };
 
     let start = Instant::now();
@@ -324,6 +408,50 @@ async fn run_preproc() -> anyhow::Result<()> {
     Ok(())
 }
 
+/// Run cache maintenance commands (`rga cache <command> [RGA OPTIONS]`)
+fn run_cache() -> anyhow::Result<()> {
+    let mut args: Vec<std::ffi::OsString> = std::env::args_os().collect();
+    args.remove(1); // "cache"
+    let command = if args.len() > 1 {
+        args.remove(1).to_string_lossy().into_owned()
+    } else {
+        "".to_string()
+    };
+    let config = rga::config::parse_args(args, false)?;
+    let rt = tokio::runtime::Runtime::new()?;
+    match command.as_str() {
+        "vacuum" => {
+            let start = Instant::now();
+            let stats = rt.block_on(vacuum_cache(&config.cache))?;
+            println!(
+                "{} cache entries: {} -> {}, trained {} dictionaries in {}",
+                stats.entries,
+                print_bytes(stats.bytes_before as f64),
+                print_bytes(stats.bytes_after as f64),
+                stats.dictionaries_trained,
+                print_dur(start)
+            );
+            Ok(())
+        }
+        "verify" => {
+            let start = Instant::now();
+            let stats = rt.block_on(verify_cache(&config.cache))?;
+            println!(
+                "{} cache entries checked, {} broken entries removed, {} entries of another cache key skipped in {}",
+                stats.entries,
+                stats.removed,
+                stats.skipped,
+                print_dur(start)
+            );
+            Ok(())
+        }
+        other => Err(anyhow::format_err!(
+            "Unknown cache command \"{}\". Known commands: vacuum, verify",
+            other
+        )),
+    }
+}
+
 /// Run the fzf integration functionality (rga-fzf)
 fn run_fzf() -> anyhow::Result<()> {
     let mut passthrough_args: Vec<String> = std::env::args().skip(1).collect();
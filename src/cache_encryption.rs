@@ -0,0 +1,90 @@
+use crate::config::CacheConfig;
+use anyhow::{Context, Result, format_err};
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+
+/// environment variable that can hold the cache key material if no key file is configured
+static RGA_CACHE_KEY: &str = "RGA_CACHE_KEY";
+
+const NONCE_LEN: usize = 24;
+
+/**
+ * Authenticated encryption of cache blobs plus keyed hashing of file paths,
+ * so the cache db neither contains the extracted text nor the names of the files it came from.
+ *
+ * Both keys are derived from the same key material (contents of the key file or of `RGA_CACHE_KEY`).
+ */
+pub struct CacheEncryption {
+    cipher: XChaCha20Poly1305,
+    path_key: [u8; 32],
+}
+
+impl CacheEncryption {
+    /// returns None if neither a key file nor the environment variable is set
+    pub fn from_config(config: &CacheConfig) -> Result<Option<Self>> {
+        let material = if let Some(key_file) = &config.key_file {
+            std::fs::read(key_file).with_context(|| format!("reading cache key file {key_file}"))?
+        } else if let Some(key) = std::env::var_os(RGA_CACHE_KEY) {
+            key.into_encoded_bytes()
+        } else {
+            return Ok(None);
+        };
+        // key files usually end with a newline
+        let material = material.trim_ascii();
+        if material.is_empty() {
+            return Err(format_err!("cache encryption key is empty"));
+        }
+        Ok(Some(Self::from_key_material(material)))
+    }
+
+    pub fn from_key_material(material: &[u8]) -> Self {
+        let enc_key = blake3::derive_key("ripgrep-all cache blob encryption v1", material);
+        let path_key = blake3::derive_key("ripgrep-all cache file path hashing v1", material);
+        Self {
+            cipher: XChaCha20Poly1305::new(Key::from_slice(&enc_key)),
+            path_key,
+        }
+    }
+
+    /// stable, keyed hash of a file path. used instead of the path itself as part of the cache key
+    pub fn hash_path(&self, file_path: &str) -> String {
+        blake3::keyed_hash(&self.path_key, file_path.as_bytes())
+            .to_hex()
+            .to_string()
+    }
+
+    /// encrypts a blob. `aad` binds the ciphertext to its cache row so blobs can't be swapped between entries
+    pub fn encrypt(&self, aad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: plaintext,
+                    aad,
+                },
+            )
+            .map_err(|_| format_err!("encrypting cache entry failed"))?;
+        let mut out = Vec::with_capacity(NONCE_LEN + ciphertext.len());
+        out.extend_from_slice(&nonce);
+        out.extend_from_slice(&ciphertext);
+        Ok(out)
+    }
+
+    pub fn decrypt(&self, aad: &[u8], blob: &[u8]) -> Result<Vec<u8>> {
+        if blob.len() < NONCE_LEN {
+            return Err(format_err!("encrypted cache entry too short"));
+        }
+        let (nonce, ciphertext) = blob.split_at(NONCE_LEN);
+        self.cipher
+            .decrypt(
+                XNonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad,
+                },
+            )
+            .map_err(|_| format_err!("could not decrypt cache entry (wrong key or corrupted)"))
+    }
+}
@@ -1,12 +1,11 @@
-use std::{future::Future, pin::Pin};
+use std::{future::Future, io::Write, pin::Pin, sync::Arc};
 
 use anyhow::{Context, Result};
-use async_compression::tokio::write::ZstdEncoder;
 use async_stream::stream;
 
-use crate::to_io_err;
+use crate::{preproc_cache::CacheDictionary, to_io_err};
 use log::*;
-use tokio::io::{AsyncRead, AsyncWriteExt};
+use tokio::io::AsyncRead;
 use tokio_stream::StreamExt;
 use tokio_util::io::{ReaderStream, StreamReader};
 
@@ -16,18 +15,25 @@ type FinishHandler =
  * wrap a AsyncRead so that it is passthrough,
  * but also the written data is compressed and written into a buffer,
  * unless more than max_cache_size bytes is written, then the cache is dropped and it is pure passthrough.
+ *
+ * If a dictionary is given, it is used for compression (and must be used for decompression as well).
  */
 pub fn async_read_and_write_to_cache<'a>(
     inp: impl AsyncRead + Send + 'a,
     max_cache_size: usize,
     compression_level: i32,
+    dictionary: Option<Arc<CacheDictionary>>,
     on_finish: Box<FinishHandler>,
 ) -> Result<Pin<Box<dyn AsyncRead + Send + 'a>>> {
     let inp = Box::pin(inp);
-    let mut zstd_writer = Some(ZstdEncoder::with_quality(
+    let mut encoder = zstd::stream::write::Encoder::with_dictionary(
         Vec::new(),
-        async_compression::Level::Precise(compression_level),
-    ));
+        compression_level,
+        dictionary.as_ref().map(|d| &d.data[..]).unwrap_or(&[]),
+    )?;
+    // lets us detect corrupt cache entries when decoding
+    encoder.include_checksum(true)?;
+    let mut zstd_writer = Some(encoder);
     let mut bytes_written = 0;
 
     let s = stream! {
@@ -35,7 +41,7 @@ pub fn async_read_and_write_to_cache<'a>(
         while let Some(bytes) = stream.next().await {
             trace!("read bytes: {:?}", bytes);
             if let (Ok(bytes), Some(writer)) = (&bytes, zstd_writer.as_mut()) {
-                writer.write_all(bytes).await?;
+                writer.write_all(bytes)?;
                 bytes_written += bytes.len() as u64;
                 let compressed_len = writer.get_ref().len();
                 trace!("wrote {} to zstd, len now {}", bytes.len(), compressed_len);
@@ -50,9 +56,8 @@ pub fn async_read_and_write_to_cache<'a>(
         trace!("eof");
         // EOF, call on_finish
         let finish = {
-            match zstd_writer.take() { Some(mut writer) => {
-                writer.shutdown().await?;
-                let res = writer.into_inner();
+            match zstd_writer.take() { Some(writer) => {
+                let res = writer.finish()?;
                 trace!("EOF");
                 if res.len() <= max_cache_size {
                     trace!("writing {} bytes to cache", res.len());
@@ -1,4 +1,7 @@
-use crate::{adapters::custom::CustomAdapterConfig, project_dirs};
+use crate::{
+    adapters::{custom::CustomAdapterConfig, overrides::AdapterOverride},
+    project_dirs,
+};
 use anyhow::{Context, Result};
 use derive_more::FromStr;
 use log::*;
@@ -6,6 +9,7 @@ use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use std::ffi::OsString;
 use std::io::Read;
+use std::time::Duration;
 use std::{fs::File, io::Write, iter::IntoIterator, path::PathBuf, str::FromStr};
 use structopt::StructOpt;
 
@@ -39,6 +43,41 @@ impl Default for MaxArchiveRecursion {
     }
 }
 
+#[derive(JsonSchema, Debug, Serialize, Deserialize, Copy, Clone, PartialEq)]
+pub struct FallbackBufferSize(pub u64);
+
+impl std::fmt::Display for FallbackBufferSize {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+impl Default for FallbackBufferSize {
+    fn default() -> Self {
+        Self(1024 * 1024)
+    }
+}
+impl FromStr for FallbackBufferSize {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self(parse_byte_size(s)?))
+    }
+}
+
+#[derive(JsonSchema, Debug, Serialize, Deserialize, Copy, Clone, PartialEq, FromStr, Default)]
+pub struct AdapterTimeout(pub u64);
+
+impl std::fmt::Display for AdapterTimeout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+impl AdapterTimeout {
+    /// None if there is no timeout
+    pub fn duration(&self) -> Option<Duration> {
+        (self.0 > 0).then(|| Duration::from_secs(self.0))
+    }
+}
+
 #[derive(JsonSchema, Debug, Serialize, Deserialize, Clone, PartialEq, FromStr)]
 pub struct CachePath(pub String);
 
@@ -72,24 +111,219 @@ impl Default for CacheMaxBlobLen {
 impl FromStr for CacheMaxBlobLen {
     type Err = anyhow::Error;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let suffix = s.chars().last();
-        if let Some(suffix) = suffix {
-            Ok(Self(match suffix {
-                'k' | 'M' | 'G' => usize::from_str(s.trim_end_matches(suffix))
-                    .with_context(|| "Could not parse int".to_string())
-                    .map(|e| {
-                        e * match suffix {
-                            'k' => 1000,
-                            'M' => 1_000_000,
-                            'G' => 1_000_000_000,
-                            _ => panic!("impossible"),
-                        }
-                    }),
-                _ => usize::from_str(s).with_context(|| "Could not parse int".to_string()),
-            }?))
-        } else {
-            Err(anyhow::format_err!("empty byte input"))
+        Ok(Self(parse_byte_size(s)? as usize))
+    }
+}
+
+/// parse a byte count with an optional k, M or G suffix
+fn parse_byte_size(s: &str) -> Result<u64> {
+    let suffix = s.chars().last();
+    if let Some(suffix) = suffix {
+        match suffix {
+            'k' | 'M' | 'G' => u64::from_str(s.trim_end_matches(suffix))
+                .with_context(|| "Could not parse int".to_string())
+                .map(|e| {
+                    e * match suffix {
+                        'k' => 1000,
+                        'M' => 1_000_000,
+                        'G' => 1_000_000_000,
+                        _ => panic!("impossible"),
+                    }
+                }),
+            _ => u64::from_str(s).with_context(|| "Could not parse int".to_string()),
         }
+    } else {
+        Err(anyhow::format_err!("empty byte input"))
+    }
+}
+
+#[derive(JsonSchema, Debug, Serialize, Deserialize, Copy, Clone, PartialEq)]
+pub struct MaxTotalSize(pub u64);
+
+impl std::fmt::Display for MaxTotalSize {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+impl Default for MaxTotalSize {
+    fn default() -> Self {
+        Self(10_000_000_000)
+    }
+}
+impl FromStr for MaxTotalSize {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self(parse_byte_size(s)?))
+    }
+}
+
+#[derive(JsonSchema, Debug, Serialize, Deserialize, Copy, Clone, PartialEq)]
+pub struct MaxOutputSize(pub u64);
+
+impl std::fmt::Display for MaxOutputSize {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+impl Default for MaxOutputSize {
+    fn default() -> Self {
+        Self(1_000_000_000)
+    }
+}
+impl FromStr for MaxOutputSize {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self(parse_byte_size(s)?))
+    }
+}
+
+#[derive(JsonSchema, Debug, Serialize, Deserialize, Copy, Clone, PartialEq, FromStr)]
+pub struct MaxCompressionRatio(pub u64);
+
+impl std::fmt::Display for MaxCompressionRatio {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+impl Default for MaxCompressionRatio {
+    fn default() -> Self {
+        Self(1000)
+    }
+}
+
+#[derive(JsonSchema, Debug, Serialize, Deserialize, Copy, Clone, PartialEq, FromStr)]
+pub struct MaxArchiveMembers(pub u64);
+
+impl std::fmt::Display for MaxArchiveMembers {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+impl Default for MaxArchiveMembers {
+    fn default() -> Self {
+        Self(100_000)
+    }
+}
+
+#[derive(JsonSchema, Debug, Serialize, Deserialize, Clone, PartialEq, FromStr)]
+#[serde(try_from = "String")]
+pub struct TikaUrl(pub String);
+
+impl TryFrom<String> for TikaUrl {
+    type Error = String;
+    fn try_from(url: String) -> std::result::Result<Self, Self::Error> {
+        // reqwest is built without a TLS backend
+        if !url.to_ascii_lowercase().starts_with("http://") {
+            return Err(format!(
+                "invalid tika url {url}: only http:// urls are supported"
+            ));
+        }
+        Ok(Self(url))
+    }
+}
+
+impl std::fmt::Display for TikaUrl {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+impl Default for TikaUrl {
+    fn default() -> Self {
+        Self("http://localhost:9998".to_string())
+    }
+}
+
+#[derive(JsonSchema, Debug, Serialize, Deserialize, Copy, Clone, PartialEq, FromStr)]
+pub struct TikaTimeout(pub u64);
+
+impl std::fmt::Display for TikaTimeout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+impl Default for TikaTimeout {
+    fn default() -> Self {
+        Self(60)
+    }
+}
+
+#[derive(JsonSchema, Debug, Serialize, Deserialize, Copy, Clone, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum DetectionMode {
+    /// only by file name
+    #[default]
+    Fast,
+    /// by file name, and by magic bytes if no adapter matches the file name
+    Hybrid,
+    /// by mime type and magic bytes, and by file name for most adapters
+    Accurate,
+}
+
+impl std::fmt::Display for DetectionMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Fast => "fast",
+            Self::Hybrid => "hybrid",
+            Self::Accurate => "accurate",
+        })
+    }
+}
+impl FromStr for DetectionMode {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "fast" => Self::Fast,
+            "hybrid" => Self::Hybrid,
+            "accurate" => Self::Accurate,
+            _ => anyhow::bail!("unknown detection mode {s}, expected fast, hybrid or accurate"),
+        })
+    }
+}
+
+#[derive(JsonSchema, Debug, Serialize, Deserialize, Copy, Clone, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Verbosity {
+    /// only errors
+    Quiet,
+    /// errors and warnings. each warning is only shown once
+    #[default]
+    Warn,
+    /// also which adapter is used for each file
+    Info,
+    /// everything, same as `--debug`
+    Debug,
+}
+
+impl Verbosity {
+    pub fn level_filter(self) -> log::LevelFilter {
+        match self {
+            Self::Quiet => log::LevelFilter::Error,
+            Self::Warn => log::LevelFilter::Warn,
+            Self::Info => log::LevelFilter::Info,
+            Self::Debug => log::LevelFilter::Debug,
+        }
+    }
+}
+impl std::fmt::Display for Verbosity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Quiet => "quiet",
+            Self::Warn => "warn",
+            Self::Info => "info",
+            Self::Debug => "debug",
+        })
+    }
+}
+impl FromStr for Verbosity {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "quiet" => Self::Quiet,
+            "warn" => Self::Warn,
+            "info" => Self::Info,
+            "debug" => Self::Debug,
+            _ => anyhow::bail!("unknown verbosity {s}, expected quiet, warn, info or debug"),
+        })
     }
 }
 
@@ -122,6 +356,54 @@ pub struct RgaConfig {
     #[structopt(long = "--rga-accurate")]
     pub accurate: bool,
 
+    /// How to detect the file type of files to choose an adapter.
+    ///
+    /// - fast (default): only by file name (extension).
+    /// - hybrid: by file name, and if no adapter matches the file name (for example files without extension),
+    ///   by the magic bytes at the start of the file (e.g. `PK\x03\x04` for zip).
+    ///   This reads the first 8KiB of every file that no adapter matches by name.
+    /// - accurate: same as `--rga-accurate`.
+    #[serde(default, skip_serializing_if = "is_default")]
+    #[structopt(
+        default_value,
+        long = "--rga-detection",
+        require_equals = true,
+        possible_values = &["fast", "hybrid", "accurate"],
+        hidden_short_help = true
+    )]
+    pub detection: DetectionMode,
+
+    /// Exit with an error (code 2) if any file could not be searched, e.g. because an adapter failed.
+    ///
+    /// By default, such files are skipped and listed at the end of the search.
+    #[serde(default, skip_serializing_if = "is_default")]
+    #[structopt(long = "--rga-strict", hidden_short_help = true)]
+    pub strict: bool,
+
+    /// What to print to stderr.
+    ///
+    /// - quiet: only errors.
+    /// - warn: also warnings, each one only once.
+    /// - info: also which adapter is used for each file.
+    /// - debug: everything, same as `--debug`.
+    ///
+    /// Ignored if the `RUST_LOG` environment variable is set.
+    #[serde(default, skip_serializing_if = "is_default")]
+    #[structopt(
+        default_value,
+        long = "--rga-verbose",
+        require_equals = true,
+        possible_values = &["quiet", "warn", "info", "debug"],
+        hidden_short_help = true
+    )]
+    pub verbose: Verbosity,
+
+    /// Show a progress line on stderr (if it is a terminal) with the number of files walked, adapted and
+    /// read from the cache, and the adapter that is currently running.
+    #[serde(default, skip_serializing_if = "is_default")]
+    #[structopt(long = "--rga-progress", hidden_short_help = true)]
+    pub progress: bool,
+
     /// Change which adapters to use and in which priority order (descending).
     ///
     /// - "foo,bar" means use only adapters foo and bar.
@@ -135,10 +417,28 @@ pub struct RgaConfig {
     )]
     pub adapters: Vec<String>,
 
+    /// Run the programs of these adapters in a sandbox (Linux only), e.g. "pandoc,poppler,ffmpeg" or "all".
+    ///
+    /// A sandboxed program can only read the system directories and its input, only write to its own
+    /// temporary directory and can't open sockets. Needs Landlock support in the kernel (Linux 5.13+,
+    /// truncating files is only prevented since Linux 6.2).
+    #[serde(default, skip_serializing_if = "is_default")]
+    #[structopt(
+        long = "--rga-sandbox",
+        require_equals = true,
+        require_delimiter = true,
+        hidden_short_help = true
+    )]
+    pub sandbox: Vec<String>,
+
     #[serde(default, skip_serializing_if = "is_default")]
     #[structopt(flatten)]
     pub cache: CacheConfig,
 
+    #[serde(default, skip_serializing_if = "is_default")]
+    #[structopt(flatten)]
+    pub tika: TikaConfig,
+
     /// Maximum depth of nested archives to recurse into.
     ///
     /// When searching in archives, rga will recurse into archives inside archives.
@@ -152,6 +452,40 @@ pub struct RgaConfig {
     )]
     pub max_archive_recursion: MaxArchiveRecursion,
 
+    /// Maximum size of a file within an archive that is kept in memory so it can be passed to the next matching
+    /// adapter when the first one fails. Larger files get no fallback. Files on disk are reopened instead of buffered.
+    ///
+    /// The bytes are recorded while the first adapter reads them and dropped once it produces output, so up to
+    /// this much memory is used per file that matches multiple adapters and is currently being adapted.
+    ///
+    /// Allowed suffixes on command line: k M G
+    #[serde(default, skip_serializing_if = "is_default")]
+    #[structopt(
+        default_value,
+        long = "--rga-fallback-buffer-size",
+        require_equals = true,
+        hidden_short_help = true
+    )]
+    pub fallback_buffer_size: FallbackBufferSize,
+
+    #[serde(default, skip_serializing_if = "is_default")]
+    #[structopt(flatten)]
+    pub limits: LimitsConfig,
+
+    /// Maximum time in seconds for adapting a single searched file, including all files nested within it.
+    /// 0 for no timeout.
+    ///
+    /// When the time is up, all programs started for the file are stopped
+    /// and a `[rga: adapter timed out]` line is added to the output.
+    #[serde(default, skip_serializing_if = "is_default")]
+    #[structopt(
+        default_value,
+        long = "--rga-adapter-timeout",
+        require_equals = true,
+        hidden_short_help = true
+    )]
+    pub adapter_timeout: AdapterTimeout,
+
     /// Don't prefix lines of files within archive with the path inside the archive.
     ///
     /// Inside archives, by default rga prefixes the content of each file with the file path within the archive.
@@ -164,6 +498,12 @@ pub struct RgaConfig {
     #[structopt(skip)] // config file only
     pub custom_adapters: Option<Vec<CustomAdapterConfig>>,
 
+    /// Change the file extensions, mime types and priority of existing adapters, e.g.
+    /// `{"name": "zip", "add_extensions": ["whl", "nupkg"]}`
+    #[serde(default, skip_serializing_if = "is_default")]
+    #[structopt(skip)] // config file only
+    pub adapter_overrides: Vec<AdapterOverride>,
+
     #[serde(skip)]
     #[structopt(long = "--rga-config-file", require_equals = true)]
     pub config_file_path: Option<String>,
@@ -180,6 +520,45 @@ pub struct RgaConfig {
     #[structopt(long = "--rga-list-adapters", help = "List all known adapters")]
     pub list_adapters: bool,
 
+    /// Show which adapters the given files would go through and why, instead of searching them.
+    ///
+    /// Prints the detected mime type, all matching adapters with the matcher that fired, the adapter that is used
+    /// (the others are fallbacks), the same for every file within archives, and whether the cache would hit.
+    /// Note that this runs the adapters to find the files within archives.
+    #[serde(skip)] // CLI only
+    #[structopt(long = "--rga-explain", hidden_short_help = true)]
+    pub explain: bool,
+
+    #[serde(skip)] // CLI only
+    #[structopt(
+        long = "--rga-list-adapters-json",
+        help = "List all known adapters as JSON, including whether the programs they need are installed"
+    )]
+    pub list_adapters_json: bool,
+
+    /// Check that the programs the enabled adapters need (pdftotext, pandoc, ffmpeg, custom adapters, ...)
+    /// are installed, that the cache can be opened and that the config file can be parsed.
+    #[serde(skip)] // CLI only
+    #[structopt(long = "--rga-doctor", hidden_short_help = true)]
+    pub doctor: bool,
+
+    /// Print the time, bytes read and written and number of calls of each adapter and cache operation to stderr after the search.
+    ///
+    /// The time of an adapter includes the time of the adapters of the files within it (e.g. in a zip file).
+    #[serde(skip)] // CLI only
+    #[structopt(long = "--rga-profile", hidden_short_help = true)]
+    pub profile: bool,
+
+    /// Like `--rga-profile`, and also write every adapter and cache operation to the given file
+    /// in the Chrome trace event format (can be opened in chrome://tracing or https://ui.perfetto.dev).
+    #[serde(skip)] // CLI only
+    #[structopt(
+        long = "--rga-profile-trace",
+        require_equals = true,
+        hidden_short_help = true
+    )]
+    pub profile_trace: Option<String>,
+
     #[serde(skip)] // CLI only
     #[structopt(
         long = "--rga-print-config-schema",
@@ -250,6 +629,115 @@ pub struct CacheConfig {
         require_equals = true
     )]
     pub path: CachePath,
+
+    /// Encrypt cached extracts with a key read from this file.
+    ///
+    /// If set (or if the environment variable `RGA_CACHE_KEY` contains key material),
+    /// cache entries are stored with authenticated encryption (XChaCha20-Poly1305),
+    /// and file paths are only stored as keyed hashes so the cache does not leak file names.
+    /// Entries written with a different key (or without encryption) are treated as cache misses.
+    #[serde(default, skip_serializing_if = "is_default")]
+    #[structopt(
+        long = "--rga-cache-key-file",
+        hidden_short_help = true,
+        require_equals = true
+    )]
+    pub key_file: Option<String>,
+}
+
+#[derive(StructOpt, Debug, Deserialize, Serialize, JsonSchema, Default, Clone, PartialEq)]
+pub struct TikaConfig {
+    /// Base URL of the Apache Tika server used by the tika adapter.
+    ///
+    /// The tika adapter is disabled by default, enable it with `--rga-adapters=+tika`.
+    /// Any server implementing the `PUT /tika` and `PUT /meta` endpoints of tika-server works.
+    /// Only `http://` URLs are supported, use a reverse proxy on localhost to reach a server over https.
+    #[serde(default, skip_serializing_if = "is_default")]
+    #[structopt(
+        default_value,
+        long = "--rga-tika-url",
+        hidden_short_help = true,
+        require_equals = true
+    )]
+    pub url: TikaUrl,
+
+    /// Timeout in seconds for a single request to the Tika server.
+    #[serde(default, skip_serializing_if = "is_default")]
+    #[structopt(
+        default_value,
+        long = "--rga-tika-timeout",
+        hidden_short_help = true,
+        require_equals = true
+    )]
+    pub timeout: TikaTimeout,
+
+    /// Also fetch the document metadata (title, author, ...) from the Tika server and prepend it to the text.
+    #[serde(default, skip_serializing_if = "is_default")]
+    #[structopt(long = "--rga-tika-metadata", hidden_short_help = true)]
+    pub metadata: bool,
+}
+
+/// Limits against zip bombs and other files that expand to huge outputs.
+///
+/// When a limit is reached, rga stops reading the file and puts a `[rga: limit exceeded: ...]` line in the output.
+#[derive(StructOpt, Debug, Deserialize, Serialize, JsonSchema, Default, Clone, PartialEq)]
+pub struct LimitsConfig {
+    /// Maximum total size of the files within an archive (including nested archives and compressed files),
+    /// per searched file. 0 for no limit.
+    ///
+    /// Allowed suffixes on command line: k M G
+    #[serde(default, skip_serializing_if = "is_default")]
+    #[structopt(
+        default_value,
+        long = "--rga-limit-total-size",
+        hidden_short_help = true,
+        require_equals = true
+    )]
+    pub total_size: MaxTotalSize,
+
+    /// Maximum ratio between the decompressed and compressed size of a file within an archive
+    /// or a compressed file. 0 for no limit.
+    #[serde(default, skip_serializing_if = "is_default")]
+    #[structopt(
+        default_value,
+        long = "--rga-limit-compression-ratio",
+        hidden_short_help = true,
+        require_equals = true
+    )]
+    pub compression_ratio: MaxCompressionRatio,
+
+    /// Maximum number of files within an archive (including nested archives), per searched file. 0 for no limit.
+    #[serde(default, skip_serializing_if = "is_default")]
+    #[structopt(
+        default_value,
+        long = "--rga-limit-archive-members",
+        hidden_short_help = true,
+        require_equals = true
+    )]
+    pub archive_members: MaxArchiveMembers,
+
+    /// Maximum size of the output of a single adapter run. 0 for no limit.
+    ///
+    /// Allowed suffixes on command line: k M G
+    #[serde(default, skip_serializing_if = "is_default")]
+    #[structopt(
+        default_value,
+        long = "--rga-limit-output-size",
+        hidden_short_help = true,
+        require_equals = true
+    )]
+    pub output_size: MaxOutputSize,
+}
+
+impl RgaConfig {
+    /// the detection mode, taking `--rga-accurate` into account
+    pub fn detection_mode(&self) -> DetectionMode {
+        if self.accurate {
+            DetectionMode::Accurate
+        } else {
+            self.detection
+        }
+    }
 }
 
 static RGA_CONFIG: &str = "RGA_CONFIG";
@@ -268,7 +756,7 @@ fn json_merge(a: &mut Value, b: &Value) {
     }
 }
 
-fn read_config_file(path_override: Option<String>) -> Result<(String, Value)> {
+pub(crate) fn read_config_file(path_override: Option<String>) -> Result<(String, Value)> {
     let proj = project_dirs()?;
     let config_dir = proj.config_dir();
     let config_filename = path_override
@@ -379,6 +867,11 @@ where
         // readd values with [serde(skip)]
         res.fzf_path = arg_matches.fzf_path;
         res.list_adapters = arg_matches.list_adapters;
+        res.explain = arg_matches.explain;
+        res.list_adapters_json = arg_matches.list_adapters_json;
+        res.doctor = arg_matches.doctor;
+        res.profile = arg_matches.profile;
+        res.profile_trace = arg_matches.profile_trace;
         res.print_config_schema = arg_matches.print_config_schema;
         res.rg_help = arg_matches.rg_help;
         res.rg_version = arg_matches.rg_version;
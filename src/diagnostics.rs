@@ -0,0 +1,236 @@
+/*!
+ * Everything rga prints to stderr: log messages and the progress line.
+ *
+ * Log records still go through env_logger (so `RUST_LOG` works as before), but each warning is only printed once,
+ * and the progress line is cleared before anything else is printed so they don't end up on the same line.
+ */
+use crate::config::Verbosity;
+use lazy_static::lazy_static;
+use log::{Level, LevelFilter, Log, Metadata, Record, warn};
+use std::collections::HashSet;
+use std::io::IsTerminal;
+use std::path::Path;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+/// minimum time between two redraws of the progress line
+const REDRAW_INTERVAL: Duration = Duration::from_millis(100);
+/// the progress line is cut off after this many characters so it doesn't wrap
+const MAX_PROGRESS_WIDTH: usize = 100;
+/// at most this many warnings are remembered, after that they are forgotten and may be printed again
+const MAX_SEEN_WARNINGS: usize = 10_000;
+
+lazy_static! {
+    static ref SEEN_WARNINGS: Mutex<SeenWarnings> = Mutex::new(SeenWarnings::default());
+    static ref PROGRESS: Mutex<Progress> = Mutex::new(Progress::default());
+}
+/// `RUST_LOG` was set, so it decides what is logged instead of `--rga-verbose`
+static LEVEL_FROM_ENV: AtomicBool = AtomicBool::new(false);
+
+struct DiagnosticsLogger {
+    inner: env_logger::Logger,
+}
+
+impl Log for DiagnosticsLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.inner.matches(record) {
+            return;
+        }
+        if record.level() == Level::Warn && !first_occurrence(&record.args().to_string()) {
+            return;
+        }
+        let mut progress = PROGRESS.lock().expect("progress poisoned");
+        progress.clear();
+        self.inner.log(record);
+    }
+
+    fn flush(&self) {
+        self.inner.flush()
+    }
+}
+
+#[derive(Default)]
+struct SeenWarnings(HashSet<String>);
+
+impl SeenWarnings {
+    /// true the first time a warning is seen
+    fn insert(&mut self, warning: &str) -> bool {
+        if self.0.contains(warning) {
+            return false;
+        }
+        if self.0.len() >= MAX_SEEN_WARNINGS {
+            self.0.clear();
+        }
+        self.0.insert(warning.to_string())
+    }
+}
+
+/// true the first time a warning is seen
+fn first_occurrence(warning: &str) -> bool {
+    SEEN_WARNINGS
+        .lock()
+        .expect("warnings poisoned")
+        .insert(warning)
+}
+
+/// print a warning only for the first occurrence of `key`. for warnings that name the file they are about,
+/// so they would otherwise be printed for every file
+pub fn warn_once(key: &str, message: std::fmt::Arguments) {
+    if first_occurrence(key) {
+        warn!("{message}");
+    }
+}
+
+/// install the logger. should be called as early as possible, then `configure` once the config is known
+pub fn init() {
+    let from_env = std::env::var_os("RUST_LOG").is_some();
+    let mut builder = env_logger::Builder::from_default_env();
+    if !from_env {
+        // the level is set with log::set_max_level instead
+        builder.filter_level(LevelFilter::Trace);
+    }
+    let inner = builder.build();
+    let max_level = if from_env {
+        inner.filter()
+    } else {
+        Verbosity::default().level_filter()
+    };
+    LEVEL_FROM_ENV.store(from_env, Ordering::Relaxed);
+    if log::set_boxed_logger(Box::new(DiagnosticsLogger { inner })).is_ok() {
+        log::set_max_level(max_level);
+    }
+}
+
+/// apply `--rga-verbose` and `--rga-progress`
+pub fn configure(verbosity: Verbosity, progress: bool) {
+    if !LEVEL_FROM_ENV.load(Ordering::Relaxed) {
+        log::set_max_level(verbosity.level_filter());
+    }
+    PROGRESS.lock().expect("progress poisoned").enabled =
+        progress && std::io::stderr().is_terminal();
+}
+
+#[derive(Default)]
+struct Progress {
+    enabled: bool,
+    walked: u64,
+    adapted: u64,
+    cache_hits: u64,
+    /// the adapter that was started last and its file
+    current: Option<String>,
+    last_draw: Option<Instant>,
+    /// the line is currently shown
+    drawn: bool,
+}
+
+impl Progress {
+    fn line(&self) -> String {
+        let mut line = format!(
+            "{} files, {} adapted, {} from cache",
+            self.walked, self.adapted, self.cache_hits
+        );
+        if let Some(current) = &self.current {
+            line.push_str(" | ");
+            line.push_str(current);
+        }
+        line.chars().take(MAX_PROGRESS_WIDTH).collect()
+    }
+
+    fn draw(&mut self) {
+        eprint!("\r\x1b[2K{}", self.line());
+        self.drawn = true;
+        self.last_draw = Some(Instant::now());
+    }
+
+    fn clear(&mut self) {
+        if self.drawn {
+            eprint!("\r\x1b[2K");
+            self.drawn = false;
+            // redraw with the next update
+            self.last_draw = None;
+        }
+    }
+}
+
+fn update_progress(f: impl FnOnce(&mut Progress)) {
+    let mut progress = PROGRESS.lock().expect("progress poisoned");
+    if !progress.enabled {
+        return;
+    }
+    f(&mut progress);
+    if progress
+        .last_draw
+        .is_none_or(|t| t.elapsed() >= REDRAW_INTERVAL)
+    {
+        progress.draw();
+    }
+}
+
+pub fn file_walked() {
+    update_progress(|p| p.walked += 1);
+}
+
+pub fn adapter_started(adapter: &str, path: &Path) {
+    update_progress(|p| p.current = Some(format!("{adapter}: {}", path.display())));
+}
+
+pub fn file_adapted() {
+    update_progress(|p| p.adapted += 1);
+}
+
+pub fn cache_hit() {
+    update_progress(|p| p.cache_hits += 1);
+}
+
+/// remove the progress line until the next update, e.g. before printing search results to the same terminal
+pub fn hide_progress() {
+    PROGRESS.lock().expect("progress poisoned").clear();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn warnings_once() {
+        assert!(first_occurrence("rgatest: something is off"));
+        assert!(!first_occurrence("rgatest: something is off"));
+        assert!(first_occurrence("rgatest: something else is off"));
+    }
+
+    #[test]
+    fn warnings_bounded() {
+        let mut seen = SeenWarnings::default();
+        assert!(seen.insert("first"));
+        for i in 1..MAX_SEEN_WARNINGS {
+            assert!(seen.insert(&i.to_string()));
+        }
+        assert_eq!(seen.0.len(), MAX_SEEN_WARNINGS);
+        assert!(!seen.insert("first"));
+        assert!(seen.insert("one too many"));
+        assert_eq!(seen.0.len(), 1);
+        // forgotten
+        assert!(seen.insert("first"));
+    }
+
+    #[test]
+    fn progress_line() {
+        let mut progress = Progress {
+            walked: 12,
+            adapted: 3,
+            cache_hits: 1,
+            ..Default::default()
+        };
+        assert_eq!(progress.line(), "12 files, 3 adapted, 1 from cache");
+        progress.current = Some(format!("poppler: {}", "a/".repeat(100)));
+        let line = progress.line();
+        assert!(line.starts_with("12 files, 3 adapted, 1 from cache | poppler: a/a/"));
+        assert_eq!(line.chars().count(), MAX_PROGRESS_WIDTH);
+    }
+}
@@ -0,0 +1,409 @@
+/*!
+ * `--rga-doctor`: check that the programs the adapters need are installed, and that the cache and config file work.
+ *
+ * Also provides the adapter list for `--rga-list-adapters-json`.
+ */
+use crate::adapters::custom::BUILTIN_SPAWNING_ADAPTERS;
+use crate::adapters::*;
+use crate::config::{RgaConfig, read_config_file};
+use crate::explain::describe_matcher;
+use crate::matching::{FastFileMatcher, FileMatcher};
+use crate::preproc_cache::open_cache_db;
+use crate::sandbox;
+use anyhow::Result;
+use serde::Serialize;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::process::Command;
+
+/// how long to wait for `--version` output or the tika server
+const CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Ok,
+    Warning,
+    Error,
+}
+
+impl std::fmt::Display for Status {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Status::Ok => "ok",
+            Status::Warning => "warning",
+            Status::Error => "error",
+        })
+    }
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Check {
+    pub name: String,
+    pub status: Status,
+    pub details: String,
+}
+
+/// an external program used by an adapter
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct BinaryInfo {
+    pub name: String,
+    /// where it was found in `PATH`
+    pub path: Option<PathBuf>,
+    /// the first line of its version output
+    pub version: Option<String>,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct AdapterInfo {
+    pub name: String,
+    pub version: i32,
+    pub description: String,
+    /// used with the current `--rga-adapters`
+    pub enabled: bool,
+    pub recurses: bool,
+    pub extensions: Vec<String>,
+    /// the other fast matchers, e.g. `file name glob Dockerfile*`
+    pub matchers: Vec<String>,
+    /// used with `--rga-accurate`
+    pub mimetypes: Vec<String>,
+    pub binaries: Vec<BinaryInfo>,
+}
+
+/// the programs an adapter runs
+fn adapter_binaries(config: &RgaConfig, name: &str) -> Vec<String> {
+    match name {
+        "ffmpeg" => vec!["ffprobe".to_string(), "ffmpeg".to_string()],
+        _ => config
+            .custom_adapters
+            .iter()
+            .flatten()
+            .chain(BUILTIN_SPAWNING_ADAPTERS.iter())
+            .find(|a| a.name == name)
+            .map(|a| vec![a.binary.clone()])
+            .unwrap_or_default(),
+    }
+}
+
+/// the arguments that make the program print its version
+fn version_args(binary: &str) -> &'static [&'static str] {
+    match binary {
+        "ffmpeg" | "ffprobe" => &["-version"],
+        "pdftotext" => &["-v"],
+        _ => &["--version"],
+    }
+}
+
+/// look up a program like the shell would
+fn find_in_path(binary: &str) -> Option<PathBuf> {
+    let candidates = |p: PathBuf| {
+        let exe = std::env::consts::EXE_EXTENSION;
+        let with_exe = (!exe.is_empty()).then(|| p.with_extension(exe));
+        std::iter::once(p).chain(with_exe)
+    };
+    if Path::new(binary).components().count() > 1 {
+        return candidates(PathBuf::from(binary)).find(|p| p.is_file());
+    }
+    std::env::split_paths(&std::env::var_os("PATH")?)
+        .flat_map(|dir| candidates(dir.join(binary)))
+        .find(|p| p.is_file())
+}
+
+pub async fn check_binary(binary: &str) -> BinaryInfo {
+    let mut info = BinaryInfo {
+        name: binary.to_string(),
+        path: find_in_path(binary),
+        version: None,
+        error: None,
+    };
+    let Some(path) = &info.path else {
+        info.error = Some("not found in PATH".to_string());
+        return info;
+    };
+    let output = Command::new(path)
+        .args(version_args(binary))
+        .stdin(Stdio::null())
+        .kill_on_drop(true)
+        .output();
+    match tokio::time::timeout(CHECK_TIMEOUT, output).await {
+        Ok(Ok(output)) => {
+            let first_line = |out: &[u8]| {
+                String::from_utf8_lossy(out)
+                    .lines()
+                    .map(str::trim)
+                    .find(|line| !line.is_empty())
+                    .map(str::to_string)
+            };
+            // some programs print the version to stderr
+            info.version = first_line(&output.stdout).or_else(|| first_line(&output.stderr));
+        }
+        Ok(Err(e)) => info.error = Some(format!("could not run: {e}")),
+        Err(_) => info.error = Some("timed out printing its version".to_string()),
+    }
+    info
+}
+
+/// all adapters, with the state of the programs they need
+pub async fn adapter_infos(config: &RgaConfig) -> Result<Vec<AdapterInfo>> {
+    let enabled: HashSet<String> = get_adapters_filtered(
+        config.custom_adapters.clone(),
+        &config.adapter_overrides,
+        &config.adapters,
+    )?
+    .iter()
+    .map(|a| a.metadata().name.clone())
+    .collect();
+    let (default_enabled, default_disabled) =
+        get_all_adapters(config.custom_adapters.clone(), &config.adapter_overrides);
+    let mut infos = vec![];
+    for adapter in default_enabled.iter().chain(default_disabled.iter()) {
+        let meta = adapter.metadata();
+        let mut binaries = vec![];
+        for binary in adapter_binaries(config, &meta.name) {
+            binaries.push(check_binary(&binary).await);
+        }
+        let (extensions, matchers) = meta
+            .fast_matchers
+            .iter()
+            .partition::<Vec<_>, _>(|m| matches!(m, FastFileMatcher::FileExtension(_)));
+        infos.push(AdapterInfo {
+            name: meta.name.clone(),
+            version: meta.version,
+            description: meta.description.clone(),
+            enabled: enabled.contains(&meta.name),
+            recurses: meta.recurses,
+            extensions: extensions
+                .into_iter()
+                .filter_map(|m| match m {
+                    FastFileMatcher::FileExtension(ext) => Some(ext.clone()),
+                    _ => None,
+                })
+                .collect(),
+            matchers: matchers
+                .into_iter()
+                .map(|m| describe_matcher(&FileMatcher::Fast(m.clone())))
+                .chain(meta.slow_matchers.iter().flatten().filter_map(|m| match m {
+                    FileMatcher::MagicBytes { .. } => Some(describe_matcher(m)),
+                    _ => None,
+                }))
+                .collect(),
+            mimetypes: meta
+                .slow_matchers
+                .iter()
+                .flatten()
+                .filter_map(|m| match m {
+                    FileMatcher::MimeType(mime) => Some(mime.clone()),
+                    _ => None,
+                })
+                .collect(),
+            binaries,
+        });
+    }
+    Ok(infos)
+}
+
+fn adapter_check(adapter: &AdapterInfo) -> Check {
+    let details = adapter
+        .binaries
+        .iter()
+        .map(|b| match (&b.version, &b.error) {
+            (_, Some(e)) => format!("{}: {e}", b.name),
+            (Some(version), None) => format!("{}: {version}", b.name),
+            (None, None) => format!("{}: found, unknown version", b.name),
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+    Check {
+        name: format!("adapter {}", adapter.name),
+        status: if adapter.binaries.iter().all(|b| b.error.is_none()) {
+            Status::Ok
+        } else {
+            Status::Error
+        },
+        details: if details.is_empty() {
+            "built in".to_string()
+        } else {
+            details
+        },
+    }
+}
+
+async fn cache_check(config: &RgaConfig) -> Check {
+    let name = "cache".to_string();
+    if config.cache.disabled {
+        return Check {
+            name,
+            status: Status::Ok,
+            details: "disabled".to_string(),
+        };
+    }
+    match open_cache_db(&config.cache).await {
+        Ok(_) => Check {
+            name,
+            status: Status::Ok,
+            details: config.cache.path.0.clone(),
+        },
+        Err(e) => Check {
+            name,
+            status: Status::Error,
+            details: format!("{}: {e:#}", config.cache.path.0),
+        },
+    }
+}
+
+fn config_file_check(config: &RgaConfig) -> Check {
+    let name = "config file".to_string();
+    match read_config_file(config.config_file_path.clone()) {
+        Ok((path, _)) => Check {
+            name,
+            status: Status::Ok,
+            details: path,
+        },
+        Err(e) => Check {
+            name,
+            status: Status::Error,
+            details: format!("{e:#}"),
+        },
+    }
+}
+
+async fn tika_check(config: &RgaConfig) -> Check {
+    let url = format!("{}/version", config.tika.url.0.trim_end_matches('/'));
+    let response = async {
+        let response = reqwest::Client::builder()
+            .timeout(CHECK_TIMEOUT)
+            .build()?
+            .get(&url)
+            .send()
+            .await?
+            .error_for_status()?;
+        anyhow::Ok(response.text().await?)
+    };
+    let (status, details) = match response.await {
+        Ok(version) => (Status::Ok, version.trim().to_string()),
+        Err(e) => (Status::Error, format!("{url}: {e:#}")),
+    };
+    Check {
+        name: "tika server".to_string(),
+        status,
+        details,
+    }
+}
+
+fn sandbox_check() -> Check {
+    // only prepares the restrictions, nothing is started
+    let mut cmd = Command::new("true");
+    let (status, details) = match sandbox::restrict(&mut cmd, &Default::default()) {
+        Ok(()) => (Status::Ok, "supported".to_string()),
+        Err(e) => (Status::Error, format!("{e:#}")),
+    };
+    Check {
+        name: "sandbox".to_string(),
+        status,
+        details,
+    }
+}
+
+/// run all checks. a missing program of a disabled adapter is only a warning
+pub async fn doctor(config: &RgaConfig) -> Result<Vec<Check>> {
+    let mut checks = vec![config_file_check(config), cache_check(config).await];
+    let adapters = adapter_infos(config).await?;
+    for adapter in &adapters {
+        let mut check = adapter_check(adapter);
+        if !adapter.enabled {
+            if check.status != Status::Ok {
+                check.status = Status::Warning;
+            }
+            check.details.push_str(" (disabled)");
+        }
+        checks.push(check);
+    }
+    if adapters.iter().any(|a| a.enabled && a.name == "tika") {
+        checks.push(tika_check(config).await);
+    }
+    let sandboxed = adapters.iter().any(|a| {
+        a.enabled
+            && (sandbox::enabled_for(config, &a.name)
+                || config
+                    .custom_adapters
+                    .iter()
+                    .flatten()
+                    .any(|c| c.name == a.name && c.sandbox == Some(true)))
+    });
+    if sandboxed {
+        checks.push(sandbox_check());
+    }
+    Ok(checks)
+}
+
+pub fn format_table(checks: &[Check]) -> String {
+    let width = checks.iter().map(|c| c.name.len()).max().unwrap_or(0);
+    checks
+        .iter()
+        .map(|c| {
+            format!(
+                "{:<width$}  {:<7}  {}\n",
+                c.name,
+                c.status.to_string(),
+                c.details
+            )
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[tokio::test]
+    async fn binaries() {
+        let sh = check_binary("sh").await;
+        assert!(sh.path.is_some());
+        assert_eq!(sh.error, None);
+        let missing = check_binary("rga-test-binary-that-does-not-exist").await;
+        assert_eq!(missing.error.as_deref(), Some("not found in PATH"));
+    }
+
+    #[tokio::test]
+    async fn adapters() -> Result<()> {
+        let infos = adapter_infos(&RgaConfig::default()).await?;
+        let poppler = infos
+            .iter()
+            .find(|a| a.name == "poppler")
+            .expect("poppler is builtin");
+        assert!(poppler.enabled);
+        assert_eq!(poppler.extensions, vec!["pdf"]);
+        assert_eq!(poppler.binaries[0].name, "pdftotext");
+        let mail = infos
+            .iter()
+            .find(|a| a.name == "mail")
+            .expect("mail is builtin");
+        assert!(!mail.enabled);
+        assert!(mail.binaries.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn table() {
+        let checks = vec![
+            Check {
+                name: "cache".to_string(),
+                status: Status::Ok,
+                details: "disabled".to_string(),
+            },
+            Check {
+                name: "adapter ffmpeg".to_string(),
+                status: Status::Error,
+                details: "ffprobe: not found in PATH".to_string(),
+            },
+        ];
+        assert_eq!(
+            format_table(&checks),
+            "cache           ok       disabled\nadapter ffmpeg  error    ffprobe: not found in PATH\n"
+        );
+    }
+}
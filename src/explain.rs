@@ -0,0 +1,208 @@
+/*!
+ * `--rga-explain`: show which adapters a file goes through and why, without searching it.
+ */
+use crate::adapters::*;
+use crate::config::RgaConfig;
+use crate::limits::LimitUsage;
+use crate::matching::{AdapterCandidate, FastFileMatcher, FileMatcher, MAGIC_SNIFF_LEN};
+use crate::preproc::{ActiveAdapters, choose_adapters, peek};
+use crate::preproc_cache::{CacheKey, CacheLookup, PreprocCache, open_cache_db};
+use anyhow::{Context, Result};
+use std::fmt::Write;
+use std::future::Future;
+use std::path::Path;
+use std::pin::Pin;
+use tokio_stream::StreamExt;
+
+/// human readable description of a matcher, e.g. `extension .gz`
+pub fn describe_matcher(matcher: &FileMatcher) -> String {
+    use FastFileMatcher::*;
+    match matcher {
+        FileMatcher::Fast(FileExtension(ext)) => format!("extension .{ext}"),
+        FileMatcher::Fast(FileNameGlob(glob)) => format!("file name glob {glob}"),
+        FileMatcher::Fast(PathGlob(glob)) => format!("path glob {glob}"),
+        FileMatcher::Fast(PathRegex(re)) => format!("path regex {re}"),
+        FileMatcher::Fast(PathPrefix(prefix)) => format!("path prefix {prefix}"),
+        FileMatcher::MimeType(mime) => format!("mime type {mime}"),
+        FileMatcher::MagicBytes { offset, bytes } => {
+            format!(
+                "magic bytes \"{}\" at offset {offset}",
+                bytes.escape_ascii()
+            )
+        }
+    }
+}
+
+/// describe how rga would adapt the given file
+pub async fn explain(config: &RgaConfig, path: &Path) -> Result<String> {
+    let file = tokio::fs::File::open(path)
+        .await
+        .with_context(|| format!("opening {}", path.display()))?;
+    let mut out = String::new();
+    writeln!(out, "detection mode: {}", config.detection_mode())?;
+    let ai = AdaptInfo {
+        filepath_hint: path.to_path_buf(),
+        is_real_file: true,
+        archive_recursion_depth: 0,
+        inp: Box::pin(file),
+        line_prefix: String::new(),
+        // the explanation is about the adapters, the prefixes would only add noise
+        postprocess: false,
+        config: config.clone(),
+        limit_usage: LimitUsage::default(),
+    };
+    explain_file(ai, 0, &mut out).await?;
+    Ok(out)
+}
+
+fn explain_file<'a>(
+    ai: AdaptInfo,
+    indent: usize,
+    out: &'a mut String,
+) -> Pin<Box<dyn Future<Output = Result<()>> + Send + 'a>> {
+    Box::pin(async move {
+        let pad = "  ".repeat(indent);
+        let mut inp = ai.inp;
+        let mimetype = tree_magic::from_u8(&peek(&mut inp, MAGIC_SNIFF_LEN).await?);
+        let (candidates, active_adapters) = choose_adapters(
+            &ai.config,
+            &ai.filepath_hint,
+            ai.archive_recursion_depth,
+            &mut inp,
+        )
+        .await?;
+        writeln!(
+            out,
+            "{pad}{} (depth {}, detected mime type {mimetype})",
+            ai.filepath_hint.display(),
+            ai.archive_recursion_depth
+        )?;
+        if candidates.is_empty() {
+            writeln!(out, "{pad}  no matching adapter, searched as is")?;
+            if let Err(e) = tokio::io::copy(&mut inp, &mut tokio::io::sink()).await {
+                writeln!(out, "{pad}  error reading: {e}")?;
+            }
+            return Ok(());
+        }
+        for (i, (adapter, matcher)) in candidates.iter().enumerate() {
+            writeln!(
+                out,
+                "{pad}  {}. {} (matched by {}){}",
+                i + 1,
+                adapter.metadata().name,
+                describe_matcher(matcher),
+                if i == 0 { ", used" } else { ", fallback" }
+            )?;
+        }
+        if ai.is_real_file {
+            explain_cache(
+                &ai.config,
+                &ai.filepath_hint,
+                &candidates,
+                &active_adapters,
+                &pad,
+                out,
+            )
+            .await?;
+        }
+        if ai.archive_recursion_depth >= ai.config.max_archive_recursion.0 {
+            writeln!(out, "{pad}  max archive recursion reached, not adapted")?;
+            return Ok(());
+        }
+        let (adapter, matcher) = candidates.into_iter().next().expect("checked above");
+        let ai = AdaptInfo {
+            inp: Box::pin(inp),
+            ..ai
+        };
+        let mut outputs = match adapter.adapt(ai, &matcher).await {
+            Ok(outputs) => outputs,
+            Err(e) => {
+                writeln!(out, "{pad}  {} failed: {:#}", adapter.metadata().name, e)?;
+                return Ok(());
+            }
+        };
+        while let Some(output) = outputs.next().await {
+            match output {
+                Ok(output) => explain_file(output, indent + 1, out).await?,
+                Err(e) => {
+                    writeln!(out, "{pad}  {} failed: {:#}", adapter.metadata().name, e)?;
+                    break;
+                }
+            }
+        }
+        Ok(())
+    })
+}
+
+/// whether the cache has an entry for each of the candidates, checked in the same order as when searching
+async fn explain_cache(
+    config: &RgaConfig,
+    filepath_hint: &Path,
+    candidates: &[AdapterCandidate],
+    active_adapters: &ActiveAdapters,
+    pad: &str,
+    out: &mut String,
+) -> Result<()> {
+    if config.cache.disabled {
+        writeln!(out, "{pad}  cache: disabled")?;
+        return Ok(());
+    }
+    let cache = open_cache_db(&config.cache).await?;
+    for (adapter, _) in candidates {
+        let key = CacheKey::new(
+            !config.no_prefix_filenames,
+            filepath_hint,
+            adapter.as_ref(),
+            active_adapters,
+        )?;
+        let lookup = cache.get(&key).await.context("cache.get")?;
+        let status = match lookup {
+            CacheLookup::Hit(_) => "hit",
+            CacheLookup::Miss => "miss",
+            CacheLookup::Corrupt => "corrupt",
+        };
+        writeln!(out, "{pad}  cache {status}: {key}")?;
+        if let CacheLookup::Hit(_) = lookup {
+            break;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+    use pretty_assertions::assert_eq;
+
+    #[tokio::test]
+    async fn gz() -> Result<()> {
+        let mut config = RgaConfig::default();
+        config.cache.disabled = true;
+        let path = test_data_dir().join("hello.gz");
+        let explanation = explain(&config, &path).await?;
+        let lines: Vec<_> = explanation.lines().collect();
+        assert_eq!(lines[0], "detection mode: fast");
+        assert!(lines[1].starts_with(&format!("{} (depth 0", path.display())));
+        assert_eq!(lines[2], "  1. decompress (matched by extension .gz), used");
+        assert_eq!(lines[3], "  cache: disabled");
+        assert!(lines[4].starts_with(&format!(
+            "  {} (depth 1",
+            test_data_dir().join("hello").display()
+        )));
+        assert_eq!(lines[5], "    no matching adapter, searched as is");
+        Ok(())
+    }
+
+    #[test]
+    fn matchers() {
+        assert_eq!(
+            describe_matcher(&FileMatcher::magic(257, b"ustar")),
+            "magic bytes \"ustar\" at offset 257"
+        );
+        assert_eq!(
+            describe_matcher(&FileMatcher::MimeType("application/pdf".to_string())),
+            "mime type application/pdf"
+        );
+    }
+}
@@ -0,0 +1,275 @@
+/*!
+ * High level API to use rga's adapters as a library, for example in an indexer.
+ *
+ * ```no_run
+ * # async fn run() -> anyhow::Result<()> {
+ * use ripgrep_all::extract::{Extractor, ExtractorConfig};
+ * use tokio::io::AsyncReadExt;
+ * use tokio_stream::StreamExt;
+ *
+ * let extractor = Extractor::new(ExtractorConfig::default());
+ * let mut entries = extractor.extract("archive.tar.gz").await?;
+ * while let Some(entry) = entries.next().await {
+ *     let mut entry = entry?;
+ *     let mut text = String::new();
+ *     entry.text.read_to_string(&mut text).await?;
+ *     println!("{} (via {}): {}", entry.virtual_path.display(), entry.adapter_chain.join(" > "), text);
+ * }
+ * # Ok(())
+ * # }
+ * ```
+ *
+ * The files are adapted the same way as by `rga_preproc`, including the fallback to the next adapter
+ * and the limits, but the output is not decorated with line prefixes and not cached.
+ * When a limit is reached, the entry with the text `[rga: limit exceeded: ...]` is returned.
+ */
+use crate::adapters::custom::CustomAdapterConfig;
+use crate::adapters::{AdaptInfo, ReadBox};
+use crate::config::{AdapterTimeout, CacheConfig, LimitsConfig, MaxArchiveRecursion, RgaConfig};
+use crate::limits::LimitUsage;
+use crate::matching::FileMatcher;
+use crate::preproc::preproc_entries;
+use anyhow::{Context, Result};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::time::Duration;
+use tokio_stream::{Stream, StreamExt};
+
+/// Options for the [Extractor]. Independent of the command line configuration.
+#[derive(Debug, Clone)]
+pub struct ExtractorConfig {
+    /// Which adapters to use, same syntax as `--rga-adapters`. Empty means the default list.
+    pub adapters: Vec<String>,
+    /// Detect file types by mime type instead of only by file name (`--rga-accurate`).
+    pub accurate: bool,
+    /// Maximum depth of nested archives to recurse into.
+    pub max_archive_recursion: i32,
+    /// Additional subprocess-spawning adapters.
+    pub custom_adapters: Vec<CustomAdapterConfig>,
+    /// Limits against zip bombs, same as the `--rga-limit-*` flags.
+    pub limits: LimitsConfig,
+    /// Stop extracting a file after this time, in whole seconds (`--rga-adapter-timeout`).
+    /// The text of the current entry then ends with `[rga: adapter timed out after Ns]` and the next entry is an error.
+    pub adapter_timeout: Option<Duration>,
+}
+
+impl Default for ExtractorConfig {
+    fn default() -> Self {
+        Self {
+            adapters: vec![],
+            accurate: false,
+            max_archive_recursion: MaxArchiveRecursion::default().0,
+            custom_adapters: vec![],
+            limits: LimitsConfig::default(),
+            adapter_timeout: None,
+        }
+    }
+}
+
+impl ExtractorConfig {
+    fn to_rga_config(&self) -> RgaConfig {
+        RgaConfig {
+            accurate: self.accurate,
+            adapters: self.adapters.clone(),
+            max_archive_recursion: MaxArchiveRecursion(self.max_archive_recursion),
+            custom_adapters: Some(self.custom_adapters.clone()),
+            limits: self.limits.clone(),
+            adapter_timeout: AdapterTimeout(self.adapter_timeout.map_or(0, |t| t.as_secs().max(1))),
+            no_prefix_filenames: true,
+            cache: CacheConfig {
+                disabled: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+}
+
+/// One extracted file. For a plain document this is the converted text of the document itself,
+/// for archives there is one entry per member.
+pub struct ExtractedEntry {
+    /// Path of this entry. For files within archives, this is the path within the innermost archive.
+    pub virtual_path: PathBuf,
+    /// The files this entry was extracted from, outermost (the input file) first. Empty for the input file itself.
+    pub containers: Vec<PathBuf>,
+    /// Names of the adapters that were run to get this entry, outermost first. Empty if no adapter matched.
+    pub adapter_chain: Vec<String>,
+    /// The matcher that selected the last adapter in the chain.
+    pub detected_by: Option<FileMatcher>,
+    /// Depth at which this entry is in archives. 0 for the input file.
+    pub archive_recursion_depth: i32,
+    /// The text of this entry. Must be read fully (or dropped) before polling the next entry.
+    pub text: ReadBox,
+}
+
+pub type ExtractedEntries = Pin<Box<dyn Stream<Item = Result<ExtractedEntry>> + Send>>;
+
+pub struct Extractor {
+    config: RgaConfig,
+}
+
+impl Extractor {
+    pub fn new(config: ExtractorConfig) -> Self {
+        Self {
+            config: config.to_rga_config(),
+        }
+    }
+
+    /// Extract the text of a file on the file system.
+    pub async fn extract(&self, path: impl AsRef<Path>) -> Result<ExtractedEntries> {
+        let path = path.as_ref();
+        let file = tokio::fs::File::open(path)
+            .await
+            .with_context(|| format!("opening {}", path.display()))?;
+        self.extract_ai(AdaptInfo {
+            filepath_hint: path.to_path_buf(),
+            is_real_file: true,
+            archive_recursion_depth: 0,
+            inp: Box::pin(file),
+            line_prefix: "".to_string(),
+            postprocess: false,
+            config: self.config.clone(),
+            limit_usage: LimitUsage::default(),
+        })
+        .await
+    }
+
+    /// Extract the text of a stream. `virtual_path` is used to detect the file type.
+    pub async fn extract_reader(
+        &self,
+        virtual_path: impl Into<PathBuf>,
+        inp: ReadBox,
+    ) -> Result<ExtractedEntries> {
+        self.extract_ai(AdaptInfo {
+            filepath_hint: virtual_path.into(),
+            is_real_file: false,
+            archive_recursion_depth: 0,
+            inp,
+            line_prefix: "".to_string(),
+            postprocess: false,
+            config: self.config.clone(),
+            limit_usage: LimitUsage::default(),
+        })
+        .await
+    }
+
+    async fn extract_ai(&self, ai: AdaptInfo) -> Result<ExtractedEntries> {
+        let entries = preproc_entries(ai).await?;
+        Ok(Box::pin(entries.map(|entry| {
+            entry.map(|entry| ExtractedEntry {
+                virtual_path: entry.ai.filepath_hint,
+                containers: entry.steps.iter().map(|s| s.path.clone()).collect(),
+                adapter_chain: entry.steps.iter().map(|s| s.adapter.clone()).collect(),
+                detected_by: entry.steps.last().map(|s| s.detected_by.clone()),
+                archive_recursion_depth: entry.ai.archive_recursion_depth,
+                text: entry.ai.inp,
+            })
+        })))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::MaxArchiveMembers;
+    use crate::test_utils::*;
+    use pretty_assertions::assert_eq;
+    use tokio::io::AsyncReadExt;
+
+    async fn read_entries(mut entries: ExtractedEntries) -> Result<Vec<(ExtractedEntry, String)>> {
+        let mut out = vec![];
+        while let Some(entry) = entries.next().await {
+            let mut entry = entry?;
+            let mut text = String::new();
+            entry.text.read_to_string(&mut text).await?;
+            out.push((entry, text));
+        }
+        Ok(out)
+    }
+
+    async fn create_tar(files: &[(&str, &str)]) -> Result<ReadBox> {
+        let mut builder = tokio_tar::Builder::new(Vec::new());
+        for (name, content) in files {
+            let mut header = tokio_tar::Header::new_gnu();
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            builder
+                .append_data(&mut header, name, content.as_bytes())
+                .await?;
+        }
+        Ok(Box::pin(std::io::Cursor::new(builder.into_inner().await?)))
+    }
+
+    #[tokio::test]
+    async fn extract_gz() -> Result<()> {
+        let extractor = Extractor::new(ExtractorConfig::default());
+        let filepath = test_data_dir().join("hello.gz");
+        let mut entries = extractor.extract(&filepath).await?;
+        let mut entry = entries.next().await.expect("one entry")?;
+        let mut text = String::new();
+        entry.text.read_to_string(&mut text).await?;
+        assert_eq!(text, "hello\n");
+        assert_eq!(entry.virtual_path, test_data_dir().join("hello"));
+        assert_eq!(entry.containers, vec![filepath]);
+        assert_eq!(entry.adapter_chain, vec!["decompress".to_string()]);
+        assert_eq!(entry.archive_recursion_depth, 1);
+        assert!(entries.next().await.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn archive_members() -> Result<()> {
+        let extractor = Extractor::new(ExtractorConfig::default());
+        let tar = create_tar(&[("a.txt", "hello"), ("dir/b.txt", "world")]).await?;
+        let entries = read_entries(extractor.extract_reader("test.tar", tar).await?).await?;
+        let summary: Vec<_> = entries
+            .iter()
+            .map(|(e, text)| {
+                (
+                    e.virtual_path.clone(),
+                    e.adapter_chain.clone(),
+                    text.clone(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (
+                    PathBuf::from("a.txt"),
+                    vec!["tar".to_string()],
+                    "hello".to_string()
+                ),
+                (
+                    PathBuf::from("dir/b.txt"),
+                    vec!["tar".to_string()],
+                    "world".to_string()
+                ),
+            ]
+        );
+        assert_eq!(entries[0].0.containers, vec![PathBuf::from("test.tar")]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn limits() -> Result<()> {
+        let extractor = Extractor::new(ExtractorConfig {
+            limits: LimitsConfig {
+                archive_members: MaxArchiveMembers(1),
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        let tar = create_tar(&[("a.txt", "hello"), ("b.txt", "world")]).await?;
+        let entries = read_entries(extractor.extract_reader("test.tar", tar).await?).await?;
+        let texts: Vec<_> = entries.iter().map(|(_, text)| text.as_str()).collect();
+        assert_eq!(
+            texts,
+            vec![
+                "hello",
+                "[rga: limit exceeded: more than 1 files in archive]\n"
+            ]
+        );
+        Ok(())
+    }
+}
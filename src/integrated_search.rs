@@ -5,27 +5,41 @@ use grep_regex::RegexMatcherBuilder;
 use grep_searcher::{BinaryDetection, SearcherBuilder};
 use ignore::WalkBuilder;
 use log::debug;
+use std::io::Read;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use termcolor::{ColorChoice, StandardStream};
 
 use crate::adapters::*;
-use crate::config::RgaConfig;
+use crate::config::{DetectionMode, RgaConfig};
+use crate::diagnostics;
+use crate::limits::LimitUsage;
+use crate::matching::{AdapterCandidate, FileMeta, MAGIC_SNIFF_LEN, adapter_matcher};
 use crate::preproc::*;
 
+type FastMatcher = Box<dyn Fn(FileMeta) -> Option<AdapterCandidate> + Send + Sync>;
+
 pub struct IntegratedSearcher {
     config: RgaConfig,
     _adapters: Vec<Arc<dyn FileAdapter>>,
     pre_glob: String,
+    /// matches files by name and path, for the matchers that can't be expressed in pre_glob, and by magic bytes
+    fast_matcher: FastMatcher,
 }
 
 impl IntegratedSearcher {
-    pub fn new(config: RgaConfig, adapters: Vec<Arc<dyn FileAdapter>>, pre_glob: String) -> Self {
-        Self {
+    pub fn new(
+        config: RgaConfig,
+        adapters: Vec<Arc<dyn FileAdapter>>,
+        pre_glob: String,
+    ) -> Result<Self> {
+        let fast_matcher = Box::new(adapter_matcher(&adapters, config.detection_mode())?);
+        Ok(Self {
             config,
             _adapters: adapters,
             pre_glob,
-        }
+            fast_matcher,
+        })
     }
 
     /// Run the integrated search with the given pattern and paths
@@ -68,6 +82,7 @@ impl IntegratedSearcher {
         };
 
         let mut found_match = false;
+        let mut errors = vec![];
 
         for path in paths_to_search {
             let walker = WalkBuilder::new(&path)
@@ -88,27 +103,42 @@ impl IntegratedSearcher {
                 }
 
                 let file_path = entry.path();
+                diagnostics::file_walked();
                 
                 // Check if file matches pre_glob pattern
-                if !self.should_preprocess(file_path) {
+                let result = if !self.should_preprocess(file_path) {
                     // For non-preprocessed files, search directly
-                    if self.search_file(&mut searcher, &matcher, &mut printer, file_path)? {
-                        found_match = true;
-                    }
+                    self.search_file(&mut searcher, &matcher, &mut printer, file_path)
                 } else {
                     // Preprocess the file inline and search the output
-                    if self.search_preprocessed_file_async(&matcher, &mut printer, file_path, !no_line_number).await? {
-                        found_match = true;
+                    self.search_preprocessed_file_async(&matcher, &mut printer, file_path, !no_line_number).await
+                };
+                match result {
+                    Ok(true) => found_match = true,
+                    Ok(false) => {}
+                    // continue with the other files, the errors are shown at the end
+                    Err(e) => {
+                        debug!("Error searching {}: {:#}", file_path.display(), e);
+                        errors.push(FileError::new(file_path, &e));
                     }
                 }
             }
         }
 
-        // Return exit code: 0 if found matches, 1 if not
-        Ok(if found_match { 0 } else { 1 })
+        diagnostics::hide_progress();
+        print_error_summary(&errors);
+
+        // Return exit code: 0 if found matches, 1 if not, 2 if a file could not be searched with --rga-strict
+        Ok(if self.config.strict && !errors.is_empty() {
+            2
+        } else if found_match {
+            0
+        } else {
+            1
+        })
     }
 
-    /// Check if a file should be preprocessed based on pre_glob pattern
+    /// Check if a file should be preprocessed based on pre_glob pattern, the other adapter matchers and magic bytes
     fn should_preprocess(&self, path: &Path) -> bool {
         if self.pre_glob == "*" {
             return true;
@@ -117,11 +147,39 @@ impl IntegratedSearcher {
         if let Some(ext) = path.extension() {
             let ext_str = ext.to_string_lossy().to_lowercase();
             // Extract extensions from pre_glob (format: "*.{ext1,ext2,...}")
-            if let Some(exts) = self.pre_glob.strip_prefix("*.{").and_then(|s| s.strip_suffix("}")) {
-                return exts.split(',').any(|e| e.to_lowercase() == ext_str);
+            let exts = self.pre_glob.strip_prefix("*.{").and_then(|s| s.strip_suffix("}"));
+            if exts.is_some_and(|exts| exts.split(',').any(|e| e.to_lowercase() == ext_str)) {
+                return true;
             }
         }
-        false
+        // other matchers, e.g. file name globs
+        let Some(filename) = path.file_name() else {
+            return false;
+        };
+        let mut meta = FileMeta {
+            lossy_filename: filename.to_string_lossy().to_string(),
+            lossy_path: path
+                .to_string_lossy()
+                .replace(std::path::MAIN_SEPARATOR, "/"),
+            mimetype: None,
+            head: None,
+        };
+        if (self.fast_matcher)(meta.clone()).is_some() {
+            return true;
+        }
+        if self.config.detection_mode() != DetectionMode::Hybrid {
+            return false;
+        }
+        // unknown file name, check the magic bytes
+        let mut head = Vec::with_capacity(MAGIC_SNIFF_LEN);
+        let read = std::fs::File::open(path)
+            .and_then(|f| f.take(MAGIC_SNIFF_LEN as u64).read_to_end(&mut head));
+        if let Err(e) = read {
+            debug!("could not read {}: {}", path.display(), e);
+            return false;
+        }
+        meta.head = Some(head);
+        (self.fast_matcher)(meta).is_some()
     }
 
     /// Search a regular file directly without preprocessing
@@ -132,6 +190,7 @@ impl IntegratedSearcher {
         printer: &mut grep_printer::Standard<StandardStream>,
         path: &Path,
     ) -> Result<bool> {
+        diagnostics::hide_progress();
         let result = searcher.search_path(
             matcher,
             path,
@@ -145,10 +204,7 @@ impl IntegratedSearcher {
                 // TODO: Track actual match count for accurate exit codes
                 Ok(true)
             }
-            Err(err) => {
-                debug!("Error searching {}: {}", path.display(), err);
-                Ok(false)
-            }
+            Err(err) => Err(anyhow::Error::new(err).context("Failed to search file")),
         }
     }
 
@@ -166,6 +222,7 @@ impl IntegratedSearcher {
         let preprocessed = self.preprocess_file_async(path).await?;
 
         // Search the preprocessed content
+        diagnostics::hide_progress();
         let mut searcher = SearcherBuilder::new()
             .binary_detection(BinaryDetection::quit(b'\x00'))
             .line_number(line_numbers)
@@ -183,10 +240,7 @@ impl IntegratedSearcher {
                 // TODO: Track actual match count for accurate exit codes
                 Ok(true)
             }
-            Err(err) => {
-                debug!("Error searching preprocessed content for {}: {}", path.display(), err);
-                Ok(false)
-            }
+            Err(err) => Err(anyhow::Error::new(err).context("Failed to search preprocessed output")),
         }
     }
 
@@ -206,6 +260,7 @@ impl IntegratedSearcher {
             archive_recursion_depth: 0,
             postprocess: !self.config.no_prefix_filenames,
             config: self.config.clone(),
+            limit_usage: LimitUsage::default(),
         };
 
         let mut output = rga_preproc(ai).await
@@ -236,16 +291,14 @@ impl IntegratedSearcher {
                 "--color=always" => color = ColorChoiceArg::Always,
                 "--color=never" => color = ColorChoiceArg::Never,
                 "--color=auto" => color = ColorChoiceArg::Auto,
-                "--color" => {
-                    // Handle --color value format (two separate arguments)
-                    if i + 1 < args.len() {
-                        i += 1;
-                        match args[i].as_str() {
-                            "always" => color = ColorChoiceArg::Always,
-                            "never" => color = ColorChoiceArg::Never,
-                            "auto" => color = ColorChoiceArg::Auto,
-                            _ => {}
-                        }
+                // Handle --color value format (two separate arguments)
+                "--color" if i + 1 < args.len() => {
+                    i += 1;
+                    match args[i].as_str() {
+                        "always" => color = ColorChoiceArg::Always,
+                        "never" => color = ColorChoiceArg::Never,
+                        "auto" => color = ColorChoiceArg::Auto,
+                        _ => {}
                     }
                 }
                 _ => {}
@@ -257,6 +310,48 @@ impl IntegratedSearcher {
     }
 }
 
+/// a file that could not be searched, for the summary at the end
+struct FileError {
+    path: PathBuf,
+    /// the adapter that failed, if known
+    adapter: Option<String>,
+    message: String,
+}
+
+impl FileError {
+    fn new(path: &Path, e: &anyhow::Error) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            adapter: AdapterFailed::find(e).map(|a| a.adapter.clone()),
+            message: format!("{e:#}"),
+        }
+    }
+}
+
+impl std::fmt::Display for FileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.path.display())?;
+        if let Some(adapter) = &self.adapter {
+            write!(f, " ({adapter})")?;
+        }
+        write!(f, ": {}", self.message)
+    }
+}
+
+fn print_error_summary(errors: &[FileError]) {
+    if errors.is_empty() {
+        return;
+    }
+    eprintln!(
+        "rga: {} file{} could not be searched:",
+        errors.len(),
+        if errors.len() == 1 { "" } else { "s" }
+    );
+    for e in errors {
+        eprintln!("  {e}");
+    }
+}
+
 enum ColorChoiceArg {
     Always,
     Never,
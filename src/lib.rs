@@ -2,14 +2,23 @@
 
 pub mod adapted_iter;
 pub mod adapters;
+mod cache_encryption;
 mod caching_writer;
 pub mod config;
+pub mod diagnostics;
+pub mod doctor;
 pub mod expand;
+pub mod explain;
+pub mod extract;
 pub mod integrated_search;
+pub mod limits;
 pub mod matching;
 pub mod preproc;
 pub mod preproc_cache;
+mod process_group;
+pub mod profile;
 pub mod recurse;
+pub mod sandbox;
 #[cfg(test)]
 pub mod test_utils;
 use anyhow::Context;
@@ -65,7 +74,11 @@ pub fn print_bytes(bytes: impl Into<f64>) -> String {
 }
 
 pub fn to_io_err(e: anyhow::Error) -> std::io::Error {
-    std::io::Error::other(e)
+    // io errors without context are passed on as they are, so their inner error can still be downcast
+    if !e.chain().next().is_some_and(|e| e.is::<std::io::Error>()) {
+        return std::io::Error::other(e);
+    }
+    e.downcast().unwrap_or_else(std::io::Error::other)
 }
 
 #[cfg(test)]
@@ -0,0 +1,364 @@
+/*!
+ * Limits against zip bombs and other files that expand to huge outputs, configured in `LimitsConfig`,
+ * and the `--rga-adapter-timeout` for files that take too long.
+ *
+ * Instead of failing the file, the output ends with a `[rga: limit exceeded: ...]` line,
+ * so everything up to that point is still searched.
+ */
+use crate::adapters::ReadBox;
+use crate::config::LimitsConfig;
+use crate::print_bytes;
+use std::future::Future;
+use std::io::Cursor;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::task::{Context, Poll, ready};
+use tokio::io::{AsyncRead, ReadBuf};
+use tokio::time::{Instant, Sleep};
+
+/// the compression ratio of a file is only checked once it is larger than this,
+/// so small files that compress very well (e.g. empty lines) don't hit the limit
+const MIN_RATIO_CHECK_SIZE: u64 = 1 << 20;
+
+/// what a searched file has used up so far. shared by all files nested within it (see `AdaptInfo::limit_usage`),
+/// a new one is started for every searched file
+#[derive(Default, Clone, Debug)]
+pub struct LimitUsage(Arc<Usage>);
+
+#[derive(Default, Debug)]
+struct Usage {
+    total_size: AtomicU64,
+    archive_members: AtomicU64,
+    exceeded: AtomicBool,
+}
+
+impl LimitUsage {
+    /// a limit was hit, so the output is incomplete (and should not be cached)
+    pub fn exceeded(&self) -> bool {
+        self.0.exceeded.load(Ordering::Relaxed)
+    }
+
+    fn exceed(&self, reason: Option<String>) -> Option<String> {
+        if reason.is_some() {
+            self.0.exceeded.store(true, Ordering::Relaxed);
+        }
+        reason
+    }
+}
+
+impl LimitsConfig {
+    /// count a file within an archive. returns the reason if there are too many
+    pub fn count_member(&self, usage: &LimitUsage) -> Option<String> {
+        let max = self.archive_members.0;
+        let members = usage.0.archive_members.fetch_add(1, Ordering::Relaxed) + 1;
+        let exceeded =
+            (max > 0 && members > max).then(|| format!("more than {max} files in archive"));
+        usage.exceed(exceeded)
+    }
+
+    /// check the sizes of a file within an archive as stored in the archive, before reading it
+    pub fn check_ratio(
+        &self,
+        usage: &LimitUsage,
+        compressed_size: u64,
+        uncompressed_size: u64,
+    ) -> Option<String> {
+        let max = self.compression_ratio.0;
+        usage.exceed(
+            (max > 0
+                && uncompressed_size > MIN_RATIO_CHECK_SIZE
+                && uncompressed_size / compressed_size.max(1) > max)
+                .then(|| format!("compression ratio larger than {max}")),
+        )
+    }
+
+    /// limit the output of an adapter. `member`: the output is a file within an archive, so it counts towards the total size
+    pub fn limit_output(&self, inp: ReadBox, member: bool, usage: &LimitUsage) -> ReadBox {
+        let mut checks: Vec<Check> = vec![];
+        let max = self.output_size.0;
+        if max > 0 {
+            let mut size = 0;
+            checks.push(Box::new(move |n| {
+                size += n;
+                (size > max)
+                    .then(|| format!("adapter output larger than {}", print_bytes(max as f64)))
+            }));
+        }
+        let max = self.total_size.0;
+        if member && max > 0 {
+            let usage = usage.clone();
+            checks.push(Box::new(move |n| {
+                let total = usage.0.total_size.fetch_add(n, Ordering::Relaxed) + n;
+                (total > max).then(|| {
+                    format!(
+                        "files in archive larger than {} in total",
+                        print_bytes(max as f64)
+                    )
+                })
+            }));
+        }
+        LimitedReader::wrap(inp, checks, usage)
+    }
+
+    /// limit the ratio between the size of the decompressed output and `compressed_size`,
+    /// the number of compressed bytes read so far (see `CountingReader`)
+    pub fn limit_ratio(
+        &self,
+        inp: ReadBox,
+        compressed_size: Arc<AtomicU64>,
+        usage: &LimitUsage,
+    ) -> ReadBox {
+        let max = self.compression_ratio.0;
+        if max == 0 {
+            return inp;
+        }
+        let mut size = 0;
+        LimitedReader::wrap(
+            inp,
+            vec![Box::new(move |n| {
+                size += n;
+                let compressed = compressed_size.load(Ordering::Relaxed).max(1);
+                (size > MIN_RATIO_CHECK_SIZE && size / compressed > max)
+                    .then(|| format!("compression ratio larger than {max}"))
+            })],
+            usage,
+        )
+    }
+}
+
+/// called with the number of bytes read, returns the reason if a limit was exceeded
+type Check = Box<dyn FnMut(u64) -> Option<String> + Send>;
+
+struct LimitedReader {
+    /// dropped once a limit is exceeded, so e.g. an adapter process is stopped
+    inner: Option<ReadBox>,
+    checks: Vec<Check>,
+    marker: Option<Cursor<Vec<u8>>>,
+    usage: LimitUsage,
+}
+
+impl LimitedReader {
+    fn wrap(inner: ReadBox, checks: Vec<Check>, usage: &LimitUsage) -> ReadBox {
+        if checks.is_empty() {
+            return inner;
+        }
+        Box::pin(LimitedReader {
+            inner: Some(inner),
+            checks,
+            marker: None,
+            usage: usage.clone(),
+        })
+    }
+}
+
+impl AsyncRead for LimitedReader {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        if let Some(marker) = this.marker.as_mut() {
+            return Pin::new(marker).poll_read(cx, buf);
+        }
+        let Some(inner) = this.inner.as_mut() else {
+            return Poll::Ready(Ok(()));
+        };
+        let before = buf.filled().len();
+        let res = inner.as_mut().poll_read(cx, buf);
+        let n = (buf.filled().len() - before) as u64;
+        let exceeded = this.checks.iter_mut().find_map(|check| check(n));
+        if let Some(reason) = this.usage.exceed(exceeded) {
+            this.inner = None;
+            this.marker = Some(Cursor::new(
+                format!("\n[rga: limit exceeded: {reason}]\n").into_bytes(),
+            ));
+        }
+        res
+    }
+}
+
+/// end the output with `marker` at the `deadline`. the input is dropped then, which stops the adapters
+/// and kills the programs they started
+pub fn limit_time(inp: ReadBox, deadline: Instant, marker: String) -> ReadBox {
+    Box::pin(TimeoutReader {
+        inner: Some(inp),
+        deadline: Box::pin(tokio::time::sleep_until(deadline)),
+        marker: Some(Cursor::new(format!("\n{marker}\n").into_bytes())),
+    })
+}
+
+struct TimeoutReader {
+    inner: Option<ReadBox>,
+    deadline: Pin<Box<Sleep>>,
+    /// None if the input ended in time
+    marker: Option<Cursor<Vec<u8>>>,
+}
+
+impl AsyncRead for TimeoutReader {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        if let Some(inner) = this.inner.as_mut() {
+            if this.deadline.as_mut().poll(cx).is_pending() {
+                let before = buf.filled().len();
+                let res = ready!(inner.as_mut().poll_read(cx, buf));
+                if res.is_ok() && buf.remaining() > 0 && buf.filled().len() == before {
+                    this.inner = None;
+                    this.marker = None;
+                }
+                return Poll::Ready(res);
+            }
+            this.inner = None;
+        }
+        match this.marker.as_mut() {
+            Some(marker) => Pin::new(marker).poll_read(cx, buf),
+            None => Poll::Ready(Ok(())),
+        }
+    }
+}
+
+/// counts the bytes read through it
+pub struct CountingReader {
+    inner: ReadBox,
+    count: Arc<AtomicU64>,
+}
+
+impl CountingReader {
+    pub fn new(inner: ReadBox, count: Arc<AtomicU64>) -> Self {
+        Self { inner, count }
+    }
+}
+
+impl AsyncRead for CountingReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let before = buf.filled().len();
+        let res = self.inner.as_mut().poll_read(cx, buf);
+        self.count
+            .fetch_add((buf.filled().len() - before) as u64, Ordering::Relaxed);
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{MaxArchiveMembers, MaxOutputSize, MaxTotalSize};
+    use anyhow::Result;
+    use pretty_assertions::assert_eq;
+    use std::time::Duration;
+    use tokio::io::AsyncReadExt;
+
+    async fn read(mut inp: ReadBox) -> Result<String> {
+        let mut out = String::new();
+        inp.read_to_string(&mut out).await?;
+        Ok(out)
+    }
+
+    #[tokio::test]
+    async fn output_size() -> Result<()> {
+        let limits = LimitsConfig {
+            output_size: MaxOutputSize(5),
+            ..Default::default()
+        };
+        let usage = LimitUsage::default();
+        let inp = Box::pin(Cursor::new(b"hello".to_vec()));
+        assert_eq!(
+            read(limits.limit_output(inp, false, &usage)).await?,
+            "hello"
+        );
+        assert!(!usage.exceeded());
+        let inp = Box::pin(Cursor::new(b"hello world".to_vec()));
+        let out = read(limits.limit_output(inp, false, &usage)).await?;
+        assert_eq!(
+            out,
+            "hello world\n[rga: limit exceeded: adapter output larger than 5 B]\n"
+        );
+        assert!(usage.exceeded());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn total_size() -> Result<()> {
+        let limits = LimitsConfig {
+            total_size: MaxTotalSize(15),
+            ..Default::default()
+        };
+        let usage = LimitUsage::default();
+        let member = || Box::pin(Cursor::new(b"0123456789".to_vec()));
+        assert_eq!(
+            read(limits.limit_output(member(), true, &usage)).await?,
+            "0123456789"
+        );
+        // not a member, doesn't count
+        assert_eq!(
+            read(limits.limit_output(member(), false, &usage)).await?,
+            "0123456789"
+        );
+        assert!(
+            read(limits.limit_output(member(), true, &usage))
+                .await?
+                .ends_with("[rga: limit exceeded: files in archive larger than 15 B in total]\n")
+        );
+        // a new file starts from zero
+        let usage = LimitUsage::default();
+        assert_eq!(
+            read(limits.limit_output(member(), true, &usage)).await?,
+            "0123456789"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn timeout() -> Result<()> {
+        // never sends anything
+        let (_writer, hanging) = tokio::io::duplex(64);
+        let deadline = Instant::now() + Duration::from_millis(50);
+        let out = read(limit_time(
+            Box::pin(hanging),
+            deadline,
+            "timed out".to_string(),
+        ))
+        .await?;
+        assert_eq!(out, "\ntimed out\n");
+
+        let deadline = Instant::now() + Duration::from_secs(60);
+        let inp = Box::pin(Cursor::new(b"hello".to_vec()));
+        let out = read(limit_time(inp, deadline, "timed out".to_string())).await?;
+        assert_eq!(out, "hello");
+        Ok(())
+    }
+
+    #[test]
+    fn members_and_ratio() {
+        let limits = LimitsConfig {
+            archive_members: MaxArchiveMembers(2),
+            ..Default::default()
+        };
+        let usage = LimitUsage::default();
+        assert_eq!(limits.count_member(&usage), None);
+        assert_eq!(limits.count_member(&usage), None);
+        assert_eq!(
+            limits.count_member(&usage).as_deref(),
+            Some("more than 2 files in archive")
+        );
+        assert!(usage.exceeded());
+
+        let usage = LimitUsage::default();
+        assert_eq!(limits.check_ratio(&usage, 100, 1000), None);
+        assert_eq!(limits.check_ratio(&usage, 0, 0), None);
+        assert!(!usage.exceeded());
+        assert_eq!(
+            limits.check_ratio(&usage, 1000, 10_000_000_000).as_deref(),
+            Some("compression ratio larger than 1000")
+        );
+    }
+}
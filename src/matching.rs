@@ -2,17 +2,22 @@
  * Module for matching adapters to files based on file name or mime type
  */
 use crate::adapters::*;
+use crate::config::DetectionMode;
+use crate::diagnostics;
 
 use anyhow::*;
 
 use regex::{Regex, RegexSet};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
 
 use std::iter::Iterator;
 
 use std::sync::Arc;
 
 // match only based on file path
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema, PartialEq)]
+#[serde(rename_all = "snake_case")]
 pub enum FastFileMatcher {
     // MimeType(Regex),
     /**
@@ -20,8 +25,34 @@ pub enum FastFileMatcher {
      *
      */
     FileExtension(String),
-    // todo: maybe add others, e.g. regex on whole filename or even paths
-    // todo: maybe allow matching a directory (e.g. /var/lib/postgres)
+    /// glob matched against the file name only, e.g. `Dockerfile*` or `*.log.[0-9]`
+    FileNameGlob(String),
+    /// glob matched against the whole path, e.g. `**/mail/cur/*`. `*` does not match `/`, `**` matches any number of directories.
+    ///
+    /// For files within archives, the path is the path within the archive.
+    PathGlob(String),
+    /// regex matched against the whole path (unanchored)
+    PathRegex(String),
+    /// matches all files within the given directory, e.g. `/var/lib/postgres`.
+    /// Relative paths are not made absolute before matching.
+    PathPrefix(String),
+}
+
+impl FastFileMatcher {
+    /// the regex for this matcher, and whether it is matched against the whole path instead of only the file name
+    fn to_regex(&self) -> (String, bool) {
+        use FastFileMatcher::*;
+        match self {
+            FileExtension(ext) => (extension_to_regex(ext).as_str().to_string(), false),
+            FileNameGlob(glob) => (glob_to_regex(glob), false),
+            PathGlob(glob) => (glob_to_regex(glob), true),
+            PathRegex(re) => (re.clone(), true),
+            PathPrefix(prefix) => (
+                format!("^{}(?:/|$)", regex::escape(prefix.trim_end_matches('/'))),
+                true,
+            ),
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -32,6 +63,47 @@ pub enum FileMatcher {
     /// match by exact mime type extracted using tree_magic
     /// TODO: allow match ignoring suffix etc?
     MimeType(String),
+    /// match by the bytes at the given offset at the start of the file, e.g. `PK\x03\x04` at 0 for zip.
+    /// Used with `--rga-accurate`, and with hybrid detection if no fast matcher matches the file.
+    /// The bytes must be within the first `MAGIC_SNIFF_LEN` bytes.
+    MagicBytes { offset: usize, bytes: Vec<u8> },
+}
+
+impl FileMatcher {
+    pub fn magic(offset: usize, bytes: &[u8]) -> Self {
+        Self::MagicBytes {
+            offset,
+            bytes: bytes.to_vec(),
+        }
+    }
+}
+
+/// how many bytes at the start of a file are checked by `FileMatcher::MagicBytes`
+pub const MAGIC_SNIFF_LEN: usize = 8192;
+
+/// parse a string with `\xNN` escapes for non-printable bytes, e.g. `\x1f\x8b`.
+/// Invalid escapes are kept as they are
+pub fn unescape_bytes(s: &str) -> Vec<u8> {
+    let mut out = Vec::with_capacity(s.len());
+    let mut rest = s.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        let escaped = if b == b'\\' {
+            tail.strip_prefix(b"x")
+                .and_then(|hex| hex.get(..2))
+                .and_then(|hex| std::str::from_utf8(hex).ok())
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+        } else {
+            None
+        };
+        if let Some(byte) = escaped {
+            out.push(byte);
+            rest = &tail[3..];
+            continue;
+        }
+        out.push(b);
+        rest = tail;
+    }
+    out
 }
 
 impl From<FastFileMatcher> for FileMatcher {
@@ -40,12 +112,17 @@ impl From<FastFileMatcher> for FileMatcher {
     }
 }
 
+#[derive(Clone)]
 pub struct FileMeta {
     // filename is not actually a utf8 string, but since we can't do regex on OsStr and can't get a &[u8] from OsStr either,
     // and since we probably only want to do only matching on ascii stuff anyways, this is the filename as a string with non-valid bytes removed
     pub lossy_filename: String,
+    // same for the whole path, with `/` as the separator
+    pub lossy_path: String,
     // only given when slow matching is enabled
     pub mimetype: Option<&'static str>,
+    // the first bytes of the file (up to MAGIC_SNIFF_LEN), only given when magic bytes should be checked
+    pub head: Option<Vec<u8>>,
 }
 
 pub fn extension_to_regex(extension: &str) -> Regex {
@@ -53,38 +130,126 @@ pub fn extension_to_regex(extension: &str) -> Regex {
         .expect("we know this regex compiles")
 }
 
+/// convert a glob to a regex matching the whole string.
+/// `*` and `?` do not match `/`, `**` matches anything including `/`, `[...]`, `[!...]` and `{a,b}` work as usual
+pub fn glob_to_regex(glob: &str) -> String {
+    let mut re = String::from("^");
+    let mut chars = glob.chars().peekable();
+    let mut in_alternation = false;
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                if chars.peek() == Some(&'/') {
+                    chars.next();
+                    re.push_str("(?:.*/)?");
+                } else {
+                    re.push_str(".*");
+                }
+            }
+            '*' => re.push_str("[^/]*"),
+            '?' => re.push_str("[^/]"),
+            '[' => {
+                re.push('[');
+                if chars.peek() == Some(&'!') {
+                    chars.next();
+                    re.push('^');
+                }
+                for c in chars.by_ref() {
+                    if c == ']' {
+                        break;
+                    }
+                    if matches!(c, '\\' | '[' | '&' | '~') {
+                        re.push('\\');
+                    }
+                    re.push(c);
+                }
+                re.push(']');
+            }
+            '{' if !in_alternation => {
+                in_alternation = true;
+                re.push_str("(?:");
+            }
+            '}' if in_alternation => {
+                in_alternation = false;
+                re.push(')');
+            }
+            ',' if in_alternation => re.push('|'),
+            c => re.push_str(&regex::escape(c.encode_utf8(&mut [0; 4]))),
+        }
+    }
+    re.push('$');
+    re
+}
+
+/// an adapter that matched a file and the matcher that matched
+pub type AdapterCandidate = (Arc<dyn FileAdapter>, FileMatcher);
+
 #[allow(clippy::type_complexity)]
 pub fn adapter_matcher(
     adapters: &[Arc<dyn FileAdapter>],
-    slow: bool,
-) -> Result<impl Fn(FileMeta) -> Option<(Arc<dyn FileAdapter>, FileMatcher)> + use<>> {
+    mode: DetectionMode,
+) -> Result<impl Fn(FileMeta) -> Option<AdapterCandidate> + use<>> {
+    let candidates = adapter_candidates(adapters, mode)?;
+    Ok(move |meta: FileMeta| candidates(meta).into_iter().next())
+}
+
+/// like adapter_matcher, but returns all matching adapters in priority order (each adapter once),
+/// so the next ones can be used as a fallback if the first one fails.
+///
+/// Magic bytes are checked if `FileMeta::head` is given. With hybrid detection, the caller should only do that if there are no other matches.
+pub fn adapter_candidates(
+    adapters: &[Arc<dyn FileAdapter>],
+    mode: DetectionMode,
+) -> Result<impl Fn(FileMeta) -> Vec<AdapterCandidate> + use<>> {
+    let slow = mode == DetectionMode::Accurate;
     // need order later
     let adapter_names: Vec<String> = adapters.iter().map(|e| e.metadata().name.clone()).collect();
     let mut fname_regexes = vec![];
+    let mut path_regexes = vec![];
     let mut mime_regexes = vec![];
+    let mut magic_matchers = vec![];
     for adapter in adapters.iter() {
         let metadata = adapter.metadata();
         use FileMatcher::*;
         for matcher in metadata.get_matchers(slow) {
             match matcher.as_ref() {
+                MagicBytes { .. } => {
+                    if !slow {
+                        // added below for hybrid detection
+                        continue;
+                    }
+                    magic_matchers.push((matcher.as_ref().clone(), adapter.clone()))
+                }
                 MimeType(re) => {
                     mime_regexes.push((re.clone(), adapter.clone(), MimeType(re.clone())))
                 }
-                Fast(FastFileMatcher::FileExtension(re)) => fname_regexes.push((
-                    extension_to_regex(re),
-                    adapter.clone(),
-                    Fast(FastFileMatcher::FileExtension(re.clone())),
-                )),
+                Fast(fast) => {
+                    let (re, on_path) = fast.to_regex();
+                    let regexes = if on_path {
+                        &mut path_regexes
+                    } else {
+                        &mut fname_regexes
+                    };
+                    regexes.push((re, adapter.clone(), Fast(fast.clone())));
+                }
             };
         }
+        if mode == DetectionMode::Hybrid {
+            for matcher in metadata.slow_matchers.iter().flatten() {
+                if let MagicBytes { .. } = matcher {
+                    magic_matchers.push((matcher.clone(), adapter.clone()));
+                }
+            }
+        }
     }
     let fname_regex_set = RegexSet::new(fname_regexes.iter().map(|p| p.0.as_str()))?;
+    let path_regex_set =
+        RegexSet::new(path_regexes.iter().map(|p| p.0.as_str())).context("invalid path matcher")?;
     let mime_regex_set = RegexSet::new(mime_regexes.iter().map(|p| p.0.as_str()))?;
     Ok(move |meta: FileMeta| {
-        let fname_matches: Vec<_> = fname_regex_set
-            .matches(&meta.lossy_filename)
-            .into_iter()
-            .collect();
+        let fname_matches = fname_regex_set.matches(&meta.lossy_filename);
+        let path_matches = path_regex_set.matches(&meta.lossy_path);
         let mime_matches: Vec<_> = if slow {
             mime_regex_set
                 .matches(meta.mimetype.expect("No mimetype?"))
@@ -93,43 +258,160 @@ pub fn adapter_matcher(
         } else {
             vec![]
         };
-        if fname_matches.len() + mime_matches.len() > 1 {
-            // get first according to original priority list...
-            // todo: kinda ugly
-            let fa = fname_matches
+        let magic_matches = magic_matchers.iter().filter(|(matcher, _)| {
+            let (Some(head), FileMatcher::MagicBytes { offset, bytes }) = (&meta.head, matcher)
+            else {
+                return false;
+            };
+            head.get(*offset..offset + bytes.len()) == Some(bytes.as_slice())
+        });
+        // mime and magic matches first, so they win over extension matches of the same adapter
+        let mut v: Vec<AdapterCandidate> = mime_matches
+            .iter()
+            .map(|e| (mime_regexes[*e].1.clone(), mime_regexes[*e].2.clone()))
+            .chain(magic_matches.map(|(m, a)| (a.clone(), m.clone())))
+            .chain(
+                fname_matches
+                    .iter()
+                    .map(|e| (fname_regexes[e].1.clone(), fname_regexes[e].2.clone())),
+            )
+            .chain(
+                path_matches
+                    .iter()
+                    .map(|e| (path_regexes[e].1.clone(), path_regexes[e].2.clone())),
+            )
+            .collect();
+        // get in order according to original priority list. stable, so the mime match of an adapter stays first
+        v.sort_by_key(|e| {
+            adapter_names
                 .iter()
-                .map(|e| (fname_regexes[*e].1.clone(), fname_regexes[*e].2.clone()));
-            let fb = mime_matches
+                .position(|r| r == &e.0.metadata().name)
+                .expect("impossib7")
+        });
+        v.dedup_by(|a, b| a.0.metadata().name == b.0.metadata().name);
+        if v.len() > 1 {
+            let names = v
                 .iter()
-                .map(|e| (mime_regexes[*e].1.clone(), mime_regexes[*e].2.clone()));
-            let mut v = vec![];
-            v.extend(fa);
-            v.extend(fb);
-            v.sort_by_key(|e| {
-                adapter_names
-                    .iter()
-                    .position(|r| r == &e.0.metadata().name)
-                    .expect("impossib7")
-            });
-            eprintln!(
-                "Warning: found multiple adapters for {}:",
-                meta.lossy_filename
+                .map(|e| e.0.metadata().name.as_str())
+                .collect::<Vec<_>>()
+                .join(", ");
+            // once per set of adapters, not for every file
+            diagnostics::warn_once(
+                &format!("multiple adapters: {names}"),
+                format_args!(
+                    "found multiple adapters for {}, trying in order: {names}",
+                    meta.lossy_filename
+                ),
             );
-            for mmatch in v.iter() {
-                eprintln!(" - {}", mmatch.0.metadata().name);
-            }
-            return Some(v[0].clone());
-        }
-        if mime_matches.is_empty() {
-            if fname_matches.is_empty() {
-                None
-            } else {
-                let (_, adapter, matcher) = &fname_regexes[fname_matches[0]];
-                Some((adapter.clone(), matcher.clone()))
-            }
-        } else {
-            let (_, adapter, matcher) = &mime_regexes[mime_matches[0]];
-            Some((adapter.clone(), matcher.clone()))
         }
+        v
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::adapters::custom::CustomAdapterConfig;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn globs() {
+        let matches = |glob: &str, s: &str| Regex::new(&glob_to_regex(glob)).unwrap().is_match(s);
+        assert!(matches("Dockerfile*", "Dockerfile.dev"));
+        assert!(!matches("Dockerfile*", "foo/Dockerfile"));
+        assert!(matches("*.log.[0-9]", "syslog.log.1"));
+        assert!(!matches("*.log.[!0-9]", "syslog.log.1"));
+        assert!(matches(
+            "**/mail/cur/*",
+            "mail/cur/1700000000.M1P2.host:2,S"
+        ));
+        assert!(matches("**/mail/cur/*", "/home/user/mail/cur/1"));
+        assert!(!matches("**/mail/cur/*", "/home/user/mail/cur/sub/1"));
+        assert!(matches("*.{tgz,tbz}", "a.tbz"));
+        assert!(!matches("a?c", "a/c"));
+    }
+
+    #[test]
+    fn path_matchers() -> Result<()> {
+        let adapter = |name: &str, matcher: FastFileMatcher| {
+            let a: Arc<dyn FileAdapter> = Arc::new(
+                CustomAdapterConfig {
+                    name: name.to_string(),
+                    matchers: Some(vec![matcher]),
+                    ..Default::default()
+                }
+                .to_adapter(),
+            );
+            a
+        };
+        let adapters = vec![
+            adapter(
+                "docker",
+                FastFileMatcher::FileNameGlob("Dockerfile*".to_string()),
+            ),
+            adapter("maildir", FastFileMatcher::PathGlob("**/cur/*".to_string())),
+            adapter(
+                "postgres",
+                FastFileMatcher::PathPrefix("/var/lib/postgres/".to_string()),
+            ),
+            adapter(
+                "rotated",
+                FastFileMatcher::PathRegex(r"\.log\.\d+$".to_string()),
+            ),
+        ];
+        let candidates = adapter_candidates(&adapters, DetectionMode::Fast)?;
+        let names = |path: &str| {
+            let path = std::path::Path::new(path);
+            candidates(FileMeta {
+                lossy_filename: path.file_name().unwrap().to_string_lossy().to_string(),
+                lossy_path: path.to_string_lossy().to_string(),
+                mimetype: None,
+                head: None,
+            })
+            .into_iter()
+            .map(|(a, _)| a.metadata().name.clone())
+            .collect::<Vec<_>>()
+        };
+        assert_eq!(names("foo/Dockerfile"), vec!["docker"]);
+        assert_eq!(names("Maildir/cur/123:2,S"), vec!["maildir"]);
+        assert_eq!(names("/var/lib/postgres/base/1"), vec!["postgres"]);
+        assert_eq!(names("/var/lib/postgresql/base/1"), Vec::<String>::new());
+        assert_eq!(names("/var/log/syslog.log.2"), vec!["rotated"]);
+        Ok(())
+    }
+
+    #[test]
+    fn magic_bytes() -> Result<()> {
+        assert_eq!(unescape_bytes("PK\\x03\\x04"), b"PK\x03\x04");
+        assert_eq!(unescape_bytes("\\x1F\\xzz\\"), b"\x1f\\xzz\\");
+
+        let adapters = get_adapters_filtered::<String>(None, &[], &[])?;
+        let meta = |name: &str, head: Option<&[u8]>| FileMeta {
+            lossy_filename: name.to_string(),
+            lossy_path: name.to_string(),
+            mimetype: None,
+            head: head.map(|h| h.to_vec()),
+        };
+        let names = |mode: DetectionMode, meta: FileMeta| -> Result<Vec<String>> {
+            Ok(adapter_candidates(&adapters, mode)?(meta)
+                .into_iter()
+                .map(|(a, _)| a.metadata().name.clone())
+                .collect())
+        };
+        let gz = Some(&b"\x1f\x8b\x08\x00"[..]);
+        assert_eq!(
+            names(DetectionMode::Hybrid, meta("download", gz))?,
+            vec!["decompress"]
+        );
+        assert!(names(DetectionMode::Fast, meta("download", gz))?.is_empty());
+        assert!(names(DetectionMode::Hybrid, meta("download", Some(b"hello")))?.is_empty());
+
+        let mut tar = vec![0u8; 512];
+        tar[257..262].copy_from_slice(b"ustar");
+        assert_eq!(
+            names(DetectionMode::Hybrid, meta("backup.bak", Some(&tar)))?,
+            vec!["tar"]
+        );
+        Ok(())
+    }
+}
@@ -1,107 +1,135 @@
-use crate::adapted_iter::AdaptedFilesIterBox;
+use crate::adapted_iter::{AdaptedFilesIterBox, marker_file};
 use crate::adapters::*;
 use crate::caching_writer::async_read_and_write_to_cache;
-use crate::config::RgaConfig;
+use crate::config::{DetectionMode, RgaConfig};
+use crate::diagnostics;
+use crate::limits;
 use crate::matching::*;
 use crate::preproc_cache::CacheKey;
+use crate::profile;
 use crate::recurse::concat_read_streams;
 use crate::{
-    preproc_cache::{PreprocCache, open_cache_db},
+    preproc_cache::{CacheLookup, PreprocCache, open_cache_db},
     print_bytes,
 };
-use anyhow::*;
-use async_compression::tokio::bufread::ZstdDecoder;
+use anyhow::{Context, Result, format_err};
 use async_stream::stream;
 // use futures::future::{BoxFuture, FutureExt};
 use log::*;
 use postproc::PostprocPrefix;
 use std::future::Future;
 use std::io::Cursor;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::pin::Pin;
 use std::sync::Arc;
+use std::task::{Poll, ready};
 use tokio::io::AsyncBufReadExt;
+use tokio::io::AsyncReadExt;
 use tokio::io::BufReader;
-use tokio::io::{AsyncBufRead, AsyncReadExt};
+use tokio::io::{AsyncRead, ReadBuf};
+use tokio_stream::{Stream, StreamExt};
 
 pub type ActiveAdapters = Vec<Arc<dyn FileAdapter>>;
 
-async fn choose_adapter(
+/// read the first `len` bytes of `inp` (fewer at EOF) without consuming them: `inp` is replaced by a reader that starts with them.
+///
+/// A single `fill_buf` is not enough, it returns whatever the first read returned, e.g. one block of a decompressor
+pub(crate) async fn peek(inp: &mut ReadBox, len: usize) -> Result<Vec<u8>> {
+    let mut head = Vec::with_capacity(len);
+    (&mut *inp).take(len as u64).read_to_end(&mut head).await?;
+    let rest = std::mem::replace(inp, Box::pin(tokio::io::empty()));
+    *inp = Box::pin(Cursor::new(head.clone()).chain(rest));
+    Ok(head)
+}
+
+/// all adapters matching the file, in priority order
+pub(crate) async fn choose_adapters(
     config: &RgaConfig,
     filepath_hint: &Path,
     archive_recursion_depth: i32,
-    inp: &mut (impl AsyncBufRead + Unpin),
-) -> Result<Option<(Arc<dyn FileAdapter>, FileMatcher, ActiveAdapters)>> {
-    let active_adapters = get_adapters_filtered(config.custom_adapters.clone(), &config.adapters)?;
-    let adapters = adapter_matcher(&active_adapters, config.accurate)?;
+    inp: &mut ReadBox,
+) -> Result<(Vec<AdapterCandidate>, ActiveAdapters)> {
+    let active_adapters = get_adapters_filtered(
+        config.custom_adapters.clone(),
+        &config.adapter_overrides,
+        &config.adapters,
+    )?;
+    let mode = config.detection_mode();
+    let adapters = adapter_candidates(&active_adapters, mode)?;
     let filename = filepath_hint
         .file_name()
         .ok_or_else(|| format_err!("Empty filename"))?;
     debug!("Archive recursion depth: {}", archive_recursion_depth);
 
-    let mimetype = if config.accurate {
-        let buf = inp.fill_buf().await?; // fill but do not consume!
-        if buf.starts_with(b"From \x0d") || buf.starts_with(b"From -") {
+    let mut meta = FileMeta {
+        mimetype: None,
+        lossy_filename: filename.to_string_lossy().to_string(),
+        lossy_path: filepath_hint
+            .to_string_lossy()
+            .replace(std::path::MAIN_SEPARATOR, "/"),
+        head: None,
+    };
+    if mode == DetectionMode::Accurate {
+        let buf = peek(inp, MAGIC_SNIFF_LEN).await?;
+        meta.mimetype = if buf.starts_with(b"From \x0d") || buf.starts_with(b"From -") {
             Some("application/mbox")
         } else {
-            let mimetype = tree_magic::from_u8(buf);
+            let mimetype = tree_magic::from_u8(&buf);
             debug!("mimetype: {:?}", mimetype);
             Some(mimetype)
-        }
-    } else {
-        None
-    };
-    let adapter = adapters(FileMeta {
-        mimetype,
-        lossy_filename: filename.to_string_lossy().to_string(),
-    });
-    Ok(adapter.map(|e| (e.0, e.1, active_adapters)))
+        };
+        meta.head = Some(buf);
+    }
+    let mut candidates = adapters(meta.clone());
+    if candidates.is_empty() && mode == DetectionMode::Hybrid {
+        // unknown file name, try magic bytes
+        meta.head = Some(peek(inp, MAGIC_SNIFF_LEN).await?);
+        candidates = adapters(meta);
+    }
+    Ok((candidates, active_adapters))
 }
 
 enum Ret {
-    Recurse(AdaptInfo, Arc<dyn FileAdapter>, FileMatcher, ActiveAdapters),
+    Recurse(AdaptInfo, Vec<AdapterCandidate>, ActiveAdapters),
     Passthrough(AdaptInfo),
 }
 async fn buf_choose_adapter(ai: AdaptInfo) -> Result<Ret> {
-    let mut inp = BufReader::with_capacity(1 << 16, ai.inp);
-    let adapter = choose_adapter(
+    let mut inp: ReadBox = Box::pin(BufReader::with_capacity(1 << 16, ai.inp));
+    let (candidates, active_adapters) = choose_adapters(
         &ai.config,
         &ai.filepath_hint,
         ai.archive_recursion_depth,
         &mut inp,
     )
     .await?;
-    let ai = AdaptInfo {
-        inp: Box::pin(inp),
-        ..ai
-    };
-    let (a, b, c) = match adapter {
-        Some(x) => x,
-        None => {
-            // allow passthrough if the file is in an archive or accurate matching is enabled
-            // otherwise it should have been filtered out by rg pre-glob since rg can handle those better than us
-            let allow_cat = !ai.is_real_file || ai.config.accurate;
-            if allow_cat {
-                if ai.postprocess {
-                    (
+    let ai = AdaptInfo { inp, ..ai };
+    if candidates.is_empty() {
+        // allow passthrough if the file is in an archive or the magic bytes were checked
+        // otherwise it should have been filtered out by rg pre-glob since rg can handle those better than us
+        let allow_cat = !ai.is_real_file || ai.config.detection_mode() != DetectionMode::Fast;
+        if allow_cat {
+            if ai.postprocess {
+                return Ok(Ret::Recurse(
+                    ai,
+                    vec![(
                         Arc::new(PostprocPrefix {}) as Arc<dyn FileAdapter>,
                         FileMatcher::Fast(FastFileMatcher::FileExtension("default".to_string())),
-                        Vec::new(),
-                    )
-                } else {
-                    return Ok(Ret::Passthrough(ai));
-                }
-            } else {
-                return Err(format_err!(
-                    "No adapter found for file {:?}, passthrough disabled.",
-                    ai.filepath_hint
-                        .file_name()
-                        .ok_or_else(|| format_err!("Empty filename"))?
+                    )],
+                    Vec::new(),
                 ));
+            } else {
+                return Ok(Ret::Passthrough(ai));
             }
+        } else {
+            return Err(format_err!(
+                "No adapter found for file {:?}, passthrough disabled.",
+                ai.filepath_hint
+                    .file_name()
+                    .ok_or_else(|| format_err!("Empty filename"))?
+            ));
         }
-    };
-    Ok(Ret::Recurse(ai, a, b, c))
+    }
+    Ok(Ret::Recurse(ai, candidates, active_adapters))
 }
 
 /**
@@ -112,86 +140,533 @@ async fn buf_choose_adapter(ai: AdaptInfo) -> Result<Ret> {
  */
 pub async fn rga_preproc(ai: AdaptInfo) -> Result<ReadBox> {
     debug!("path (hint) to preprocess: {:?}", ai.filepath_hint);
+    let Some(timeout) = ai.config.adapter_timeout.duration() else {
+        return preproc_chain(ai).await;
+    };
+    // added after the postprocessing, so it needs the prefix here
+    let marker = format!(
+        "{}[rga: adapter timed out after {}s]",
+        ai.line_prefix,
+        timeout.as_secs()
+    );
+    let deadline = tokio::time::Instant::now() + timeout;
+    match tokio::time::timeout_at(deadline, preproc_chain(ai)).await {
+        Ok(out) => Ok(limits::limit_time(out?, deadline, marker)),
+        Err(_) => Ok(Box::pin(Cursor::new(format!("{marker}\n").into_bytes()))),
+    }
+}
+
+/**
+ * like `rga_preproc`, but returns every file that is not adapted any further separately
+ * instead of concatenating their text. Not cached.
+ */
+pub async fn preproc_entries(ai: AdaptInfo) -> Result<AdaptedEntries> {
+    let timeout = ai.config.adapter_timeout.duration();
+    let timed_out = format!("{}: adapter timed out", ai.filepath_hint.to_string_lossy());
+    let marker = format!(
+        "{}[rga: adapter timed out after {}s]",
+        ai.line_prefix,
+        timeout.map_or(0, |t| t.as_secs())
+    );
+    let entries = async {
+        Ok(match buf_choose_adapter(ai).await? {
+            Ret::Recurse(ai, candidates, _active_adapters) => {
+                adapt_with_fallback(ai, candidates, vec![]).await?.1
+            }
+            Ret::Passthrough(ai) => {
+                Box::pin(tokio_stream::once(Ok(AdaptedEntry { ai, steps: vec![] })))
+                    as AdaptedEntries
+            }
+        })
+    };
+    let Some(timeout) = timeout else {
+        return entries.await;
+    };
+    let deadline = tokio::time::Instant::now() + timeout;
+    let mut entries = match tokio::time::timeout_at(deadline, entries).await {
+        Ok(entries) => entries?,
+        Err(_) => return Err(format_err!("{timed_out}")),
+    };
+    Ok(Box::pin(stream! {
+        loop {
+            match tokio::time::timeout_at(deadline, entries.next()).await {
+                Ok(Some(entry)) => {
+                    yield entry.map(|mut entry| {
+                        entry.ai.inp = limits::limit_time(entry.ai.inp, deadline, marker.clone());
+                        entry
+                    });
+                }
+                Ok(None) => break,
+                Err(_) => {
+                    yield Err(format_err!("{timed_out}"));
+                    break;
+                }
+            }
+        }
+    }))
+}
 
+/// choose the adapters for the file and run them, with the cache if enabled
+async fn preproc_chain(ai: AdaptInfo) -> Result<ReadBox> {
     // todo: figure out when using a bufreader is a good idea and when it is not
     // seems to be good for File::open() reads, but not sure about within archives (tar, zip)
-    let (ai, adapter, detection_reason, active_adapters) = match buf_choose_adapter(ai).await? {
-        Ret::Recurse(ai, a, b, c) => (ai, a, b, c),
+    let (ai, candidates, active_adapters) = match buf_choose_adapter(ai).await? {
+        Ret::Recurse(ai, a, b) => (ai, a, b),
         Ret::Passthrough(ai) => {
             return Ok(ai.inp);
         }
     };
     let path_hint_copy = ai.filepath_hint.clone();
-    adapt_caching(ai, adapter, detection_reason, active_adapters)
+    adapt_caching(ai, candidates, active_adapters)
         .await
         .with_context(|| format!("run_adapter({})", &path_hint_copy.to_string_lossy()))
 }
 
 async fn adapt_caching(
     ai: AdaptInfo,
-    adapter: Arc<dyn FileAdapter>,
-    detection_reason: FileMatcher,
+    candidates: Vec<AdapterCandidate>,
     active_adapters: ActiveAdapters,
 ) -> Result<ReadBox> {
-    let meta = adapter.metadata();
-    debug!(
-        "Chose adapter '{}' because of matcher {:?}",
-        &meta.name, &detection_reason
-    );
-    eprintln!(
-        "{} adapter: {}",
-        ai.filepath_hint.to_string_lossy(),
-        &meta.name
-    );
     let cache_compression_level = ai.config.cache.compression_level;
     let cache_max_blob_len = ai.config.cache.max_blob_len;
 
-    let cache = if ai.is_real_file && !ai.config.cache.disabled {
-        Some(open_cache_db(Path::new(&ai.config.cache.path.0)).await?)
+    let mut cache = if ai.is_real_file && !ai.config.cache.disabled {
+        Some(open_cache_db(&ai.config.cache).await?)
     } else {
         None
     };
 
-    let mut cache = cache.context("No cache?")?;
+    if let Some(cache) = cache.as_mut() {
+        // the output of a fallback adapter is cached under its own key, so check all of them in order
+        for (adapter, _) in &candidates {
+            let cache_key = CacheKey::new(
+                ai.postprocess,
+                &ai.filepath_hint,
+                adapter.as_ref(),
+                &active_adapters,
+            )?;
+            let span = profile::cache_span("get", &ai.filepath_hint);
+            let cached = cache.get(&cache_key).await.context("cache.get")?;
+            let cached = match cached {
+                CacheLookup::Hit(cached) => cached,
+                CacheLookup::Miss => continue,
+                CacheLookup::Corrupt => {
+                    warn!(
+                        "removing corrupt cache entry of {} for {}",
+                        adapter.metadata().name,
+                        ai.filepath_hint.to_string_lossy()
+                    );
+                    cache.delete(&cache_key).await.context("cache.delete")?;
+                    continue;
+                }
+            };
+            span.add_out(cached.zstd.len());
+            drop(span);
+            // the checksum of the stored blob was already checked by get(). decode it once before it is used,
+            // so an entry that still doesn't decode runs the adapter instead of failing halfway through the search
+            let verified =
+                tokio::task::spawn_blocking(move || cached.verify().map(|()| cached)).await?;
+            match verified.and_then(|cached| cached.into_reader()) {
+                Ok(decoded) => {
+                    debug!("cache HIT for adapter {}", adapter.metadata().name);
+                    diagnostics::cache_hit();
+                    return Ok(decoded);
+                }
+                Err(e) => {
+                    warn!(
+                        "could not decode cached output of {} for {}, running adapter again: {:#}",
+                        adapter.metadata().name,
+                        ai.filepath_hint.to_string_lossy(),
+                        e
+                    );
+                    cache.delete(&cache_key).await.context("cache.delete")?;
+                }
+            }
+        }
+        debug!("cache MISS, running adapter with caching...");
+    }
+    let filepath_hint = ai.filepath_hint.clone();
+    let postprocess = ai.postprocess;
+    let limit_usage = ai.limit_usage.clone();
+    let (adapter, entries) = adapt_with_fallback(ai, candidates, vec![]).await?;
+    let inp = concat_read_streams(Box::pin(entries.map(|entry| entry.map(|e| e.ai))));
+    let Some(mut cache) = cache else {
+        return Ok(inp);
+    };
+    let meta = adapter.metadata();
+    // keyed by the adapter that actually produced the output
     let cache_key = CacheKey::new(
-        ai.postprocess,
-        &ai.filepath_hint,
+        postprocess,
+        &filepath_hint,
         adapter.as_ref(),
         &active_adapters,
     )?;
-    // let dbg_ctx = format!("adapter {}", &adapter.metadata().name);
-    let cached = cache.get(&cache_key).await.context("cache.get")?;
-    match cached {
-        Some(cached) => Ok(Box::pin(ZstdDecoder::new(Cursor::new(cached)))),
-        None => {
-            debug!("cache MISS, running adapter with caching...");
-            let inp = loop_adapt(adapter.as_ref(), detection_reason, ai).await?;
-            let inp = concat_read_streams(inp);
-            let inp = async_read_and_write_to_cache(
-                inp,
-                cache_max_blob_len.0,
-                cache_compression_level.0,
-                Box::new(move |(uncompressed_size, compressed)| {
-                    Box::pin(async move {
-                        debug!(
-                            "uncompressed output: {}",
-                            print_bytes(uncompressed_size as f64)
-                        );
-                        if let Some(cached) = compressed {
-                            debug!("compressed output: {}", print_bytes(cached.len() as f64));
-                            cache
-                                .set(&cache_key, cached)
-                                .await
-                                .context("writing to cache")?
-                        }
-                        Ok(())
-                    })
-                }),
-            )?;
+    let dictionary = cache
+        .dictionary_for(&meta.name)
+        .await
+        .context("cache.dictionary_for")?;
+    let dictionary_id = dictionary.as_ref().map(|d| d.id);
+    let inp = async_read_and_write_to_cache(
+        inp,
+        cache_max_blob_len.0,
+        cache_compression_level.0,
+        dictionary,
+        Box::new(move |(uncompressed_size, compressed)| {
+            Box::pin(async move {
+                debug!(
+                    "uncompressed output: {}",
+                    print_bytes(uncompressed_size as f64)
+                );
+                if limit_usage.exceeded() {
+                    // the output was cut off, and could be complete with other limits
+                    debug!("limit exceeded, not caching the output");
+                } else if let Some(cached) = compressed {
+                    debug!("compressed output: {}", print_bytes(cached.len() as f64));
+                    let span = profile::cache_span("set", &filepath_hint);
+                    span.add_in(cached.len());
+                    cache
+                        .set(&cache_key, cached, dictionary_id)
+                        .await
+                        .context("writing to cache")?
+                }
+                Ok(())
+            })
+        }),
+    )?;
+
+    Ok(Box::pin(inp))
+}
+
+/// context of the error of a failed adapter, so the adapter can be found with `AdapterFailed::find`
+#[derive(Debug, Clone)]
+pub struct AdapterFailed {
+    pub adapter: String,
+    pub path: PathBuf,
+}
+
+impl AdapterFailed {
+    /// the failed adapter of an error of `rga_preproc` or of reading its output
+    pub fn find(e: &anyhow::Error) -> Option<&AdapterFailed> {
+        e.downcast_ref::<AdapterFailed>().or_else(|| {
+            e.chain().find_map(|e| {
+                let output = e.downcast_ref::<std::io::Error>()?.get_ref()?;
+                output
+                    .downcast_ref::<AdapterOutputFailed>()
+                    .map(|o| &o.failed)
+            })
+        })
+    }
+}
+
+/// an error while reading the output of an adapter. io errors can't have anyhow context, so this wraps the original error
+#[derive(Debug)]
+struct AdapterOutputFailed {
+    failed: AdapterFailed,
+    source: std::io::Error,
+}
+
+impl std::fmt::Display for AdapterOutputFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.failed.fmt(f)
+    }
+}
+
+impl std::error::Error for AdapterOutputFailed {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+/// the output of an adapter, with `AdapterFailed` added to read errors
+struct AdapterOutput {
+    inner: ReadBox,
+    failed: AdapterFailed,
+}
+
+impl AsyncRead for AdapterOutput {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let res = ready!(self.inner.as_mut().poll_read(cx, buf));
+        Poll::Ready(res.map_err(|source| {
+            std::io::Error::new(
+                source.kind(),
+                AdapterOutputFailed {
+                    failed: self.failed.clone(),
+                    source,
+                },
+            )
+        }))
+    }
+}
+
+impl std::fmt::Display for AdapterFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "adapting {} via {} failed",
+            self.path.to_string_lossy(),
+            self.adapter
+        )
+    }
+}
+
+/// the input of a file, kept so it can be read again when an adapter fails
+enum ReplayableInput {
+    /// can be opened again from the file system
+    RealFile {
+        path: PathBuf,
+        first: Option<ReadBox>,
+    },
+    /// the bytes are recorded while the first adapter reads them
+    Recorded {
+        shared: Arc<std::sync::Mutex<Recording>>,
+        started: bool,
+    },
+    /// can only be read once
+    Once(Option<ReadBox>),
+}
+
+struct Recording {
+    inner: ReadBox,
+    /// everything read from `inner` so far, while `recording`
+    buf: Vec<u8>,
+    max: usize,
+    /// false once `buf` would grow over `max` or an adapter produced output
+    recording: bool,
+    /// false once bytes were read from `inner` without being recorded
+    complete: bool,
+    /// readers of older generations were handed out to adapters that failed
+    generation: u64,
+}
+
+/// reads the recorded bytes first, then continues with the rest of the input
+struct RecordingReader {
+    shared: Arc<std::sync::Mutex<Recording>>,
+    generation: u64,
+    pos: usize,
+}
+
+impl AsyncRead for RecordingReader {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        out: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let mut s = this.shared.lock().unwrap();
+        if s.generation != this.generation {
+            return Poll::Ready(Err(std::io::Error::other(
+                "input was passed on to the next adapter",
+            )));
+        }
+        if !s.recording && this.pos == s.buf.len() && !s.buf.is_empty() {
+            s.buf = Vec::new();
+            this.pos = 0;
+        }
+        if this.pos < s.buf.len() {
+            let n = out.remaining().min(s.buf.len() - this.pos);
+            out.put_slice(&s.buf[this.pos..this.pos + n]);
+            this.pos += n;
+            return Poll::Ready(Ok(()));
+        }
+        let filled = out.filled().len();
+        let res = s.inner.as_mut().poll_read(cx, out);
+        if let Poll::Ready(Ok(())) = res {
+            let read = &out.filled()[filled..];
+            if s.recording {
+                if s.buf.len() + read.len() > s.max {
+                    s.recording = false;
+                    s.complete = false;
+                    s.buf = Vec::new();
+                    this.pos = 0;
+                } else {
+                    s.buf.extend_from_slice(read);
+                    this.pos += read.len();
+                }
+            }
+        }
+        res
+    }
+}
+
+impl ReplayableInput {
+    /// `max_buffer`: inputs that are not real files are recorded in memory up to this size
+    fn new(inp: ReadBox, is_real_file: bool, path: &Path, max_buffer: u64) -> Self {
+        if is_real_file {
+            return Self::RealFile {
+                path: path.to_path_buf(),
+                first: Some(inp),
+            };
+        }
+        Self::Recorded {
+            shared: Arc::new(std::sync::Mutex::new(Recording {
+                inner: inp,
+                buf: Vec::new(),
+                max: usize::try_from(max_buffer).unwrap_or(usize::MAX),
+                recording: true,
+                complete: true,
+                generation: 0,
+            })),
+            started: false,
+        }
+    }
+
+    /// whether the input can still be read again from the start
+    fn replayable(&self) -> bool {
+        match self {
+            Self::RealFile { .. } => true,
+            Self::Recorded { shared, .. } => shared.lock().unwrap().complete,
+            Self::Once(_) => false,
+        }
+    }
+
+    /// the input won't be read again, stop recording it
+    fn commit(&self) {
+        if let Self::Recorded { shared, .. } = self {
+            shared.lock().unwrap().recording = false;
+        }
+    }
+
+    async fn get(&mut self) -> Result<Option<ReadBox>> {
+        Ok(match self {
+            Self::RealFile { path, first } => match first.take() {
+                Some(inp) => Some(inp),
+                None => Some(Box::pin(tokio::fs::File::open(path).await?)),
+            },
+            Self::Recorded { shared, started } => {
+                let mut s = shared.lock().unwrap();
+                if *started {
+                    if !s.complete {
+                        return Ok(None);
+                    }
+                    s.generation += 1;
+                }
+                *started = true;
+                Some(Box::pin(RecordingReader {
+                    shared: shared.clone(),
+                    generation: s.generation,
+                    pos: 0,
+                }))
+            }
+            Self::Once(inp) => inp.take(),
+        })
+    }
+}
 
-            Ok(Box::pin(inp))
+/**
+ * run the first of the candidate adapters. If it fails before producing any output
+ * (e.g. because its binary is not installed or it crashes on a malformed file), try the next one.
+ *
+ * Returns the adapter that actually produced the output.
+ */
+async fn adapt_with_fallback(
+    ai: AdaptInfo,
+    candidates: Vec<AdapterCandidate>,
+    steps: Vec<AdapterStep>,
+) -> Result<(Arc<dyn FileAdapter>, AdaptedEntries)> {
+    let AdaptInfo {
+        filepath_hint,
+        is_real_file,
+        archive_recursion_depth,
+        inp,
+        line_prefix,
+        postprocess,
+        config,
+        limit_usage,
+    } = ai;
+    let count = candidates.len();
+    let mut input = if count > 1 {
+        ReplayableInput::new(
+            inp,
+            is_real_file,
+            &filepath_hint,
+            config.fallback_buffer_size.0,
+        )
+    } else {
+        ReplayableInput::Once(Some(inp))
+    };
+    for (i, (adapter, detection_reason)) in candidates.into_iter().enumerate() {
+        let Some(inp) = input.get().await? else {
+            break;
+        };
+        let meta = adapter.metadata();
+        debug!(
+            "Chose adapter '{}' because of matcher {:?}",
+            &meta.name, &detection_reason
+        );
+        diagnostics::adapter_started(&meta.name, &filepath_hint);
+        let can_fall_back = i + 1 < count && input.replayable();
+        let ai = AdaptInfo {
+            filepath_hint: filepath_hint.clone(),
+            is_real_file,
+            archive_recursion_depth,
+            inp,
+            line_prefix: line_prefix.clone(),
+            postprocess,
+            config: config.clone(),
+            limit_usage: limit_usage.clone(),
+        };
+        let mut steps = steps.clone();
+        steps.push(AdapterStep {
+            path: filepath_hint.clone(),
+            adapter: meta.name.clone(),
+            detected_by: detection_reason.clone(),
+        });
+        match run_adapter(adapter.clone(), detection_reason, ai, can_fall_back, steps).await {
+            Ok(output) => {
+                info!(
+                    "{} adapter: {}",
+                    filepath_hint.to_string_lossy(),
+                    &meta.name
+                );
+                if is_real_file {
+                    diagnostics::file_adapted();
+                }
+                input.commit();
+                return Ok((adapter, output));
+            }
+            Err(e) if can_fall_back && input.replayable() => {
+                warn!(
+                    "adapter {} failed for {}, trying the next one: {:#}",
+                    &meta.name,
+                    filepath_hint.to_string_lossy(),
+                    e
+                );
+            }
+            Err(e) => return Err(e),
         }
     }
+    Err(format_err!(
+        "No adapter could process {}",
+        filepath_hint.to_string_lossy()
+    ))
+}
+
+/// run one adapter. if `wait_for_output`, only return once the adapter has produced output (or finished),
+/// since most adapters that fail do so before producing any output
+async fn run_adapter(
+    adapter: Arc<dyn FileAdapter>,
+    detection_reason: FileMatcher,
+    ai: AdaptInfo,
+    wait_for_output: bool,
+    steps: Vec<AdapterStep>,
+) -> Result<AdaptedEntries> {
+    let mut entries = adapt_entries(adapter, detection_reason, ai, steps).await?;
+    if !wait_for_output {
+        return Ok(entries);
+    }
+    let Some(first) = entries.next().await else {
+        return Ok(entries);
+    };
+    // the errors already have the `AdapterFailed` context, see `adapt_entries_inner`
+    let mut first = first?;
+    let mut output = BufReader::new(first.ai.inp);
+    output.fill_buf().await?;
+    first.ai.inp = Box::pin(output);
+    Ok(Box::pin(tokio_stream::once(Ok(first)).chain(entries)))
 }
 
 async fn read_discard(mut x: ReadBox) -> Result<()> {
@@ -205,6 +680,25 @@ async fn read_discard(mut x: ReadBox) -> Result<()> {
     Ok(())
 }
 
+/// a file that was passed to an adapter: part of the way to an `AdaptedEntry`
+#[derive(Debug, Clone)]
+pub struct AdapterStep {
+    pub path: PathBuf,
+    pub adapter: String,
+    pub detected_by: FileMatcher,
+}
+
+/// a file that is not adapted any further: the output of the last adapter in `steps`,
+/// a file in an archive that no adapter matches or a marker like `[rga: max archive recursion reached (4)]`
+pub struct AdaptedEntry {
+    pub ai: AdaptInfo,
+    /// the adapters that were run to get this file, outermost first
+    pub steps: Vec<AdapterStep>,
+}
+
+pub type AdaptedEntries = Pin<Box<dyn Stream<Item = Result<AdaptedEntry>> + Send>>;
+
+/// run the adapter and recurse into its output files. the text of all output files concatenated is the text of the file
 pub fn loop_adapt(
     adapter: &dyn FileAdapter,
     detection_reason: FileMatcher,
@@ -217,51 +711,104 @@ pub async fn loop_adapt_inner(
     detection_reason: FileMatcher,
     ai: AdaptInfo,
 ) -> anyhow::Result<AdaptedFilesIterBox> {
+    let entries = adapt_entries_inner(adapter, detection_reason, ai, vec![]).await?;
+    Ok(Box::pin(entries.map(|entry| entry.map(|e| e.ai))))
+}
+
+fn adapt_entries(
+    adapter: Arc<dyn FileAdapter>,
+    detection_reason: FileMatcher,
+    ai: AdaptInfo,
+    steps: Vec<AdapterStep>,
+) -> Pin<Box<dyn Future<Output = Result<AdaptedEntries>> + Send>> {
+    Box::pin(
+        async move { adapt_entries_inner(adapter.as_ref(), detection_reason, ai, steps).await },
+    )
+}
+
+/// run the adapter and recurse into the files it outputs. `steps` ends with this adapter
+async fn adapt_entries_inner(
+    adapter: &dyn FileAdapter,
+    detection_reason: FileMatcher,
+    ai: AdaptInfo,
+    steps: Vec<AdapterStep>,
+) -> Result<AdaptedEntries> {
     let fph = ai.filepath_hint.clone();
+    // ends when the output stream ends, so it includes the time of the adapters of nested files
+    let span = profile::adapter_span(
+        &adapter.metadata().name,
+        &ai.filepath_hint,
+        ai.archive_recursion_depth,
+    );
+    let ai = AdaptInfo {
+        inp: span.count_in(ai.inp),
+        ..ai
+    };
     let inp = adapter.adapt(ai, &detection_reason).await;
-    let inp = if adapter.metadata().name == "postprocprefix" {
-        // don't add confusing error context
-        inp?
-    } else {
-        inp.with_context(|| {
-            format!(
-                "adapting {} via {} failed",
-                fph.to_string_lossy(),
-                adapter.metadata().name
-            )
-        })?
+    // don't add confusing error context to postprocprefix
+    let failed = (adapter.metadata().name != "postprocprefix").then(|| AdapterFailed {
+        adapter: adapter.metadata().name.clone(),
+        path: fph.clone(),
+    });
+    let inp = match &failed {
+        Some(failed) => inp.with_context(|| failed.clone())?,
+        None => inp?,
     };
+    // the outputs of these are files within an archive (or a compressed file)
+    let members = adapter.metadata().recurses;
     let s = stream! {
         for await file in inp {
             trace!("next file");
-            match buf_choose_adapter(file?).await? {
-                Ret::Recurse(ai, adapter, detection_reason, _active_adapters) => {
+            let mut file = match &failed {
+                Some(failed) => file.with_context(|| failed.clone())?,
+                None => file?,
+            };
+            if let Some(failed) = &failed {
+                // e.g. a program that exits with an error after writing some output
+                file.inp = Box::pin(AdapterOutput { inner: file.inp, failed: failed.clone() });
+            }
+            let exceeded = if members { file.config.limits.count_member(&file.limit_usage) } else { None };
+            if let Some(reason) = exceeded {
+                yield Ok(AdaptedEntry {
+                    ai: marker_file(
+                        format!("[rga: limit exceeded: {reason}]"),
+                        file.line_prefix.clone(),
+                        file.archive_recursion_depth,
+                        file.postprocess,
+                        file.config.clone(),
+                        file.limit_usage.clone(),
+                    ),
+                    steps: steps.clone(),
+                });
+                break;
+            }
+            let file = AdaptInfo {
+                inp: span.count_out(file.config.limits.limit_output(file.inp, members, &file.limit_usage)),
+                ..file
+            };
+            match buf_choose_adapter(file).await? {
+                Ret::Recurse(ai, candidates, _active_adapters) => {
                     if ai.archive_recursion_depth >= ai.config.max_archive_recursion.0 {
                         // some adapters (esp. zip) assume that the entry is read fully and might hang otherwise
                         read_discard(ai.inp).await?;
                         let s = format!("{}[rga: max archive recursion reached ({})]\n", ai.line_prefix, ai.archive_recursion_depth).into_bytes();
-                        yield Ok(AdaptInfo {
-                            inp: Box::pin(Cursor::new(s)),
-                            ..ai
+                        yield Ok(AdaptedEntry {
+                            ai: AdaptInfo {
+                                inp: Box::pin(Cursor::new(s)),
+                                ..ai
+                            },
+                            steps: steps.clone(),
                         });
                         continue;
                     }
-                    debug!(
-                        "Chose adapter '{}' because of matcher {:?}",
-                        &adapter.metadata().name, &detection_reason
-                    );
-                    eprintln!(
-                        "{} adapter: {}",
-                        ai.filepath_hint.to_string_lossy(),
-                        &adapter.metadata().name
-                    );
-                    for await ifile in loop_adapt(adapter.as_ref(), detection_reason, ai).await? {
-                        yield ifile;
+                    let (_adapter, entries) = adapt_with_fallback(ai, candidates, steps.clone()).await?;
+                    for await entry in entries {
+                        yield entry;
                     }
                 }
                 Ret::Passthrough(ai) => {
                     debug!("no adapter for {}, ending recursion", ai.filepath_hint.to_string_lossy());
-                    yield Ok(ai);
+                    yield Ok(AdaptedEntry { ai, steps: steps.clone() });
                 }
             }
             trace!("done with files");
@@ -270,3 +817,235 @@ pub async fn loop_adapt_inner(
     };
     Ok(Box::pin(s))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::adapters::custom::CustomAdapterConfig;
+    use crate::test_utils::*;
+    use anyhow::bail;
+    use pretty_assertions::assert_eq;
+
+    fn adapter(name: &str, binary: &str, args: &[&str]) -> CustomAdapterConfig {
+        CustomAdapterConfig {
+            name: name.to_string(),
+            description: "".to_string(),
+            version: 1,
+            extensions: vec!["fallbacktest".to_string()],
+            binary: binary.to_string(),
+            args: args.iter().map(|s| s.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn fallback() -> Result<()> {
+        let (mut a, _) = simple_adapt_info(
+            Path::new("foo.fallbacktest"),
+            Box::pin(Cursor::new(b"hello\n".to_vec())),
+        );
+        a.config.custom_adapters = Some(vec![
+            adapter("missing", "rga-test-binary-that-does-not-exist", &[]),
+            adapter("crashing", "sh", &["-c", "cat > /dev/null; exit 1"]),
+            adapter("working", "cat", &[]),
+        ]);
+        let mut out = rga_preproc(a).await?;
+        let mut buf = String::new();
+        out.read_to_string(&mut buf).await?;
+        assert_eq!(buf, "PREFIX:hello\nPREFIX:\n");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn fallback_buffer_size() -> Result<()> {
+        let (mut a, _) = simple_adapt_info(
+            Path::new("foo.fallbacktest"),
+            Box::pin(Cursor::new(b"hello\n".to_vec())),
+        );
+        a.config.fallback_buffer_size = crate::config::FallbackBufferSize(3);
+        a.config.custom_adapters = Some(vec![
+            adapter("crashing", "sh", &["-c", "cat > /dev/null; exit 1"]),
+            adapter("working", "cat", &[]),
+        ]);
+        // no fallback, so the failure of the first adapter ends the output
+        let mut buf = String::new();
+        match rga_preproc(a).await {
+            Ok(mut out) => assert!(out.read_to_string(&mut buf).await.is_err()),
+            Err(e) => assert!(format!("{e:#}").contains("crashing"), "{e:#}"),
+        }
+        assert_eq!(buf, "");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn replayable_input_records_lazily() -> Result<()> {
+        let inp: ReadBox = Box::pin(Cursor::new(b"hello\n".to_vec()));
+        let mut input = ReplayableInput::new(inp, false, Path::new("foo"), 4);
+        let mut first = input.get().await?.unwrap();
+        let mut head = [0; 3];
+        first.read_exact(&mut head).await?;
+        assert_eq!(&head, b"hel");
+        // the first reader only read part of the input, so it is replayed from the start
+        assert!(input.replayable());
+        let mut second = input.get().await?.unwrap();
+        assert!(first.read_u8().await.is_err());
+        let mut buf = String::new();
+        second.read_to_string(&mut buf).await?;
+        assert_eq!(buf, "hello\n");
+        // reading more than the limit stops the recording
+        assert!(!input.replayable());
+        assert!(input.get().await?.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn magic_bytes_short_reads() -> Result<()> {
+        let mut tar = vec![0u8; 512];
+        tar[257..262].copy_from_slice(b"ustar");
+        // one byte per read, so a single fill_buf would only see the first byte
+        let chunks = tar
+            .into_iter()
+            .map(|b| Ok::<_, std::io::Error>(bytes::Bytes::from(vec![b])));
+        let mut inp: ReadBox = Box::pin(tokio_util::io::StreamReader::new(tokio_stream::iter(
+            chunks,
+        )));
+        let config = RgaConfig {
+            detection: DetectionMode::Hybrid,
+            ..Default::default()
+        };
+        let (candidates, _) =
+            choose_adapters(&config, Path::new("backup.bak"), 0, &mut inp).await?;
+        let names: Vec<_> = candidates
+            .iter()
+            .map(|(a, _)| a.metadata().name.as_str())
+            .collect();
+        assert_eq!(names, vec!["tar"]);
+        // the sniffed bytes are still there for the adapter
+        let mut rest = vec![];
+        inp.read_to_end(&mut rest).await?;
+        assert_eq!(rest.len(), 512);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn failed_adapter() -> Result<()> {
+        let (mut a, _) = simple_adapt_info(
+            Path::new("foo.fallbacktest"),
+            Box::pin(Cursor::new(b"hello\n".to_vec())),
+        );
+        a.config.custom_adapters = Some(vec![adapter(
+            "missing",
+            "rga-test-binary-that-does-not-exist",
+            &[],
+        )]);
+        let Err(e) = rga_preproc(a).await else {
+            bail!("missing binary should fail");
+        };
+        assert_eq!(
+            AdapterFailed::find(&e).map(|a| a.adapter.as_str()),
+            Some("missing")
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn failed_adapter_output() -> Result<()> {
+        let (mut a, _) = simple_adapt_info(
+            Path::new("foo.fallbacktest"),
+            Box::pin(Cursor::new(b"hello\n".to_vec())),
+        );
+        a.config.custom_adapters = Some(vec![adapter("crashing", "sh", &["-c", "cat; exit 1"])]);
+        let mut out = rga_preproc(a).await?;
+        let mut buf = vec![];
+        let e = anyhow::Error::new(out.read_to_end(&mut buf).await.expect_err("adapter fails"))
+            .context("reading output");
+        assert_eq!(
+            AdapterFailed::find(&e).map(|a| a.adapter.as_str()),
+            Some("crashing")
+        );
+        let message = format!("{e:#}");
+        assert!(message.contains("via crashing failed"), "{message}");
+        assert!(message.contains("exit status: 1"), "{message}");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn adapter_timeout() -> Result<()> {
+        let (mut a, _) = simple_adapt_info(
+            Path::new("foo.fallbacktest"),
+            Box::pin(Cursor::new(b"hello\n".to_vec())),
+        );
+        a.config.custom_adapters = Some(vec![adapter(
+            "hanging",
+            "sh",
+            &["-c", "cat > /dev/null; sleep 60"],
+        )]);
+        a.config.adapter_timeout = crate::config::AdapterTimeout(1);
+        let start = std::time::Instant::now();
+        let mut out = rga_preproc(a).await?;
+        let mut buf = String::new();
+        out.read_to_string(&mut buf).await?;
+        assert!(
+            buf.ends_with("PREFIX:[rga: adapter timed out after 1s]\n"),
+            "{buf}"
+        );
+        assert!(start.elapsed() < std::time::Duration::from_secs(30));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn output_limit() -> Result<()> {
+        let cache_dir = tempfile::tempdir()?;
+        let run = async |output_size| -> Result<String> {
+            let filepath = test_data_dir().join("hello.gz");
+            let (mut a, _) = simple_fs_adapt_info(&filepath).await?;
+            a.config.cache.path =
+                crate::config::CachePath(cache_dir.path().to_string_lossy().into_owned());
+            a.config.limits.output_size = crate::config::MaxOutputSize(output_size);
+            let mut out = rga_preproc(a).await?;
+            let mut buf = String::new();
+            out.read_to_string(&mut buf).await?;
+            Ok(buf)
+        };
+        let limited = run(3).await?;
+        assert!(
+            limited.contains("[rga: limit exceeded: adapter output larger than 3 B]"),
+            "{limited}"
+        );
+        // the cut off output was not cached
+        let full = run(0).await?;
+        assert!(!full.contains("limit exceeded"), "{full}");
+        assert_eq!(run(0).await?, full);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn undecodable_cache_entry() -> Result<()> {
+        let cache_dir = tempfile::tempdir()?;
+        let run = async || -> Result<String> {
+            let (mut a, _) = simple_fs_adapt_info(&test_data_dir().join("hello.gz")).await?;
+            a.config.cache.path =
+                crate::config::CachePath(cache_dir.path().to_string_lossy().into_owned());
+            let mut out = rga_preproc(a).await?;
+            let mut buf = String::new();
+            out.read_to_string(&mut buf).await?;
+            Ok(buf)
+        };
+        let expected = run().await?;
+        // a broken zstd content checksum, with a matching blake3 checksum of the blob, so it only fails at the end of decoding
+        let db = rusqlite::Connection::open(cache_dir.path().join("cache.sqlite3"))?;
+        let mut blob: Vec<u8> =
+            db.query_row("select text_content_zstd from preproc_cache", [], |r| {
+                r.get(0)
+            })?;
+        let last = blob.len() - 1;
+        blob[last] ^= 0xff;
+        let blob = &blob[..];
+        db.execute(
+            "update preproc_cache set text_content_zstd = ?, checksum = ?",
+            rusqlite::params![blob, blake3::hash(blob).as_bytes().to_vec()],
+        )?;
+        assert_eq!(run().await?, expected);
+        Ok(())
+    }
+}
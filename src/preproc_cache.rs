@@ -1,13 +1,35 @@
-use crate::{adapters::FileAdapter, preproc::ActiveAdapters};
+use crate::{
+    adapters::{FileAdapter, ReadBox},
+    cache_encryption::CacheEncryption,
+    config::CacheConfig,
+    preproc::ActiveAdapters,
+};
 use anyhow::{Context, Result};
-use log::warn;
+use async_compression::tokio::bufread::ZstdDecoder;
+use log::{debug, warn};
 use path_clean::PathClean;
 use rusqlite::{OptionalExtension, named_params};
-use std::{path::Path, time::UNIX_EPOCH};
+use serde::Serialize;
+use std::{
+    io::{Cursor, Read},
+    path::Path,
+    sync::Arc,
+    time::UNIX_EPOCH,
+};
 use tokio_rusqlite::Connection;
 
-static SCHEMA_VERSION: i32 = 3;
-#[derive(Clone)]
+static SCHEMA_VERSION: i32 = 7;
+
+/// max size of a trained zstd dictionary (same as the zstd cli default)
+const MAX_DICTIONARY_SIZE: usize = 112_640;
+/// only the beginning of each cached text is used as a training sample
+const MAX_SAMPLE_SIZE: usize = 128 * 1024;
+/// stop collecting samples for an adapter once this many bytes are collected
+const MAX_TOTAL_SAMPLES_SIZE: usize = 100 * MAX_DICTIONARY_SIZE;
+/// training a dictionary from fewer samples than this is not worth it (and zstd usually fails anyways)
+const MIN_DICTIONARY_SAMPLES: usize = 10;
+
+#[derive(Clone, Serialize)]
 pub struct CacheKey {
     config_hash: String,
     adapter: String,
@@ -52,10 +74,94 @@ impl CacheKey {
     }
 }
 
+impl std::fmt::Display for CacheKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "adapter={} v{}, config_hash={}, active_adapters={}, file_path={}, file_mtime_unix_ms={}",
+            self.adapter,
+            self.adapter_version,
+            self.config_hash,
+            self.active_adapters,
+            self.file_path,
+            self.file_mtime_unix_ms
+        )
+    }
+}
+
+/// a trained zstd dictionary, used to compress the (usually small) cache entries of one adapter
+pub struct CacheDictionary {
+    pub id: i64,
+    pub data: Vec<u8>,
+}
+
+/// a zstd compressed cache entry, together with the dictionary it was compressed with (if any)
+pub struct CachedBlob {
+    pub zstd: Vec<u8>,
+    pub dictionary: Option<Vec<u8>>,
+}
+
+impl CachedBlob {
+    /// decompress the blob while it is read. the zstd checksum of the content is verified at the end
+    pub fn into_reader(self) -> Result<ReadBox> {
+        let inp = Cursor::new(self.zstd);
+        Ok(match self.dictionary {
+            Some(dictionary) => Box::pin(ZstdDecoder::with_dict(inp, &dictionary)?),
+            None => Box::pin(ZstdDecoder::new(inp)),
+        })
+    }
+
+    /// decompress the blob without keeping the output, to check that it is complete (including the zstd checksum)
+    pub fn verify(&self) -> Result<()> {
+        let dictionary = self.dictionary.as_deref().unwrap_or(&[]);
+        let mut decoder = zstd::stream::read::Decoder::with_dictionary(&self.zstd[..], dictionary)?;
+        std::io::copy(&mut decoder, &mut std::io::sink())?;
+        Ok(())
+    }
+
+    /// decompress the whole blob into memory
+    pub fn decode(&self) -> Result<Vec<u8>> {
+        let dictionary = self.dictionary.as_deref().unwrap_or(&[]);
+        let mut decoder = zstd::stream::read::Decoder::with_dictionary(&self.zstd[..], dictionary)?;
+        let mut out = Vec::new();
+        decoder.read_to_end(&mut out)?;
+        Ok(out)
+    }
+}
+
+/// the result of looking up a cache entry
+pub enum CacheLookup {
+    Hit(CachedBlob),
+    Miss,
+    /// the stored entry does not match its checksum, e.g. because writing it was interrupted.
+    /// the caller should delete it and run the adapter again
+    Corrupt,
+}
+
+impl CacheLookup {
+    pub fn hit(self) -> Option<CachedBlob> {
+        match self {
+            CacheLookup::Hit(blob) => Some(blob),
+            CacheLookup::Miss | CacheLookup::Corrupt => None,
+        }
+    }
+}
+
 #[async_trait::async_trait]
 pub trait PreprocCache {
-    async fn get(&self, key: &CacheKey) -> Result<Option<Vec<u8>>>;
-    async fn set(&mut self, key: &CacheKey, value: Vec<u8>) -> Result<()>;
+    /// does not change the cache, even if the entry is corrupt
+    async fn get(&self, key: &CacheKey) -> Result<CacheLookup>;
+    /// store a compressed blob. `dictionary_id` must be the id of the dictionary the blob was compressed with
+    async fn set(
+        &mut self,
+        key: &CacheKey,
+        value: Vec<u8>,
+        dictionary_id: Option<i64>,
+    ) -> Result<()>;
+    /// the most recently trained dictionary for the given adapter, used to compress new entries
+    async fn dictionary_for(&self, adapter: &str) -> Result<Option<Arc<CacheDictionary>>>;
+    /// remove an entry, e.g. because it could not be decoded
+    async fn delete(&mut self, key: &CacheKey) -> Result<()>;
 }
 
 async fn connect_pragmas(db: &Connection) -> Result<()> {
@@ -70,6 +176,14 @@ async fn connect_pragmas(db: &Connection) -> Result<()> {
         db.pragma_update(None, "temp_store", "memory")?;
         db.pragma_update(None, "synchronous", "off")?; // integrity isn't very important here
         db.pragma_update(None, "mmap_size", "2000000000")?;
+        db.execute("
+            create table if not exists zstd_dictionaries (
+                id integer primary key,
+                adapter text not null,
+                created_unix_ms integer not null default (unixepoch() * 1000),
+                dictionary blob not null
+            ) strict", []
+        )?;
         db.execute("
             create table if not exists preproc_cache (
                 config_hash text not null,
@@ -79,11 +193,15 @@ async fn connect_pragmas(db: &Connection) -> Result<()> {
                 active_adapters text not null, -- 'null' if adapter cannot recurse
                 file_path text not null,
                 file_mtime_unix_ms integer not null,
-                text_content_zstd blob not null
+                dictionary_id integer references zstd_dictionaries (id), -- null if compressed without dictionary
+                text_content_zstd blob not null,
+                checksum blob not null, -- blake3 hash of text_content_zstd, to detect partially written or corrupt entries
+                encrypted integer not null -- 1 if text_content_zstd is encrypted (see CacheEncryption)
             ) strict", []
         )?;
 
         db.execute("create unique index if not exists preproc_cache_idx on preproc_cache (config_hash, adapter, adapter_version, file_path, active_adapters)", [])?;
+        db.execute("create index if not exists zstd_dictionaries_adapter_idx on zstd_dictionaries (adapter)", [])?;
 
         Ok(())
     })
@@ -99,17 +217,33 @@ async fn connect_pragmas(db: &Connection) -> Result<()> {
     Ok(())
 }
 
+fn checksum(blob: &[u8]) -> Vec<u8> {
+    blake3::hash(blob).as_bytes().to_vec()
+}
+
+fn dictionary_aad(adapter: &str) -> String {
+    format!("zstd_dictionary:{adapter}")
+}
+
+/// the associated data of an encrypted blob: the whole key of its row (with the stored file path),
+/// so it can't be decrypted as the entry of a different adapter, config or file version
+fn blob_aad(stored_key: &CacheKey) -> Vec<u8> {
+    serde_json::to_vec(stored_key).expect("cache key is serializable")
+}
+
 struct SqliteCache {
     db: Connection,
+    encryption: Option<Arc<CacheEncryption>>,
 }
 impl SqliteCache {
-    async fn new(path: &Path) -> Result<Self> {
+    async fn new(path: &Path, encryption: Option<CacheEncryption>) -> Result<Self> {
         let db = Connection::open(path.join("cache.sqlite3")).await?;
         db.call(|db| {
             let schema_version: i32 = db.pragma_query_value(None, "user_version", |r| r.get(0))?;
             if schema_version != SCHEMA_VERSION {
                 warn!("Cache schema version mismatch, clearing cache");
                 db.execute("drop table if exists preproc_cache", [])?;
+                db.execute("drop table if exists zstd_dictionaries", [])?;
                 db.pragma_update(None, "user_version", format!("{SCHEMA_VERSION}"))?;
             }
             Ok(())
@@ -118,26 +252,44 @@ impl SqliteCache {
 
         connect_pragmas(&db).await?;
 
-        Ok(Self { db })
+        Ok(Self {
+            db,
+            encryption: encryption.map(Arc::new),
+        })
+    }
+
+    /// the key as stored in the db: the file path is hashed if encryption is enabled
+    fn stored_key(&self, key: &CacheKey) -> CacheKey {
+        match &self.encryption {
+            Some(enc) => CacheKey {
+                file_path: enc.hash_path(&key.file_path),
+                ..key.clone()
+            },
+            None => key.clone(),
+        }
     }
 }
 
 #[async_trait::async_trait]
 impl PreprocCache for SqliteCache {
-    async fn get(&self, key: &CacheKey) -> Result<Option<Vec<u8>>> {
-        let key = (*key).clone(); // todo: without cloning
-        Ok(self
+    async fn get(&self, key: &CacheKey) -> Result<CacheLookup> {
+        let key = self.stored_key(key);
+        let aad = blob_aad(&key);
+        let adapter = key.adapter.clone();
+        let row = self
             .db
             .call(move |db| {
                 Ok(db
                     .query_row(
-                        "select text_content_zstd from preproc_cache where
-                            adapter = :adapter
-                        and config_hash = :config_hash
-                        and adapter_version = :adapter_version
-                        and active_adapters = :active_adapters
-                        and file_path = :file_path
-                        and file_mtime_unix_ms = :file_mtime_unix_ms
+                        "select c.text_content_zstd, c.checksum, d.dictionary from preproc_cache c
+                        left join zstd_dictionaries d on d.id = c.dictionary_id
+                        where
+                            c.adapter = :adapter
+                        and c.config_hash = :config_hash
+                        and c.adapter_version = :adapter_version
+                        and c.active_adapters = :active_adapters
+                        and c.file_path = :file_path
+                        and c.file_mtime_unix_ms = :file_mtime_unix_ms
                 ",
                         named_params! {
                             ":config_hash": &key.config_hash,
@@ -147,32 +299,81 @@ impl PreprocCache for SqliteCache {
                             ":file_path": &key.file_path,
                             ":file_mtime_unix_ms": &key.file_mtime_unix_ms
                         },
-                        |r| r.get::<_, Vec<u8>>(0),
+                        |r| {
+                            Ok((
+                                r.get::<_, Vec<u8>>(0)?,
+                                r.get::<_, Vec<u8>>(1)?,
+                                r.get::<_, Option<Vec<u8>>>(2)?,
+                            ))
+                        },
                     )
                     .optional()?)
             })
             .await
-            .context("reading from cache")?)
+            .context("reading from cache")?;
+        let Some((blob, blob_checksum, dictionary)) = row else {
+            return Ok(CacheLookup::Miss);
+        };
+        if checksum(&blob) != blob_checksum {
+            return Ok(CacheLookup::Corrupt);
+        }
+        let Some(enc) = &self.encryption else {
+            return Ok(CacheLookup::Hit(CachedBlob {
+                zstd: blob,
+                dictionary,
+            }));
+        };
+        let decrypted = enc.decrypt(&aad, &blob).and_then(|blob| {
+            let dictionary = dictionary
+                .map(|d| enc.decrypt(dictionary_aad(&adapter).as_bytes(), &d))
+                .transpose()?;
+            Ok(CachedBlob {
+                zstd: blob,
+                dictionary,
+            })
+        });
+        match decrypted {
+            Ok(blob) => Ok(CacheLookup::Hit(blob)),
+            Err(e) => {
+                // e.g. written with a different key
+                warn!("ignoring cache entry: {e:#}");
+                Ok(CacheLookup::Miss)
+            }
+        }
     }
 
-    async fn set(&mut self, key: &CacheKey, value: Vec<u8>) -> Result<()> {
-        let key = (*key).clone(); // todo: without cloning
+    async fn set(
+        &mut self,
+        key: &CacheKey,
+        value: Vec<u8>,
+        dictionary_id: Option<i64>,
+    ) -> Result<()> {
+        let key = self.stored_key(key);
         log::trace!(
             "Writing to cache: {}, {}, {} byte",
             key.adapter,
             key.file_path,
             value.len()
         );
+        let value = match &self.encryption {
+            Some(enc) => enc.encrypt(&blob_aad(&key), &value)?,
+            None => value,
+        };
+        let value_checksum = checksum(&value);
+        let encrypted = self.encryption.is_some();
         Ok(self
             .db
             .call(move |db| {
-                db.execute(
-                    "insert into preproc_cache (config_hash, adapter, adapter_version, active_adapters, file_path, file_mtime_unix_ms, text_content_zstd) values
-                        (:config_hash, :adapter, :adapter_version, :active_adapters, :file_path, :file_mtime_unix_ms, :text_content_zstd)
+                let inserted = db.execute(
+                    "insert into preproc_cache (config_hash, adapter, adapter_version, active_adapters, file_path, file_mtime_unix_ms, dictionary_id, text_content_zstd, checksum, encrypted) values
+                        (:config_hash, :adapter, :adapter_version, :active_adapters, :file_path, :file_mtime_unix_ms, :dictionary_id, :text_content_zstd, :checksum, :encrypted)
                     on conflict (config_hash, adapter, adapter_version, active_adapters, file_path) do update set
                         file_mtime_unix_ms = :file_mtime_unix_ms,
                         created_unix_ms = unixepoch() * 1000,
-                        text_content_zstd = :text_content_zstd",
+                        dictionary_id = :dictionary_id,
+                        text_content_zstd = :text_content_zstd,
+                        checksum = :checksum,
+                        encrypted = :encrypted",
                     named_params! {
                         ":config_hash": &key.config_hash,
                         ":adapter": &key.adapter,
@@ -180,29 +381,625 @@ impl PreprocCache for SqliteCache {
                         ":active_adapters": &key.active_adapters,
                         ":file_path": &key.file_path,
                         ":file_mtime_unix_ms": &key.file_mtime_unix_ms,
-                        ":text_content_zstd": value
-                    })?;
+                        ":dictionary_id": &dictionary_id,
+                        ":text_content_zstd": value,
+                        ":checksum": value_checksum,
+                        ":encrypted": encrypted
+                    });
+                match inserted {
+                    Ok(_) => Ok(()),
+                    // the dictionary was deleted by a concurrent `rga cache vacuum`, this entry just isn't cached
+                    Err(rusqlite::Error::SqliteFailure(e, _))
+                        if e.extended_code == rusqlite::ffi::SQLITE_CONSTRAINT_FOREIGNKEY =>
+                    {
+                        debug!("cache dictionary was deleted, not caching: {e}");
+                        Ok(())
+                    }
+                    Err(e) => Err(e.into()),
+                }
+            })
+            .await?)
+    }
+
+    async fn dictionary_for(&self, adapter: &str) -> Result<Option<Arc<CacheDictionary>>> {
+        let adapter = adapter.to_string();
+        let adapter2 = adapter.clone();
+        let row = self
+            .db
+            .call(move |db| {
+                Ok(db
+                    .query_row(
+                        "select id, dictionary from zstd_dictionaries where adapter = ? order by id desc limit 1",
+                        [&adapter2],
+                        |r| Ok((r.get::<_, i64>(0)?, r.get::<_, Vec<u8>>(1)?)),
+                    )
+                    .optional()?)
+            })
+            .await
+            .context("reading dictionary from cache")?;
+        let Some((id, data)) = row else {
+            return Ok(None);
+        };
+        let data = match &self.encryption {
+            Some(enc) => match enc.decrypt(dictionary_aad(&adapter).as_bytes(), &data) {
+                Ok(data) => data,
+                Err(e) => {
+                    warn!("ignoring cache dictionary: {e:#}");
+                    return Ok(None);
+                }
+            },
+            None => data,
+        };
+        Ok(Some(Arc::new(CacheDictionary { id, data })))
+    }
+
+    async fn delete(&mut self, key: &CacheKey) -> Result<()> {
+        let key = self.stored_key(key);
+        Ok(self
+            .db
+            .call(move |db| {
+                db.execute(
+                    "delete from preproc_cache where
+                            adapter = :adapter
+                        and config_hash = :config_hash
+                        and adapter_version = :adapter_version
+                        and active_adapters = :active_adapters
+                        and file_path = :file_path",
+                    named_params! {
+                        ":config_hash": &key.config_hash,
+                        ":adapter": &key.adapter,
+                        ":adapter_version": &key.adapter_version,
+                        ":active_adapters": &key.active_adapters,
+                        ":file_path": &key.file_path,
+                    },
+                )?;
                 Ok(())
             })
             .await?)
     }
 }
 /// opens a default cache
-pub async fn open_cache_db(path: &Path) -> Result<impl PreprocCache + use<>> {
+pub async fn open_cache_db(config: &CacheConfig) -> Result<impl PreprocCache + use<>> {
+    let path = Path::new(&config.path.0);
+    std::fs::create_dir_all(path)?;
+    let encryption = CacheEncryption::from_config(config)?;
+    SqliteCache::new(path, encryption).await
+}
+
+#[derive(Default, Debug)]
+pub struct VacuumStats {
+    pub entries: usize,
+    pub bytes_before: u64,
+    pub bytes_after: u64,
+    pub dictionaries_trained: usize,
+}
+
+/**
+ * Train a zstd dictionary per adapter from the existing cache entries,
+ * recompress the entries of that adapter with it, drop unused dictionaries and vacuum the db.
+ *
+ * New cache entries are compressed with the latest dictionary of their adapter.
+ */
+pub async fn vacuum_cache(config: &CacheConfig) -> Result<VacuumStats> {
+    let path = Path::new(&config.path.0);
+    std::fs::create_dir_all(path)?;
+    let cache = SqliteCache::new(path, CacheEncryption::from_config(config)?).await?;
+    let encryption = cache.encryption.clone();
+    let compression_level = config.compression_level.0;
+    cache
+        .db
+        .call(move |db| Ok(vacuum_sync(db, encryption.as_deref(), compression_level)))
+        .await?
+}
+
+/// why `read_row` could not read a row
+enum RowError {
+    /// encrypted with a different key, or encrypted while encryption is not configured (or the other way around).
+    /// the entry is not broken, just unreadable with the current configuration
+    Skipped(anyhow::Error),
+    /// checksum mismatch or zstd data that does not decode
+    Broken(anyhow::Error),
+    Db(rusqlite::Error),
+}
+
+impl From<rusqlite::Error> for RowError {
+    fn from(e: rusqlite::Error) -> Self {
+        RowError::Db(e)
+    }
+}
+
+/// reads and decodes one row of the cache table. returns the stored key, the uncompressed content and the stored size
+fn read_row(
+    db: &rusqlite::Connection,
+    encryption: Option<&CacheEncryption>,
+    rowid: i64,
+) -> std::result::Result<(CacheKey, Vec<u8>, usize), RowError> {
+    let (key, blob, blob_checksum, dictionary, encrypted) = db.query_row(
+        "select c.config_hash, c.adapter, c.adapter_version, c.active_adapters, c.file_path, c.file_mtime_unix_ms,
+            c.text_content_zstd, c.checksum, d.dictionary, c.encrypted from preproc_cache c
+        left join zstd_dictionaries d on d.id = c.dictionary_id
+        where c.rowid = ?",
+        [rowid],
+        |r| {
+            Ok((
+                CacheKey {
+                    config_hash: r.get(0)?,
+                    adapter: r.get(1)?,
+                    adapter_version: r.get(2)?,
+                    active_adapters: r.get(3)?,
+                    file_path: r.get(4)?,
+                    file_mtime_unix_ms: r.get(5)?,
+                },
+                r.get::<_, Vec<u8>>(6)?,
+                r.get::<_, Vec<u8>>(7)?,
+                r.get::<_, Option<Vec<u8>>>(8)?,
+                r.get::<_, bool>(9)?,
+            ))
+        },
+    )?;
+    if checksum(&blob) != blob_checksum {
+        return Err(RowError::Broken(anyhow::format_err!("checksum mismatch")));
+    }
+    let stored_len = blob.len();
+    let blob = match (encryption, encrypted) {
+        (Some(enc), true) => {
+            let decrypt = || {
+                Ok(CachedBlob {
+                    zstd: enc.decrypt(&blob_aad(&key), &blob)?,
+                    dictionary: dictionary
+                        .map(|d| enc.decrypt(dictionary_aad(&key.adapter).as_bytes(), &d))
+                        .transpose()?,
+                })
+            };
+            decrypt().map_err(RowError::Skipped)?
+        }
+        (None, false) => CachedBlob {
+            zstd: blob,
+            dictionary,
+        },
+        (None, true) => {
+            return Err(RowError::Skipped(anyhow::format_err!(
+                "encrypted, but no cache key is configured"
+            )));
+        }
+        (Some(_), false) => {
+            return Err(RowError::Skipped(anyhow::format_err!(
+                "not encrypted, but a cache key is configured"
+            )));
+        }
+    };
+    let content = blob.decode().map_err(RowError::Broken)?;
+    Ok((key, content, stored_len))
+}
+
+fn vacuum_sync(
+    db: &mut rusqlite::Connection,
+    encryption: Option<&CacheEncryption>,
+    compression_level: i32,
+) -> Result<VacuumStats> {
+    let mut stats = VacuumStats::default();
+    let adapters = db
+        .prepare("select distinct adapter from preproc_cache")?
+        .query_map([], |r| r.get::<_, String>(0))?
+        .collect::<Result<Vec<_>, _>>()?;
+    for adapter in adapters {
+        let rowids = db
+            .prepare("select rowid from preproc_cache where adapter = ?")?
+            .query_map([&adapter], |r| r.get::<_, i64>(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+        stats.entries += rowids.len();
+
+        let mut samples = vec![];
+        let mut samples_size = 0;
+        for rowid in &rowids {
+            if samples_size >= MAX_TOTAL_SAMPLES_SIZE {
+                break;
+            }
+            match read_row(db, encryption, *rowid) {
+                Ok((_, mut content, _)) => {
+                    content.truncate(MAX_SAMPLE_SIZE);
+                    samples_size += content.len();
+                    samples.push(content);
+                }
+                Err(RowError::Skipped(e) | RowError::Broken(e)) => {
+                    warn!("skipping unreadable cache entry: {e:#}")
+                }
+                Err(RowError::Db(e)) => return Err(e.into()),
+            }
+        }
+        let dictionary = if samples.len() < MIN_DICTIONARY_SAMPLES {
+            None
+        } else {
+            zstd::dict::from_samples(&samples, MAX_DICTIONARY_SIZE.min(samples_size / 10))
+                .map_err(|e| debug!("could not train dictionary for {adapter}: {e}"))
+                .ok()
+        };
+        drop(samples);
+        let Some(dictionary) = dictionary else {
+            let size: i64 = db.query_row(
+                "select coalesce(sum(length(text_content_zstd)), 0) from preproc_cache where adapter = ?",
+                [&adapter],
+                |r| r.get(0),
+            )?;
+            stats.bytes_before += size as u64;
+            stats.bytes_after += size as u64;
+            continue;
+        };
+        debug!("trained {} byte dictionary for {adapter}", dictionary.len());
+
+        let tx = db.transaction()?;
+        let stored_dictionary = match encryption {
+            Some(enc) => enc.encrypt(dictionary_aad(&adapter).as_bytes(), &dictionary)?,
+            None => dictionary.clone(),
+        };
+        tx.execute(
+            "insert into zstd_dictionaries (adapter, dictionary) values (?, ?)",
+            rusqlite::params![&adapter, &stored_dictionary],
+        )?;
+        let dictionary_id = tx.last_insert_rowid();
+        for rowid in &rowids {
+            let (key, content, stored_len) = match read_row(&tx, encryption, *rowid) {
+                Ok(r) => r,
+                Err(RowError::Skipped(_) | RowError::Broken(_)) => continue,
+                Err(RowError::Db(e)) => return Err(e.into()),
+            };
+            stats.bytes_before += stored_len as u64;
+            let mut encoder = zstd::stream::write::Encoder::with_dictionary(
+                Vec::new(),
+                compression_level,
+                &dictionary,
+            )?;
+            encoder.include_checksum(true)?;
+            std::io::Write::write_all(&mut encoder, &content)?;
+            let compressed = encoder.finish()?;
+            let compressed = match encryption {
+                Some(enc) => enc.encrypt(&blob_aad(&key), &compressed)?,
+                None => compressed,
+            };
+            if compressed.len() < stored_len {
+                stats.bytes_after += compressed.len() as u64;
+                tx.execute(
+                    "update preproc_cache set text_content_zstd = ?, checksum = ?, dictionary_id = ? where rowid = ?",
+                    rusqlite::params![&compressed, checksum(&compressed), dictionary_id, rowid],
+                )?;
+            } else {
+                stats.bytes_after += stored_len as u64;
+            }
+        }
+        tx.commit()?;
+        stats.dictionaries_trained += 1;
+    }
+    // foreign keys are enforced per connection. `connect_pragmas` enables them on every connection to the cache,
+    // so a concurrent `set` that references a dictionary deleted here fails (and skips the write) instead of storing a broken entry
+    let tx = db.transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)?;
+    tx.execute(
+        "delete from zstd_dictionaries where id not in (select dictionary_id from preproc_cache where dictionary_id is not null)",
+        [],
+    )?;
+    tx.commit()?;
+    db.execute("vacuum", [])?;
+    Ok(stats)
+}
+
+#[derive(Default, Debug)]
+pub struct VerifyStats {
+    pub entries: usize,
+    pub removed: usize,
+    /// entries that could not be decrypted with the configured key (or without one)
+    pub skipped: usize,
+}
+
+/// check the checksum of every cache entry and fully decode it. Broken entries are removed,
+/// entries that are encrypted with a different key are skipped.
+pub async fn verify_cache(config: &CacheConfig) -> Result<VerifyStats> {
+    let path = Path::new(&config.path.0);
     std::fs::create_dir_all(path)?;
-    SqliteCache::new(path).await
+    let cache = SqliteCache::new(path, CacheEncryption::from_config(config)?).await?;
+    let encryption = cache.encryption.clone();
+    cache
+        .db
+        .call(move |db| Ok(verify_sync(db, encryption.as_deref())))
+        .await?
+}
+
+fn verify_sync(
+    db: &mut rusqlite::Connection,
+    encryption: Option<&CacheEncryption>,
+) -> Result<VerifyStats> {
+    let rowids = db
+        .prepare("select rowid from preproc_cache")?
+        .query_map([], |r| r.get::<_, i64>(0))?
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut stats = VerifyStats {
+        entries: rowids.len(),
+        ..Default::default()
+    };
+    for rowid in rowids {
+        match read_row(db, encryption, rowid) {
+            Ok(_) => {}
+            Err(RowError::Skipped(e)) => {
+                debug!("skipping cache entry {rowid}: {e:#}");
+                stats.skipped += 1;
+            }
+            Err(RowError::Broken(e)) => {
+                warn!("removing broken cache entry {rowid}: {e:#}");
+                db.execute("delete from preproc_cache where rowid = ?", [rowid])?;
+                stats.removed += 1;
+            }
+            Err(RowError::Db(e)) => return Err(e.into()),
+        }
+    }
+    Ok(stats)
 }
 
 #[cfg(test)]
 mod test {
 
+    use crate::config::CachePath;
     use crate::preproc_cache::*;
+    use tokio::io::AsyncReadExt;
+
+    fn test_key(file_path: &str) -> CacheKey {
+        CacheKey {
+            config_hash: "a41e2e9".to_string(),
+            adapter: "test".to_string(),
+            adapter_version: 1,
+            active_adapters: "null".to_string(),
+            file_path: file_path.to_string(),
+            file_mtime_unix_ms: 0,
+        }
+    }
+
+    fn compress(data: &[u8]) -> Vec<u8> {
+        zstd::stream::encode_all(data, 3).unwrap()
+    }
 
     #[tokio::test]
     async fn test_read_write() -> anyhow::Result<()> {
         let path = tempfile::tempdir()?;
-        let _db = open_cache_db(&path.path().join("foo.sqlite3")).await?;
+        let _db = open_cache_db(&CacheConfig {
+            path: CachePath(
+                path.path()
+                    .join("foo.sqlite3")
+                    .to_string_lossy()
+                    .into_owned(),
+            ),
+            ..Default::default()
+        })
+        .await?;
         // db.set();
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_encrypted() -> anyhow::Result<()> {
+        let path = tempfile::tempdir()?;
+        let key = test_key("/home/user/secret-report.pdf");
+        let mut db = SqliteCache::new(
+            path.path(),
+            Some(CacheEncryption::from_key_material(b"hunter2")),
+        )
+        .await?;
+        db.set(&key, compress(b"confidential"), None).await?;
+        let blob = db.get(&key).await?.hit().expect("entry exists");
+        assert_eq!(blob.decode()?, b"confidential");
+
+        let (stored_path, blob) = db
+            .db
+            .call(|db| {
+                Ok(db.query_row(
+                    "select file_path, text_content_zstd from preproc_cache",
+                    [],
+                    |r| Ok((r.get::<_, String>(0)?, r.get::<_, Vec<u8>>(1)?)),
+                )?)
+            })
+            .await?;
+        assert!(!stored_path.contains("secret-report"));
+        assert_ne!(blob, compress(b"confidential"));
+
+        // a different key can't read the entry
+        let other = SqliteCache::new(
+            path.path(),
+            Some(CacheEncryption::from_key_material(b"hunter3")),
+        )
+        .await?;
+        assert!(matches!(other.get(&key).await?, CacheLookup::Miss));
+
+        // the blob of another entry for the same file can't be swapped in
+        let old_version = CacheKey {
+            file_mtime_unix_ms: 1,
+            config_hash: "f1502a3".to_string(),
+            ..key.clone()
+        };
+        db.set(&old_version, compress(b"outdated"), None).await?;
+        db.db
+            .call(|db| {
+                db.execute(
+                    "update preproc_cache set text_content_zstd = (select text_content_zstd from preproc_cache where config_hash = 'f1502a3'),
+                        checksum = (select checksum from preproc_cache where config_hash = 'f1502a3')
+                    where config_hash = 'a41e2e9'",
+                    [],
+                )?;
+                Ok(())
+            })
+            .await?;
+        assert!(matches!(db.get(&key).await?, CacheLookup::Miss));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_verify_encrypted() -> anyhow::Result<()> {
+        let path = tempfile::tempdir()?;
+        let key_file = path.path().join("key");
+        std::fs::write(&key_file, "hunter2")?;
+        let mut db = SqliteCache::new(
+            path.path(),
+            Some(CacheEncryption::from_key_material(b"hunter2")),
+        )
+        .await?;
+        db.set(&test_key("/a.pdf"), compress(b"hello a"), None)
+            .await?;
+
+        // without the key and with a different key, the entry can't be checked but isn't removed either
+        let mut config = CacheConfig {
+            path: CachePath(path.path().to_string_lossy().into_owned()),
+            ..Default::default()
+        };
+        let stats = verify_cache(&config).await?;
+        assert_eq!((stats.entries, stats.removed, stats.skipped), (1, 0, 1));
+        let other_key = path.path().join("other-key");
+        std::fs::write(&other_key, "hunter3")?;
+        config.key_file = Some(other_key.to_string_lossy().into_owned());
+        let stats = verify_cache(&config).await?;
+        assert_eq!((stats.entries, stats.removed, stats.skipped), (1, 0, 1));
+
+        config.key_file = Some(key_file.to_string_lossy().into_owned());
+        let stats = verify_cache(&config).await?;
+        assert_eq!((stats.entries, stats.removed, stats.skipped), (1, 0, 0));
+        assert_eq!(
+            db.get(&test_key("/a.pdf"))
+                .await?
+                .hit()
+                .expect("exists")
+                .decode()?,
+            b"hello a"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_deleted_dictionary() -> anyhow::Result<()> {
+        let path = tempfile::tempdir()?;
+        let mut db = SqliteCache::new(path.path(), None).await?;
+        // e.g. the latest dictionary, deleted by a vacuum after `dictionary_for`
+        db.set(&test_key("/a.pdf"), compress(b"hello a"), Some(1234))
+            .await?;
+        assert!(matches!(
+            db.get(&test_key("/a.pdf")).await?,
+            CacheLookup::Miss
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_corrupt_entries() -> anyhow::Result<()> {
+        let path = tempfile::tempdir()?;
+        let config = CacheConfig {
+            path: CachePath(path.path().to_string_lossy().into_owned()),
+            ..Default::default()
+        };
+        let mut db = SqliteCache::new(path.path(), None).await?;
+        db.set(&test_key("/a.pdf"), compress(b"hello a"), None)
+            .await?;
+        db.set(&test_key("/b.pdf"), compress(b"hello b"), None)
+            .await?;
+        db.set(&test_key("/c.pdf"), compress(b"hello c"), None)
+            .await?;
+        db.db
+            .call(|db| {
+                // a truncated blob, e.g. from a write interrupted by a crash
+                db.execute(
+                    "update preproc_cache set text_content_zstd = substr(text_content_zstd, 1, 5) where file_path = '/a.pdf'",
+                    [],
+                )?;
+                // a blob with a valid checksum that still doesn't decode
+                let garbage = b"not zstd".to_vec();
+                db.execute(
+                    "update preproc_cache set text_content_zstd = ?, checksum = ? where file_path = '/b.pdf'",
+                    rusqlite::params![&garbage, checksum(&garbage)],
+                )?;
+                Ok(())
+            })
+            .await?;
+
+        assert!(matches!(
+            db.get(&test_key("/a.pdf")).await?,
+            CacheLookup::Corrupt
+        ));
+        // get doesn't remove it
+        assert!(matches!(
+            db.get(&test_key("/a.pdf")).await?,
+            CacheLookup::Corrupt
+        ));
+        assert!(
+            db.get(&test_key("/b.pdf"))
+                .await?
+                .hit()
+                .expect("exists")
+                .decode()
+                .is_err()
+        );
+
+        let stats = verify_cache(&config).await?;
+        assert_eq!(stats.entries, 3);
+        assert_eq!(stats.removed, 2);
+        assert!(matches!(
+            db.get(&test_key("/a.pdf")).await?,
+            CacheLookup::Miss
+        ));
+        assert!(matches!(
+            db.get(&test_key("/b.pdf")).await?,
+            CacheLookup::Miss
+        ));
+        assert_eq!(
+            db.get(&test_key("/c.pdf"))
+                .await?
+                .hit()
+                .expect("exists")
+                .decode()?,
+            b"hello c"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_vacuum_dictionary() -> anyhow::Result<()> {
+        let path = tempfile::tempdir()?;
+        let config = CacheConfig {
+            path: CachePath(path.path().to_string_lossy().into_owned()),
+            ..Default::default()
+        };
+        let mut db = SqliteCache::new(path.path(), None).await?;
+        let texts: Vec<String> = (0..200)
+            .map(|i| {
+                format!(
+                    "Page 1: Quarterly report number {i}\nPage 1: Revenue grew by {} percent compared to the previous quarter.\nPage 2: Confidential - do not distribute\n",
+                    i * 7 % 31
+                )
+            })
+            .collect();
+        for (i, text) in texts.iter().enumerate() {
+            db.set(
+                &test_key(&format!("/docs/{i}.pdf")),
+                compress(text.as_bytes()),
+                None,
+            )
+            .await?;
+        }
+        let stats = vacuum_cache(&config).await?;
+        assert_eq!(stats.entries, 200);
+        assert_eq!(stats.dictionaries_trained, 1);
+        assert!(stats.bytes_after < stats.bytes_before);
+
+        let dictionary = db
+            .dictionary_for("test")
+            .await?
+            .expect("dictionary trained");
+        let mut with_dictionary = 0;
+        for (i, text) in texts.iter().enumerate() {
+            let blob = db
+                .get(&test_key(&format!("/docs/{i}.pdf")))
+                .await?
+                .hit()
+                .expect("entry exists");
+            if blob.dictionary.as_deref() == Some(&dictionary.data[..]) {
+                with_dictionary += 1;
+            }
+            let mut decoded = Vec::new();
+            blob.into_reader()?.read_to_end(&mut decoded).await?;
+            assert_eq!(decoded, text.as_bytes());
+        }
+        assert!(with_dictionary > 0);
+        Ok(())
+    }
 }
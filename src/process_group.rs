@@ -0,0 +1,116 @@
+/*!
+ * Adapter programs run in their own process group, so a program and everything it started can be killed
+ * together (see `custom::pipe_output` and the persistent workers).
+ *
+ * Those groups are not in the foreground process group of the terminal anymore, so they don't get the
+ * SIGINT of Ctrl-C, and the destructors that would kill them don't run when rga is killed by a signal.
+ * Instead, the running groups are tracked here and killed by a handler for SIGINT, SIGTERM and SIGHUP,
+ * which then exits rga the way the signal would have.
+ */
+use log::debug;
+#[cfg(unix)]
+use std::sync::atomic::{AtomicI32, Ordering};
+use tokio::process::{Child, Command};
+
+/// more running adapter programs than this are not killed on a signal
+#[cfg(unix)]
+const MAX_GROUPS: usize = 1024;
+
+/// the ids of the tracked groups, 0 for a free slot. atomics instead of a mutex, since they are read by the signal handler
+#[cfg(unix)]
+static GROUPS: [AtomicI32; MAX_GROUPS] = [const { AtomicI32::new(0) }; MAX_GROUPS];
+
+/// start the program of `cmd` in a new process group
+pub fn configure(cmd: &mut Command) {
+    #[cfg(unix)]
+    cmd.process_group(0);
+    #[cfg(not(unix))]
+    let _ = cmd;
+}
+
+/// the process group of a running program (started with `configure`). killed on SIGINT, SIGTERM and SIGHUP until dropped
+pub struct ProcessGroup {
+    #[cfg(unix)]
+    slot: Option<(usize, i32)>,
+}
+
+impl ProcessGroup {
+    pub fn track(child: &Child) -> Self {
+        #[cfg(unix)]
+        {
+            install_handler();
+            let Some(pid) = child.id() else {
+                return Self { slot: None };
+            };
+            let pgid = pid as i32;
+            let slot = GROUPS.iter().position(|g| {
+                g.compare_exchange(0, pgid, Ordering::SeqCst, Ordering::SeqCst)
+                    .is_ok()
+            });
+            if slot.is_none() {
+                debug!(
+                    "too many adapter programs running, process group {pgid} is not killed on exit"
+                );
+            }
+            Self {
+                slot: slot.map(|s| (s, pgid)),
+            }
+        }
+        #[cfg(not(unix))]
+        {
+            let _ = child;
+            Self {}
+        }
+    }
+}
+
+impl Drop for ProcessGroup {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        if let Some((slot, pgid)) = self.slot {
+            let _ = GROUPS[slot].compare_exchange(pgid, 0, Ordering::SeqCst, Ordering::SeqCst);
+        }
+    }
+}
+
+#[cfg(unix)]
+fn install_handler() {
+    static INSTALL: std::sync::Once = std::sync::Once::new();
+    INSTALL.call_once(|| {
+        for signal in [libc::SIGINT, libc::SIGTERM, libc::SIGHUP] {
+            // SAFETY: the handler only calls async-signal-safe functions
+            unsafe {
+                let mut old: libc::sigaction = std::mem::zeroed();
+                libc::sigaction(signal, std::ptr::null(), &mut old);
+                if old.sa_sigaction != libc::SIG_DFL {
+                    // ignored (e.g. started with nohup) or handled by someone else
+                    continue;
+                }
+                let mut action: libc::sigaction = std::mem::zeroed();
+                action.sa_sigaction = kill_groups_and_exit as *const () as usize;
+                libc::sigemptyset(&mut action.sa_mask);
+                if libc::sigaction(signal, &action, std::ptr::null_mut()) != 0 {
+                    debug!("could not install handler for signal {signal}");
+                }
+            }
+        }
+    });
+}
+
+#[cfg(unix)]
+extern "C" fn kill_groups_and_exit(signal: libc::c_int) {
+    for group in &GROUPS {
+        let pgid = group.load(Ordering::SeqCst);
+        if pgid > 0 {
+            // SAFETY: killpg, signal and raise are async-signal-safe
+            unsafe {
+                libc::killpg(pgid, libc::SIGKILL);
+            }
+        }
+    }
+    // SAFETY: as above. the default action terminates the process
+    unsafe {
+        libc::signal(signal, libc::SIG_DFL);
+        libc::raise(signal);
+    }
+}
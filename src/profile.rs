@@ -0,0 +1,238 @@
+/*!
+ * `--rga-profile`: wall time, bytes in/out and number of calls per adapter and cache operation.
+ *
+ * Adapter times are inclusive, i.e. the time of an archive adapter contains the time of the adapters of its members.
+ * Optionally all spans are also written to a file in the Chrome trace event format,
+ * which can be opened in `chrome://tracing` or https://ui.perfetto.dev.
+ */
+use crate::adapters::ReadBox;
+use crate::limits::CountingReader;
+use crate::print_bytes;
+use anyhow::{Context, Result};
+use lazy_static::lazy_static;
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// trace "thread" the cache operations are shown in, so they don't overlap with the adapters
+const CACHE_TID: i32 = 1000;
+
+lazy_static! {
+    static ref PROFILER: Mutex<Option<Profiler>> = Mutex::new(None);
+}
+
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
+struct Stats {
+    calls: u64,
+    time: Duration,
+    bytes_in: u64,
+    bytes_out: u64,
+}
+
+struct Profiler {
+    start: Instant,
+    /// by (category, name)
+    stats: BTreeMap<(&'static str, String), Stats>,
+    /// only kept if a trace file is written
+    events: Option<Vec<serde_json::Value>>,
+}
+
+/// start recording. `trace` keeps every span for `finish`
+pub fn start(trace: bool) {
+    *PROFILER.lock().expect("profiler poisoned") = Some(Profiler {
+        start: Instant::now(),
+        stats: BTreeMap::new(),
+        events: trace.then(Vec::new),
+    });
+}
+
+fn enabled() -> bool {
+    PROFILER.lock().expect("profiler poisoned").is_some()
+}
+
+/// a timed operation, recorded when dropped. does nothing if profiling was not started
+pub struct Span(Option<SpanInner>);
+
+struct SpanInner {
+    category: &'static str,
+    name: String,
+    file: String,
+    tid: i32,
+    start: Instant,
+    bytes_in: Arc<AtomicU64>,
+    bytes_out: Arc<AtomicU64>,
+}
+
+/// time an adapter run. `depth` is the archive recursion depth, so nested adapters are shown below their parent
+pub fn adapter_span(name: &str, file: &Path, depth: i32) -> Span {
+    span("adapter", name, file, depth)
+}
+
+/// time a cache operation, e.g. `get`
+pub fn cache_span(operation: &str, file: &Path) -> Span {
+    span("cache", operation, file, CACHE_TID)
+}
+
+fn span(category: &'static str, name: &str, file: &Path, tid: i32) -> Span {
+    if !enabled() {
+        return Span(None);
+    }
+    Span(Some(SpanInner {
+        category,
+        name: name.to_string(),
+        file: file.to_string_lossy().into_owned(),
+        tid,
+        start: Instant::now(),
+        bytes_in: Default::default(),
+        bytes_out: Default::default(),
+    }))
+}
+
+impl Span {
+    /// count the bytes read from the input
+    pub fn count_in(&self, inp: ReadBox) -> ReadBox {
+        match &self.0 {
+            Some(s) => Box::pin(CountingReader::new(inp, s.bytes_in.clone())),
+            None => inp,
+        }
+    }
+
+    /// count the bytes read from an output
+    pub fn count_out(&self, out: ReadBox) -> ReadBox {
+        match &self.0 {
+            Some(s) => Box::pin(CountingReader::new(out, s.bytes_out.clone())),
+            None => out,
+        }
+    }
+
+    pub fn add_in(&self, bytes: usize) {
+        if let Some(s) = &self.0 {
+            s.bytes_in.fetch_add(bytes as u64, Ordering::Relaxed);
+        }
+    }
+
+    pub fn add_out(&self, bytes: usize) {
+        if let Some(s) = &self.0 {
+            s.bytes_out.fetch_add(bytes as u64, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        let Some(s) = self.0.take() else {
+            return;
+        };
+        let time = s.start.elapsed();
+        let bytes_in = s.bytes_in.load(Ordering::Relaxed);
+        let bytes_out = s.bytes_out.load(Ordering::Relaxed);
+        let mut profiler = PROFILER.lock().expect("profiler poisoned");
+        let Some(profiler) = profiler.as_mut() else {
+            return;
+        };
+        let stats = profiler
+            .stats
+            .entry((s.category, s.name.clone()))
+            .or_default();
+        stats.calls += 1;
+        stats.time += time;
+        stats.bytes_in += bytes_in;
+        stats.bytes_out += bytes_out;
+        let ts = s.start.saturating_duration_since(profiler.start);
+        if let Some(events) = profiler.events.as_mut() {
+            events.push(serde_json::json!({
+                "name": s.name,
+                "cat": s.category,
+                "ph": "X",
+                "ts": ts.as_micros() as u64,
+                "dur": time.as_micros() as u64,
+                "pid": std::process::id(),
+                "tid": s.tid,
+                "args": {
+                    "file": s.file,
+                    "bytes_in": bytes_in,
+                    "bytes_out": bytes_out,
+                },
+            }));
+        }
+    }
+}
+
+fn summary(stats: &BTreeMap<(&'static str, String), Stats>, total: Duration) -> String {
+    let mut out = format!(
+        "{:<24} {:>8} {:>12} {:>12} {:>12}\n",
+        "", "calls", "time", "bytes in", "bytes out"
+    );
+    for ((category, name), s) in stats {
+        out.push_str(&format!(
+            "{:<24} {:>8} {:>12} {:>12} {:>12}\n",
+            format!("{category} {name}"),
+            s.calls,
+            format!("{:.1?}", s.time),
+            print_bytes(s.bytes_in as f64),
+            print_bytes(s.bytes_out as f64),
+        ));
+    }
+    out.push_str(&format!("total time: {total:.1?}\n"));
+    out
+}
+
+/// stop recording, print the summary to stderr and write the trace file, if any
+pub fn finish(trace_file: Option<&Path>) -> Result<()> {
+    let Some(profiler) = PROFILER.lock().expect("profiler poisoned").take() else {
+        return Ok(());
+    };
+    eprint!("{}", summary(&profiler.stats, profiler.start.elapsed()));
+    if let (Some(path), Some(events)) = (trace_file, profiler.events) {
+        let mut file = std::io::BufWriter::new(
+            std::fs::File::create(path)
+                .with_context(|| format!("creating trace file {}", path.display()))?,
+        );
+        serde_json::to_writer(&mut file, &serde_json::json!({ "traceEvents": events }))?;
+        file.flush()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn summary_table() {
+        let mut stats = BTreeMap::new();
+        stats.insert(
+            ("adapter", "zip".to_string()),
+            Stats {
+                calls: 2,
+                time: Duration::from_millis(1500),
+                bytes_in: 1000,
+                bytes_out: 5000,
+            },
+        );
+        let table = summary(&stats, Duration::from_secs(2));
+        let lines: Vec<_> = table.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[1].starts_with("adapter zip"));
+        assert!(lines[1].contains("1.5s"));
+        assert_eq!(lines[2], "total time: 2.0s");
+    }
+
+    #[tokio::test]
+    async fn counting() -> Result<()> {
+        use tokio::io::AsyncReadExt;
+        let count = Arc::new(AtomicU64::new(0));
+        let mut r = CountingReader::new(
+            Box::pin(std::io::Cursor::new(b"hello world".to_vec())),
+            count.clone(),
+        );
+        let mut out = String::new();
+        r.read_to_string(&mut out).await?;
+        assert_eq!(count.load(Ordering::Relaxed), 11);
+        Ok(())
+    }
+}
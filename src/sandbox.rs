@@ -0,0 +1,252 @@
+/*!
+ * `--rga-sandbox`: run the programs of adapters restricted with Landlock and seccomp (Linux only).
+ *
+ * A sandboxed program can read the system directories (for its libraries and data files) and its input,
+ * write only to its own temporary directory and can't open sockets (network, unix or netlink).
+ * If the kernel does not support Landlock, starting the program fails instead of running it unrestricted.
+ * Access rights of newer Landlock versions (e.g. truncating files, Linux 6.2) are enforced where the kernel supports them.
+ */
+use crate::config::RgaConfig;
+use anyhow::Result;
+use std::path::PathBuf;
+use tokio::process::Command;
+
+/// what a sandboxed program can access besides the system directories
+#[derive(Debug, Default, Clone)]
+pub struct SandboxPolicy {
+    /// read only, e.g. the input file
+    pub read: Vec<PathBuf>,
+    /// read and write, e.g. the temporary output directory
+    pub write: Vec<PathBuf>,
+}
+
+/// whether the programs of this adapter should be sandboxed according to `--rga-sandbox`
+pub fn enabled_for(config: &RgaConfig, adapter: &str) -> bool {
+    config.sandbox.iter().any(|a| a == adapter || a == "all")
+}
+
+/// restrict the program `cmd` will start to the policy. fails if the kernel does not support it
+#[cfg(target_os = "linux")]
+pub fn restrict(cmd: &mut Command, policy: &SandboxPolicy) -> Result<()> {
+    let mut policy = policy.clone();
+    // the program itself might not be in a system directory
+    policy
+        .read
+        .extend(linux::find_program(cmd.as_std().get_program()));
+    let mut ruleset = Some(linux::landlock_ruleset(&policy)?);
+    let filter = linux::seccomp_filter()?;
+    // SAFETY: runs in the child between fork and exec. everything is prepared above,
+    // only the syscalls applying the restrictions are made here
+    unsafe {
+        cmd.pre_exec(move || {
+            if let Some(ruleset) = ruleset.take() {
+                let status = ruleset.restrict_self().map_err(std::io::Error::other)?;
+                if status.ruleset == landlock::RulesetStatus::NotEnforced {
+                    return Err(std::io::Error::other("Landlock rules are not enforced"));
+                }
+            }
+            seccompiler::apply_filter(&filter).map_err(std::io::Error::other)?;
+            Ok(())
+        });
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn restrict(_cmd: &mut Command, _policy: &SandboxPolicy) -> Result<()> {
+    Err(anyhow::format_err!(
+        "the adapter sandbox (--rga-sandbox) is only available on Linux"
+    ))
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use super::SandboxPolicy;
+    use anyhow::{Context, Result};
+    use landlock::{
+        ABI, Access, AccessFs, CompatLevel, Compatible, Ruleset, RulesetAttr, RulesetCreated,
+        RulesetCreatedAttr, path_beneath_rules,
+    };
+    use seccompiler::{
+        BpfProgram, SeccompAction, SeccompCmpArgLen, SeccompCmpOp, SeccompCondition, SeccompFilter,
+        SeccompRule,
+    };
+    use std::ffi::OsStr;
+    use std::path::{Path, PathBuf};
+
+    /// required: Landlock as in Linux 5.13, which covers reading and writing files
+    const MIN_ABI_VERSION: ABI = ABI::V1;
+    /// used as far as the kernel supports it: Landlock as in Linux 6.2, which adds truncating files
+    const ABI_VERSION: ABI = ABI::V3;
+
+    /// readable in the sandbox, if they exist
+    const SYSTEM_DIRS: &[&str] = &[
+        "/usr",
+        "/bin",
+        "/sbin",
+        "/lib",
+        "/lib32",
+        "/lib64",
+        "/etc",
+        "/opt",
+        "/nix/store",
+        "/proc",
+        "/sys",
+        "/dev",
+    ];
+
+    /// the path of the program, looked up in `PATH` if it has no directory
+    pub fn find_program(program: &OsStr) -> Option<PathBuf> {
+        let program = Path::new(program);
+        if program.components().count() > 1 {
+            return Some(program.to_path_buf());
+        }
+        std::env::split_paths(&std::env::var_os("PATH")?)
+            .map(|dir| dir.join(program))
+            .find(|path| path.is_file())
+    }
+
+    fn existing(paths: impl IntoIterator<Item = PathBuf>) -> Vec<PathBuf> {
+        paths.into_iter().filter(|p| p.exists()).collect()
+    }
+
+    pub fn landlock_ruleset(policy: &SandboxPolicy) -> Result<RulesetCreated> {
+        let read = existing(
+            SYSTEM_DIRS
+                .iter()
+                .map(PathBuf::from)
+                .chain(policy.read.iter().cloned()),
+        );
+        let write = existing(
+            policy
+                .write
+                .iter()
+                .cloned()
+                .chain([PathBuf::from("/dev/null")]),
+        );
+        let ruleset = Ruleset::default()
+            .set_compatibility(CompatLevel::HardRequirement)
+            .handle_access(AccessFs::from_all(MIN_ABI_VERSION))
+            .and_then(|r| {
+                r.set_compatibility(CompatLevel::BestEffort)
+                    .handle_access(AccessFs::from_all(ABI_VERSION))
+            })
+            .and_then(|r| r.create())
+            .context(
+                "Landlock is not available, the sandbox needs Linux 5.13 or newer with Landlock enabled",
+            )?;
+        ruleset
+            .add_rules(path_beneath_rules(read, AccessFs::from_read(ABI_VERSION)))
+            .and_then(|r| r.add_rules(path_beneath_rules(write, AccessFs::from_all(ABI_VERSION))))
+            .context("adding Landlock rules")
+    }
+
+    /// deny sockets, ptrace and io_uring (which could be used to get around the socket rule).
+    /// unix sockets could reach services outside the sandbox, netlink sockets could change the network configuration
+    pub fn seccomp_filter() -> Result<BpfProgram> {
+        let socket_family = |family: i32| -> Result<SeccompRule> {
+            Ok(SeccompRule::new(vec![SeccompCondition::new(
+                0,
+                SeccompCmpArgLen::Dword,
+                SeccompCmpOp::Eq,
+                family as u64,
+            )?])?)
+        };
+        let rules = [
+            (
+                libc::SYS_socket,
+                vec![
+                    socket_family(libc::AF_INET)?,
+                    socket_family(libc::AF_INET6)?,
+                    socket_family(libc::AF_PACKET)?,
+                    socket_family(libc::AF_UNIX)?,
+                    socket_family(libc::AF_NETLINK)?,
+                ],
+            ),
+            (libc::SYS_ptrace, vec![]),
+            (libc::SYS_io_uring_setup, vec![]),
+        ]
+        .into_iter()
+        .collect();
+        let filter = SeccompFilter::new(
+            rules,
+            SeccompAction::Allow,
+            SeccompAction::Errno(libc::EACCES as u32),
+            std::env::consts::ARCH
+                .try_into()
+                .context("seccomp is not supported on this architecture")?,
+        )?;
+        Ok(filter.try_into()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn enabled() {
+        let config = RgaConfig {
+            sandbox: vec!["pandoc".to_string()],
+            ..Default::default()
+        };
+        assert!(enabled_for(&config, "pandoc"));
+        assert!(!enabled_for(&config, "ffmpeg"));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    #[ignore = "needs a kernel with Landlock ABI v3 (Linux 6.2 or newer)"]
+    async fn restricted() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let readonly = tempfile::tempdir()?;
+        let keep = readonly.path().join("keep");
+        std::fs::write(&keep, "keep")?;
+        let outside = std::env::current_dir()?.join("rga-sandbox-test");
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg(format!(
+            "echo ok > '{0}/a' && cat '{0}/a' && (echo no > '{1}' 2>/dev/null || echo denied) \
+             && (python3 -c 'import os; os.truncate(\"{2}\", 0)' 2>/dev/null || echo denied)",
+            dir.path().display(),
+            outside.display(),
+            keep.display()
+        ));
+        let policy = SandboxPolicy {
+            read: vec![readonly.path().to_path_buf()],
+            write: vec![dir.path().to_path_buf()],
+        };
+        restrict(&mut cmd, &policy)?;
+        let out = cmd.output().await?;
+        assert_eq!(String::from_utf8_lossy(&out.stdout), "ok\ndenied\ndenied\n");
+        assert!(!outside.exists());
+        assert_eq!(std::fs::read_to_string(&keep)?, "keep");
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn no_sockets() -> Result<()> {
+        let filter = linux::seccomp_filter()?;
+        let mut cmd = Command::new("python3");
+        cmd.arg("-c").arg(
+            "import socket
+for family in [socket.AF_INET, socket.AF_UNIX, socket.AF_NETLINK]:
+    try:
+        socket.socket(family, socket.SOCK_DGRAM)
+        print('allowed')
+    except PermissionError:
+        print('denied')",
+        );
+        // SAFETY: only applies the prepared filter between fork and exec
+        unsafe {
+            cmd.pre_exec(move || seccompiler::apply_filter(&filter).map_err(std::io::Error::other));
+        }
+        let out = cmd.output().await?;
+        assert_eq!(
+            String::from_utf8_lossy(&out.stdout),
+            "denied\ndenied\ndenied\n"
+        );
+        Ok(())
+    }
+}
@@ -5,6 +5,7 @@ use crate::{
         custom::{BUILTIN_SPAWNING_ADAPTERS, CustomSpawningFileAdapter},
     },
     config::RgaConfig,
+    limits::LimitUsage,
     matching::{FastFileMatcher, FileMatcher},
     recurse::concat_read_streams,
 };
@@ -44,6 +45,7 @@ pub fn simple_adapt_info_full(
             line_prefix: "PREFIX:".to_string(),
             config: RgaConfig::default(),
             postprocess: true,
+            limit_usage: LimitUsage::default(),
         },
         FastFileMatcher::FileExtension(
             filepath
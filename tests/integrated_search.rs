@@ -0,0 +1,71 @@
+//! Runs the `rga` binary on a directory with a file that an adapter fails on
+
+use std::path::Path;
+use std::process::{Command, Output};
+
+fn rga(dir: &Path, args: &[&str]) -> Output {
+    let config = dir.join("config.jsonc");
+    Command::new(env!("CARGO_BIN_EXE_rga"))
+        .arg(format!("--rga-config-file={}", config.display()))
+        .arg("--rga-no-cache")
+        .args(args)
+        .arg("hello")
+        .arg(dir.join("files"))
+        .env_remove("RGA_CONFIG")
+        .env("HOME", dir)
+        .env("XDG_CONFIG_HOME", dir.join("config"))
+        .env("XDG_CACHE_HOME", dir.join("cache"))
+        .output()
+        .expect("could not run rga")
+}
+
+/// `good.txt` can be searched, the `failing` adapter exits with an error after some output for `bad.failtest`
+fn setup() -> tempfile::TempDir {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(
+        dir.path().join("config.jsonc"),
+        r#"{"custom_adapters": [{
+            "name": "failing",
+            "version": 1,
+            "description": "fails after some output",
+            "extensions": ["failtest"],
+            "binary": "sh",
+            "args": ["-c", "echo hello; exit 3"]
+        }]}"#,
+    )
+    .unwrap();
+    let files = dir.path().join("files");
+    std::fs::create_dir(&files).unwrap();
+    std::fs::write(files.join("good.txt"), "hello world\n").unwrap();
+    std::fs::write(files.join("bad.failtest"), "hello\n").unwrap();
+    dir
+}
+
+#[cfg(unix)]
+#[test]
+fn continues_after_failed_file() {
+    let dir = setup();
+    let out = rga(dir.path(), &[]);
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(stdout.contains("hello world"), "{stdout}");
+    assert!(
+        stderr.contains("rga: 1 file could not be searched:"),
+        "{stderr}"
+    );
+    assert!(stderr.contains("bad.failtest (failing): "), "{stderr}");
+    assert!(stderr.contains("exit status: 3"), "{stderr}");
+    assert_eq!(out.status.code(), Some(0), "{stderr}");
+}
+
+#[cfg(unix)]
+#[test]
+fn strict() {
+    let dir = setup();
+    let out = rga(dir.path(), &["--rga-strict"]);
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(stdout.contains("hello world"), "{stdout}");
+    assert!(stderr.contains("bad.failtest (failing): "), "{stderr}");
+    assert_eq!(out.status.code(), Some(2), "{stderr}");
+}
@@ -0,0 +1,72 @@
+//! Interrupts the `rga` binary while an adapter program is running
+#![cfg(target_os = "linux")]
+
+use std::os::unix::process::ExitStatusExt;
+use std::process::Command;
+use std::time::{Duration, Instant};
+
+/// zombies count as exited, they might never be reaped in a container
+fn exited(pid: &str) -> bool {
+    match std::fs::read_to_string(format!("/proc/{pid}/stat")) {
+        Ok(stat) => stat
+            .rsplit_once(')')
+            .is_some_and(|(_, state)| state.trim_start().starts_with('Z')),
+        Err(_) => true,
+    }
+}
+
+fn wait_until(what: &str, mut f: impl FnMut() -> bool) {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while !f() {
+        assert!(Instant::now() < deadline, "{what}");
+        std::thread::sleep(Duration::from_millis(50));
+    }
+}
+
+#[test]
+fn kills_adapter_programs() {
+    let dir = tempfile::tempdir().unwrap();
+    let pid_file = dir.path().join("pid");
+    let config = serde_json::json!({"custom_adapters": [{
+        "name": "hanging",
+        "version": 1,
+        "description": "starts a subprocess and waits for it",
+        "extensions": ["hangtest"],
+        "binary": "sh",
+        "args": ["-c", format!("sleep 60 & echo $! > {}; wait", pid_file.display())]
+    }]});
+    std::fs::write(dir.path().join("config.jsonc"), config.to_string()).unwrap();
+    let files = dir.path().join("files");
+    std::fs::create_dir(&files).unwrap();
+    std::fs::write(files.join("a.hangtest"), "hello\n").unwrap();
+
+    let mut rga = Command::new(env!("CARGO_BIN_EXE_rga"))
+        .arg(format!(
+            "--rga-config-file={}",
+            dir.path().join("config.jsonc").display()
+        ))
+        .arg("--rga-no-cache")
+        .arg("hello")
+        .arg(&files)
+        .env_remove("RGA_CONFIG")
+        .env("HOME", dir.path())
+        .spawn()
+        .unwrap();
+    let mut sleep_pid = String::new();
+    wait_until("adapter program not started", || {
+        sleep_pid = std::fs::read_to_string(&pid_file).unwrap_or_default();
+        sleep_pid.ends_with('\n')
+    });
+    let sleep_pid = sleep_pid.trim();
+    assert!(!exited(sleep_pid));
+
+    // SAFETY: only sends a signal to our own child
+    unsafe {
+        libc::kill(rga.id() as libc::pid_t, libc::SIGINT);
+    }
+    let status = rga.wait().unwrap();
+    assert_eq!(status.signal(), Some(libc::SIGINT));
+    wait_until("subprocess of the adapter still running", || {
+        exited(sleep_pid)
+    });
+}